@@ -307,7 +307,23 @@ async function handleGenerate(event) {
     prompt: prompt,
     maxPrice: event.headers['x-max-price'] || '0.001',
     maxLatency: parseInt(event.headers['x-max-latency'] || '1000'),
-    timestamp: Date.now().toString()
+    timestamp: Date.now().toString(),
+    userId: userId,
+    // Lets the matcher recognise a retried request and return the first
+    // response instead of charging again
+    metadata: {
+      idempotency_key: event.headers['idempotency-key'] || event.requestContext.requestId
+    },
+    // Comma-separated list of acceptable GPU types; omitted means any
+    gpuTypes: (event.headers['x-gpu-types'] || '')
+      .split(',')
+      .map(gpu => gpu.trim())
+      .filter(Boolean),
+    // Comma-separated capability requirements, e.g. "context_length >= 32000"
+    requirements: (event.headers['x-requirements'] || '')
+      .split(',')
+      .map(requirement => requirement.trim())
+      .filter(Boolean)
   };
 
   // Find matching provider
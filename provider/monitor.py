@@ -1,6 +1,7 @@
 import subprocess
 import json
 import time
+import grpc
 import redis
 from decimal import Decimal, ROUND_DOWN
 from datetime import datetime
@@ -8,6 +9,9 @@ import logging
 from dataclasses import dataclass
 from typing import Optional, Dict, List
 
+# Payout messages and stub for the matcher's gRPC service
+payout_pb2, payout_pb2_grpc = grpc.protos_and_services("matcher_payout.proto")
+
 @dataclass
 class ProviderEarnings:
     total_credits: Decimal
@@ -22,13 +26,34 @@ class GPUMonitor:
         provider_id: str,
         ask_price: str,
         max_latency: int,
-        payout_threshold: Decimal = Decimal('100.00')
+        payout_threshold: Decimal = Decimal('100.00'),
+        matcher_addr: str = 'localhost:50051',
+        endpoint_url: Optional[str] = None,
+        endpoint_token: Optional[str] = None,
+        endpoint_protocol: str = 'ollama',
+        endpoint_model: Optional[str] = None
     ):
         self.redis = redis.from_url(redis_url)
         self.provider_id = provider_id
         self.base_price = Decimal(ask_price).quantize(Decimal('0.00000001'), rounding=ROUND_DOWN)
         self.max_latency = max_latency
         self.payout_threshold = payout_threshold
+        self.matcher = payout_pb2_grpc.MatcherServiceStub(grpc.insecure_channel(matcher_addr))
+        # Each GPU quotes its own ask, and the matcher credits earnings to
+        # the ask's provider ID
+        self.ask_ids: List[str] = []
+        # Where the matcher forwards filled bids; without one, clients call
+        # the provider themselves
+        self.endpoint = None
+        if endpoint_url:
+            self.endpoint = {
+                'url': endpoint_url,
+                'auth_token': endpoint_token,
+                'protocol': endpoint_protocol,
+                # The name the endpoint serves the model under, e.g. an
+                # Ollama tag; the matcher sends its catalog ID otherwise
+                'model': endpoint_model
+            }
         self.logger = logging.getLogger('GPUMonitor')
 
     def get_gpu_stats(self):
@@ -62,9 +87,20 @@ class GPUMonitor:
         base_rate = Decimal('0.000001')  # 1 credit per million tokens
 
         # Adjust based on GPU type
+        # Mirrors the matcher's catalog; unlisted GPUs are priced at 1x
         gpu_multipliers = {
+            't4': Decimal('0.4'),
+            'rtx3090': Decimal('0.6'),
+            'l4': Decimal('0.7'),
+            'a10g': Decimal('0.8'),
+            'v100': Decimal('0.8'),
+            'rtx4090': Decimal('0.8'),
+            'a6000': Decimal('1.0'),
+            'l40s': Decimal('1.2'),
             'a100': Decimal('1.5'),
             'h100': Decimal('2.0'),
+            'mi300x': Decimal('2.0'),
+            'h200': Decimal('2.5'),
             'default': Decimal('1.0')
         }
         gpu_multiplier = gpu_multipliers.get(gpu_type.lower(), gpu_multipliers['default'])
@@ -96,9 +132,12 @@ class GPUMonitor:
             rounding=ROUND_DOWN
         )
 
-    def get_earnings(self) -> ProviderEarnings:
+    def gpu_ask_id(self, idx: int) -> str:
+        return f"{self.provider_id}_gpu{idx}"
+
+    def get_earnings(self, ask_id: str) -> Optional[ProviderEarnings]:
         try:
-            earnings_key = f"provider:earnings:{self.provider_id}"
+            earnings_key = f"provider:earnings:{ask_id}"
             raw_data = self.redis.get(earnings_key)
             
             if raw_data:
@@ -119,14 +158,33 @@ class GPUMonitor:
             self.logger.error(f"Failed to get earnings: {e}", exc_info=True)
             return None
 
-    def check_payout_eligibility(self) -> Optional[Decimal]:
-        earnings = self.get_earnings()
-        if not earnings:
-            return None
+    def get_total_earnings(self) -> Optional[ProviderEarnings]:
+        """Earnings of every GPU this agent quotes, added up."""
+        total = ProviderEarnings(
+            total_credits=Decimal('0'),
+            pending_payout=Decimal('0'),
+            last_payout=datetime.fromtimestamp(0),
+            total_inference_count=0
+        )
+        for ask_id in self.ask_ids:
+            earnings = self.get_earnings(ask_id)
+            if not earnings:
+                return None
+            total.total_credits += earnings.total_credits
+            total.pending_payout += earnings.pending_payout
+            total.last_payout = max(total.last_payout, earnings.last_payout)
+            total.total_inference_count += earnings.total_inference_count
+        return total
 
-        if earnings.pending_payout >= self.payout_threshold:
-            return earnings.pending_payout
-        return None
+    def check_payout_eligibility(self) -> Dict[str, Decimal]:
+        """Pending earnings of each GPU ask that has reached the threshold,
+        which the matcher applies per provider ID."""
+        eligible = {}
+        for ask_id in self.ask_ids:
+            earnings = self.get_earnings(ask_id)
+            if earnings and earnings.pending_payout >= self.payout_threshold:
+                eligible[ask_id] = earnings.pending_payout
+        return eligible
 
     def update_ask(self, gpu_stats: List[Dict]) -> bool:
         if not gpu_stats:
@@ -136,13 +194,29 @@ class GPUMonitor:
         
         try:
             # Update asks for each GPU
+            self.ask_ids = [self.gpu_ask_id(idx) for idx in range(len(gpu_stats))]
             for idx, stats in enumerate(gpu_stats):
-                gpu_id = f"{self.provider_id}_gpu{idx}"
+                gpu_id = self.gpu_ask_id(idx)
                 
                 # Calculate prices and rates with proper decimal handling
                 price = self.adjust_price(stats['utilization'])
                 credit_rate = self.calculate_credit_rate("a100", "gpt4")
                 available_tokens = self.calculate_available_tokens(stats['memory_free'])
+                # The matcher counts tokens claimed by fills still in flight
+                # separately; they are not free until it gives them back
+                claimed = int(self.redis.get(f"capacity:claimed:{gpu_id}:gpt4") or 0)
+
+                # Requoting gives up time priority; heartbeats at the same
+                # price and credit rate keep the original posting time
+                ask_key = f"ask:{gpu_id}:gpt4"
+                posted_at = current_time
+                existing = self.redis.get(ask_key)
+                if existing:
+                    previous = json.loads(existing)
+                    if (previous.get('price') == str(price)
+                            and previous.get('credit_rate') == str(credit_rate)
+                            and previous.get('posted_at')):
+                        posted_at = previous['posted_at']
 
                 ask = {
                     'provider_id': gpu_id,
@@ -151,22 +225,37 @@ class GPUMonitor:
                     'price': str(price),
                     'credit_rate': str(credit_rate),
                     'max_latency': self.max_latency,
-                    'available_tokens': available_tokens,
+                    'available_tokens': max(available_tokens - claimed, 0),
                     'last_heartbeat': current_time,
+                    'posted_at': posted_at,
                     'capabilities': {
                         'streaming': 'true',
                         'batch_support': 'true',
                         'max_tokens': str(available_tokens)
                     }
                 }
+                if self.endpoint:
+                    ask['endpoint'] = self.endpoint
 
                 # Atomic update with proper expiration
                 pipe = self.redis.pipeline()
                 pipe.set(
-                    f"ask:{gpu_id}:gpt4",
+                    ask_key,
                     json.dumps(ask),
                     ex=120  # Auto-expire after 2 minutes if monitor fails
                 )
+
+                # Keep the matcher's secondary indexes in sync; entries for
+                # expired asks are pruned by the matcher
+                pipe.sadd("asks:model:gpt4", ask_key)
+                pipe.sadd("asks:gpu:a100", ask_key)
+                pipe.zadd("asks:heartbeat", {ask_key: current_time})
+
+                # The matcher finds asks through the price index of each GPU
+                # type registered for the model
+                pipe.zadd("price:gpt4:a100", {gpu_id: float(price)})
+                pipe.zadd("latency:gpt4:a100", {gpu_id: self.max_latency})
+                pipe.sadd("gpus:gpt4", "a100")
                 
                 # Update provider status
                 status_key = f"provider:status:{gpu_id}"
@@ -182,11 +271,9 @@ class GPUMonitor:
                 pipe.execute()
 
             # Check for pending payouts
-            payout_amount = self.check_payout_eligibility()
-            if payout_amount:
-                self.logger.info(f"Payout threshold reached: {payout_amount} credits pending")
-                # Trigger payout process (implement based on your payment system)
-                self.request_payout(payout_amount)
+            for ask_id, pending in self.check_payout_eligibility().items():
+                self.logger.info(f"Payout threshold reached for {ask_id}: {pending} credits pending")
+                self.request_payout(ask_id)
 
             return True
 
@@ -194,29 +281,17 @@ class GPUMonitor:
             self.logger.error(f"Failed to update ask: {e}", exc_info=True)
             return False
 
-    def request_payout(self, amount: Decimal):
+    def request_payout(self, ask_id: str):
+        """Pays out everything pending for one GPU ask through the matcher,
+        which records the payout in its ledger."""
         try:
-            payout_request = {
-                'provider_id': self.provider_id,
-                'amount': str(amount),
-                'timestamp': int(datetime.utcnow().timestamp()),
-                'status': 'pending'
-            }
-            
-            # Record payout request
-            self.redis.rpush(
-                f"provider:payouts:{self.provider_id}",
-                json.dumps(payout_request)
+            response = self.matcher.RequestProviderPayout(
+                payout_pb2.ProviderPayoutRequest(provider_id=ask_id, amount=""),
+                timeout=10
             )
-            
-            # Notify payout service (implement based on your system)
-            self.redis.publish(
-                'payout_requests',
-                json.dumps(payout_request)
-            )
-            
-        except Exception as e:
-            self.logger.error(f"Failed to request payout: {e}", exc_info=True)
+            self.logger.info(f"Payout requested for {ask_id}; {response.pending_payout} credits still pending")
+        except grpc.RpcError as e:
+            self.logger.error(f"Failed to request payout for {ask_id}: {e.code()} {e.details()}")
 
 def main():
     # Configure logging
@@ -232,14 +307,17 @@ def main():
             provider_id="provider1",
             ask_price="0.001",
             max_latency=1000,
-            payout_threshold=Decimal('100.00')
+            payout_threshold=Decimal('100.00'),
+            matcher_addr="your-matcher-endpoint:50051"
         )
 
         while True:
             try:
                 stats = monitor.get_gpu_stats()
                 if monitor.update_ask(stats):
-                    logger.info(f"Updated asks for {len(stats)} GPUs")
+                    earnings = monitor.get_total_earnings()
+                    pending = earnings.pending_payout if earnings else "unknown"
+                    logger.info(f"Updated asks for {len(stats)} GPUs; {pending} credits pending payout")
                 else:
                     logger.warning("Failed to update asks - check nvidia-smi")
             except Exception as e:
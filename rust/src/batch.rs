@@ -0,0 +1,326 @@
+use crate::capabilities::Requirement;
+use crate::orderbook::{
+    prompt_tokens, Ask, Bid, CapacityClaim, CreditReservation, CreditTransaction, OrderBook,
+};
+use rust_decimal::prelude::*;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Many prompts for one model under a single price and latency limit.
+#[derive(Debug, Clone)]
+pub struct BatchBid {
+    pub model: String,
+    pub prompts: Vec<String>,
+    pub max_price: Decimal,
+    pub max_latency: u32,
+    pub user_id: String,
+    pub metadata: HashMap<String, String>,
+    /// Acceptable GPU types; empty accepts any
+    pub gpu_types: Vec<String>,
+    /// Capabilities every ask the batch is placed on must advertise
+    pub requirements: Vec<Requirement>,
+    /// Fill the prompts that fit instead of failing the whole batch
+    pub allow_partial: bool,
+}
+
+impl BatchBid {
+    /// The bid every prompt of the batch is matched as.
+    fn as_bid(&self) -> Bid {
+        Bid {
+            model: self.model.clone(),
+            prompt: String::new(),
+            max_price: self.max_price,
+            max_latency: self.max_latency,
+            timestamp: 0,
+            user_id: self.user_id.clone(),
+            required_credits: None,
+            metadata: self.metadata.clone(),
+            gpu_types: self.gpu_types.clone(),
+            requirements: self.requirements.clone(),
+        }
+    }
+}
+
+/// One prompt of a batch and the ask it was placed on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allocation {
+    pub prompt_index: usize,
+    pub provider_id: String,
+    pub tokens: u32,
+    pub credits: Decimal,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BatchPlan {
+    pub allocations: Vec<Allocation>,
+    /// Indexes of prompts no ask had capacity for
+    pub unallocated: Vec<usize>,
+}
+
+/// Places each prompt on the first ask in `asks` with capacity left for it,
+/// so callers pass asks best first. Prompts are counted with `model`'s
+/// tokenizer and placed largest first so small ones do not strand the
+/// capacity large ones need.
+pub fn plan_batch(model: &str, prompts: &[String], asks: &[Ask]) -> BatchPlan {
+    let mut remaining: Vec<u32> = asks.iter().map(|a| a.available_tokens).collect();
+    let prompt_tokens: Vec<u32> = prompts.iter().map(|p| prompt_tokens(model, p)).collect();
+    let mut order: Vec<usize> = (0..prompts.len()).collect();
+    order.sort_by_key(|&i| (Reverse(prompt_tokens[i]), i));
+
+    let mut plan = BatchPlan::default();
+    for index in order {
+        let tokens = prompt_tokens[index];
+        match remaining.iter().position(|r| *r >= tokens) {
+            Some(slot) => {
+                remaining[slot] -= tokens;
+                plan.allocations.push(Allocation {
+                    prompt_index: index,
+                    provider_id: asks[slot].provider_id.clone(),
+                    tokens,
+                    credits: asks[slot].calculate_credit_cost(tokens),
+                });
+            }
+            None => plan.unallocated.push(index),
+        }
+    }
+
+    plan.allocations.sort_by_key(|a| a.prompt_index);
+    plan.unallocated.sort_unstable();
+    plan
+}
+
+/// What one provider was charged for its share of a batch.
+#[derive(Debug, Clone)]
+pub struct ProviderCharge {
+    pub provider_id: String,
+    pub tokens: u32,
+    pub prompt_count: u32,
+    pub transaction: CreditTransaction,
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchFill {
+    pub allocations: Vec<Allocation>,
+    pub unallocated: Vec<usize>,
+    pub charges: Vec<ProviderCharge>,
+}
+
+// A provider's share of a batch between claiming and charging
+struct Share {
+    ask: Ask,
+    allocations: Vec<Allocation>,
+    claim: CapacityClaim,
+}
+
+/// Splits a batch across live asks, claims each provider's share of
+/// capacity and charges the user once per provider. Returns `None`, with
+/// nothing claimed or charged, when no prompt could be placed or when a
+/// batch that does not allow partial fills does not fit whole. As with
+/// single bids, charged shares leave their capacity to the providers' next
+/// heartbeat. If a capture fails after others went through, the shares
+/// not yet charged are released and reported as unallocated alongside the
+/// charges already made.
+pub async fn fill_batch(orderbook: &OrderBook, batch: &BatchBid) -> redis::RedisResult<Option<BatchFill>> {
+    // Batches are charged on match and never forwarded, so like resting
+    // bids they are not placed on asks that forward to an endpoint
+    let asks: Vec<Ask> = orderbook.ranked_asks(&batch.as_bid()).await?
+        .into_iter()
+        .filter(|a| a.endpoint.is_none())
+        .collect();
+    let plan = plan_batch(&batch.model, &batch.prompts, &asks);
+
+    let mut unallocated = plan.unallocated;
+    let mut shares = Vec::new();
+    for ask in asks {
+        let allocations: Vec<Allocation> = plan.allocations
+            .iter()
+            .filter(|a| a.provider_id == ask.provider_id)
+            .cloned()
+            .collect();
+        if allocations.is_empty() {
+            continue;
+        }
+
+        // A concurrent fill may have taken the capacity since the plan was made
+        let tokens = allocations.iter().map(|a| a.tokens).sum();
+        match orderbook.claim_capacity(&ask, tokens).await? {
+            Some(claim) => shares.push(Share { ask, allocations, claim }),
+            None => unallocated.extend(allocations.iter().map(|a| a.prompt_index)),
+        }
+    }
+
+    if shares.is_empty() || (!batch.allow_partial && !unallocated.is_empty()) {
+        release_shares(orderbook, &shares).await;
+        return Ok(None);
+    }
+
+    // Hold every share's credits before capturing any, so a balance that
+    // cannot cover the whole batch charges nothing
+    let mut reservations: Vec<CreditReservation> = Vec::with_capacity(shares.len());
+    for share in &shares {
+        let credits = share.allocations.iter().map(|a| a.credits).sum();
+        match orderbook.reserve_credits(&batch.user_id, credits, &share.ask.provider_id).await {
+            Ok(reservation) => reservations.push(reservation),
+            Err(e) => {
+                for reservation in &reservations {
+                    if let Err(e) = orderbook.release_credits(reservation).await {
+                        eprintln!("Failed to release reservation {}: {}", reservation.reservation_id, e);
+                    }
+                }
+                release_shares(orderbook, &shares).await;
+                return Err(e);
+            }
+        }
+    }
+
+    let mut charges = Vec::with_capacity(shares.len());
+    let mut allocations = Vec::new();
+    let mut pending = shares.into_iter().zip(reservations);
+    while let Some((share, reservation)) = pending.next() {
+        let transaction = match orderbook.capture_credits(&reservation, reservation.amount).await {
+            Ok(transaction) => transaction,
+            Err(e) => {
+                let (unsettled, held): (Vec<Share>, Vec<CreditReservation>) =
+                    std::iter::once((share, reservation)).chain(pending).unzip();
+                for reservation in &held {
+                    if let Err(e) = orderbook.release_credits(reservation).await {
+                        eprintln!("Failed to release reservation {}: {}", reservation.reservation_id, e);
+                    }
+                }
+                release_shares(orderbook, &unsettled).await;
+                if charges.is_empty() {
+                    return Err(e);
+                }
+                eprintln!("Failed to charge a batch share, stopping after {} charges: {}", charges.len(), e);
+                unallocated.extend(unsettled.iter().flat_map(|s| s.allocations.iter().map(|a| a.prompt_index)));
+                break;
+            }
+        };
+        if let Err(e) = orderbook.settle_capacity(&share.claim).await {
+            eprintln!("Failed to settle capacity with {}: {}", share.claim.provider_id, e);
+        }
+        charges.push(ProviderCharge {
+            provider_id: share.ask.provider_id,
+            tokens: share.claim.tokens,
+            prompt_count: share.allocations.len() as u32,
+            transaction,
+        });
+        allocations.extend(share.allocations);
+    }
+
+    allocations.sort_by_key(|a| a.prompt_index);
+    unallocated.sort_unstable();
+    Ok(Some(BatchFill { allocations, unallocated, charges }))
+}
+
+async fn release_shares(orderbook: &OrderBook, shares: &[Share]) {
+    for share in shares {
+        if let Err(e) = orderbook.release_capacity(&share.claim).await {
+            eprintln!("Failed to return capacity to {}: {}", share.claim.provider_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::test_ask;
+    use crate::gateway::ProviderEndpoint;
+    use crate::store::{MemoryStore, OrderBookStore};
+    use rust_decimal_macros::dec;
+    use std::sync::Arc;
+
+    fn ask(provider_id: &str, gpu_type: &str, available_tokens: u32) -> Ask {
+        Ask {
+            gpu_type: gpu_type.into(),
+            available_tokens,
+            ..test_ask(provider_id, dec!(0.001))
+        }
+    }
+
+    fn prompts(lengths: &[usize]) -> Vec<String> {
+        lengths.iter().map(|n| "x".repeat(*n)).collect()
+    }
+
+    fn batch(lengths: &[usize], allow_partial: bool) -> BatchBid {
+        BatchBid {
+            model: "gpt4".into(),
+            prompts: prompts(lengths),
+            max_price: dec!(0.002),
+            max_latency: 1000,
+            user_id: "u1".into(),
+            metadata: HashMap::new(),
+            gpu_types: Vec::new(),
+            requirements: Vec::new(),
+            allow_partial,
+        }
+    }
+
+    #[test]
+    fn test_plan_fills_best_asks_first() {
+        let asks = vec![ask("first", "a100", 10), ask("second", "a100", 100)];
+        let plan = plan_batch("gpt4", &prompts(&[8, 40, 32]), &asks);
+
+        // 10 tokens (40 bytes) fit the first ask; the rest spill over
+        let placed: Vec<(usize, &str)> = plan.allocations
+            .iter()
+            .map(|a| (a.prompt_index, a.provider_id.as_str()))
+            .collect();
+        assert_eq!(placed, vec![(0, "second"), (1, "first"), (2, "second")]);
+        assert!(plan.unallocated.is_empty());
+
+        let plan = plan_batch("gpt4", &prompts(&[800]), &asks);
+        assert_eq!(plan.unallocated, vec![0]);
+    }
+
+    #[tokio::test]
+    async fn test_fill_charges_each_provider() {
+        let store = Arc::new(MemoryStore::new());
+        store.deposit_credits("u1", dec!(1000), "credit_purchase").await.unwrap();
+        let orderbook = OrderBook::new(store.clone(), 60);
+        // a100 is cheaper per token than h100
+        orderbook.add_ask(ask("h", "h100", 1000)).await.unwrap();
+        orderbook.add_ask(ask("a", "a100", 20)).await.unwrap();
+        // Cheapest, but batches are never forwarded
+        orderbook.add_ask(Ask {
+            price: dec!(0.0001),
+            endpoint: Some(ProviderEndpoint {
+                url: "http://f:11434/api/generate".into(),
+                auth_token: None,
+                protocol: Default::default(),
+                model: None,
+            }),
+            ..ask("f", "a100", 1000)
+        }).await.unwrap();
+
+        let fill = fill_batch(&orderbook, &batch(&[40, 40, 40], false)).await.unwrap().unwrap();
+        assert!(fill.unallocated.is_empty());
+        assert_eq!(fill.charges.len(), 2);
+        assert!(fill.charges.iter().all(|c| c.provider_id != "f"));
+
+        let charged: HashMap<String, (u32, Decimal)> = fill.charges
+            .iter()
+            .map(|c| (c.provider_id.clone(), (c.prompt_count, c.transaction.amount)))
+            .collect();
+        assert_eq!(charged["a"], (2, dec!(60)));
+        assert_eq!(charged["h"], (1, dec!(40)));
+        assert_eq!(store.get_credit_balance("u1").await.unwrap(), dec!(900));
+        assert_eq!(store.get_ask("a", "gpt4").await.unwrap().unwrap().available_tokens, 0);
+    }
+
+    #[tokio::test]
+    async fn test_batch_that_does_not_fit_claims_nothing() {
+        let store = Arc::new(MemoryStore::new());
+        store.deposit_credits("u1", dec!(1000), "credit_purchase").await.unwrap();
+        let orderbook = OrderBook::new(store.clone(), 60);
+        orderbook.add_ask(ask("a", "a100", 20)).await.unwrap();
+
+        assert!(fill_batch(&orderbook, &batch(&[40, 400], false)).await.unwrap().is_none());
+        assert_eq!(store.get_ask("a", "gpt4").await.unwrap().unwrap().available_tokens, 20);
+        assert_eq!(store.get_credit_balance("u1").await.unwrap(), dec!(1000));
+
+        let fill = fill_batch(&orderbook, &batch(&[40, 400], true)).await.unwrap().unwrap();
+        assert_eq!(fill.unallocated, vec![1]);
+        assert_eq!(fill.allocations.len(), 1);
+    }
+}
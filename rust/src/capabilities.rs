@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    // Longest operators first so `>=` is not read as `>`
+    const OPERATORS: [(&'static str, Comparison); 7] = [
+        ("==", Comparison::Eq),
+        ("!=", Comparison::Ne),
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("=", Comparison::Eq),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt),
+    ];
+
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Eq => ordering == Ordering::Equal,
+            Comparison::Ne => ordering != Ordering::Equal,
+            Comparison::Lt => ordering == Ordering::Less,
+            Comparison::Le => ordering != Ordering::Greater,
+            Comparison::Gt => ordering == Ordering::Greater,
+            Comparison::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// A capability an ask must advertise for a bid to match it, written as
+/// `key <op> value` (e.g. `context_length >= 32000`, `region = eu-west`) or
+/// as a bare key, which requires the capability to be present and not
+/// `false`. Values that both parse as numbers compare numerically; other
+/// values only support `=` and `!=` and compare case-insensitively.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Requirement {
+    pub key: String,
+    pub comparison: Comparison,
+    /// `None` for a bare key
+    pub value: Option<String>,
+}
+
+impl FromStr for Requirement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let operator = Comparison::OPERATORS
+            .iter()
+            .filter_map(|(op, comparison)| s.find(op).map(|at| (at, *op, *comparison)))
+            .min_by_key(|(at, op, _)| (*at, std::cmp::Reverse(op.len())));
+
+        let (key, comparison, value) = match operator {
+            Some((at, op, comparison)) => {
+                let value = s[at + op.len()..].trim();
+                if value.is_empty() {
+                    return Err(format!("Capability requirement {:?} has no value", s));
+                }
+                (s[..at].trim(), comparison, Some(value.to_string()))
+            }
+            None => (s, Comparison::Eq, None),
+        };
+
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(format!("Invalid capability requirement {:?}", s));
+        }
+        Ok(Self {
+            key: key.to_ascii_lowercase(),
+            comparison,
+            value,
+        })
+    }
+}
+
+impl Requirement {
+    pub fn is_met(&self, capabilities: &HashMap<String, String>) -> bool {
+        let Some(actual) = capabilities
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(&self.key))
+            .map(|(_, value)| value.trim())
+        else {
+            return false;
+        };
+
+        let Some(expected) = &self.value else {
+            return !actual.eq_ignore_ascii_case("false");
+        };
+
+        match (actual.parse::<f64>(), expected.parse::<f64>()) {
+            (Ok(actual), Ok(expected)) => actual
+                .partial_cmp(&expected)
+                .is_some_and(|ordering| self.comparison.holds(ordering)),
+            _ => match self.comparison {
+                Comparison::Eq => actual.eq_ignore_ascii_case(expected),
+                Comparison::Ne => !actual.eq_ignore_ascii_case(expected),
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Whether `capabilities` satisfy every requirement.
+pub fn meets_all(requirements: &[Requirement], capabilities: &HashMap<String, String>) -> bool {
+    requirements.iter().all(|r| r.is_met(capabilities))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities() -> HashMap<String, String> {
+        [
+            ("context_length", "32768"),
+            ("Quantization", "FP16"),
+            ("function_calling", "true"),
+            ("streaming", "false"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+
+    fn met(requirement: &str) -> bool {
+        requirement.parse::<Requirement>().unwrap().is_met(&capabilities())
+    }
+
+    #[test]
+    fn test_parse_requirements() {
+        let requirement: Requirement = " Context_Length >= 32000 ".parse().unwrap();
+        assert_eq!(requirement.key, "context_length");
+        assert_eq!(requirement.comparison, Comparison::Ge);
+        assert_eq!(requirement.value.as_deref(), Some("32000"));
+
+        assert_eq!("region==eu".parse::<Requirement>().unwrap().comparison, Comparison::Eq);
+        assert!("context_length >=".parse::<Requirement>().is_err());
+        assert!(">= 5".parse::<Requirement>().is_err());
+        assert!("two words".parse::<Requirement>().is_err());
+    }
+
+    #[test]
+    fn test_numeric_comparisons_are_typed() {
+        // 32768 >= 4096 numerically even though "32768" < "4096" as strings
+        assert!(met("context_length >= 4096"));
+        assert!(met("context_length > 32000.5"));
+        assert!(!met("context_length < 32768"));
+        assert!(met("context_length = 32768.0"));
+        assert!(!met("context_length >= 65536"));
+    }
+
+    #[test]
+    fn test_string_and_presence_requirements() {
+        assert!(met("quantization = fp16"));
+        assert!(met("quantization != int8"));
+        assert!(!met("quantization > fp8"));
+        assert!(met("function_calling"));
+        assert!(!met("streaming"));
+        assert!(!met("region = eu-west"));
+        assert!(!met("region != eu-west"));
+    }
+}
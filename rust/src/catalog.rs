@@ -0,0 +1,321 @@
+use crate::tokenizer::{BpeTokenizer, ByteLengthCounter, TokenCounter};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// A model providers may quote and bids may request.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelSpec {
+    pub id: String,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Largest prompt, in tokens, a bid for the model may carry
+    pub context_window: u32,
+    pub multiplier: Decimal,
+    /// BPE vocabulary (`.tiktoken` format) prompts are counted with,
+    /// relative to the catalog file; without one, four bytes make a token
+    #[serde(default)]
+    pub vocab: Option<PathBuf>,
+}
+
+/// A GPU type providers may serve on.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GpuSpec {
+    pub id: String,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    pub multiplier: Decimal,
+}
+
+#[derive(Deserialize)]
+struct CatalogFile {
+    models: Vec<ModelSpec>,
+    gpus: Vec<GpuSpec>,
+}
+
+/// Canonical model and GPU identifiers with their aliases, context windows
+/// and pricing multipliers. Names are matched case-insensitively.
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    models: Vec<ModelSpec>,
+    gpus: Vec<GpuSpec>,
+    // Token counter of each entry in models
+    counters: Vec<Arc<dyn TokenCounter>>,
+    // Normalized ID or alias -> index into models/gpus
+    model_names: HashMap<String, usize>,
+    gpu_names: HashMap<String, usize>,
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().to_ascii_lowercase()
+}
+
+fn index_names<'a>(
+    kind: &str,
+    entries: impl Iterator<Item = (&'a str, &'a [String])>,
+) -> Result<HashMap<String, usize>, String> {
+    let mut names = HashMap::new();
+    for (index, (id, aliases)) in entries.enumerate() {
+        for name in std::iter::once(id).chain(aliases.iter().map(String::as_str)) {
+            let name = normalize(name);
+            if name.is_empty() {
+                return Err(format!("{} {:?} has an empty name", kind, id));
+            }
+            if names.insert(name.clone(), index).is_some() {
+                return Err(format!("{} name {:?} is defined more than once", kind, name));
+            }
+        }
+    }
+    Ok(names)
+}
+
+impl Catalog {
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        Self::parse(json, Path::new("."))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        Self::parse(&json, base).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // Vocabulary paths are resolved against `base`
+    fn parse(json: &str, base: &Path) -> Result<Self, String> {
+        let file: CatalogFile = serde_json::from_str(json).map_err(|e| e.to_string())?;
+
+        for model in &file.models {
+            if model.context_window == 0 {
+                return Err(format!("model {:?} needs a context_window", model.id));
+            }
+            if model.multiplier <= Decimal::ZERO {
+                return Err(format!("model {:?} needs a positive multiplier", model.id));
+            }
+        }
+        for gpu in &file.gpus {
+            if gpu.multiplier <= Decimal::ZERO {
+                return Err(format!("GPU {:?} needs a positive multiplier", gpu.id));
+            }
+        }
+
+        let counters = file.models
+            .iter()
+            .map(|model| -> Result<Arc<dyn TokenCounter>, String> {
+                Ok(match &model.vocab {
+                    Some(vocab) => Arc::new(BpeTokenizer::load(&base.join(vocab))?),
+                    None => Arc::new(ByteLengthCounter),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        let model_names = index_names("model", file.models.iter().map(|m| (m.id.as_str(), m.aliases.as_slice())))?;
+        let gpu_names = index_names("GPU", file.gpus.iter().map(|g| (g.id.as_str(), g.aliases.as_slice())))?;
+
+        Ok(Self {
+            models: file.models,
+            gpus: file.gpus,
+            counters,
+            model_names,
+            gpu_names,
+        })
+    }
+
+    /// The catalog shipped with the matcher, used when no file is configured.
+    pub fn builtin() -> Self {
+        Self::from_json(include_str!("../catalog.json")).expect("built-in catalog is valid")
+    }
+
+    pub fn model(&self, name: &str) -> Option<&ModelSpec> {
+        self.model_names.get(&normalize(name)).map(|&i| &self.models[i])
+    }
+
+    /// How prompts for `model` are counted; models the catalog does not
+    /// know are estimated at four bytes per token.
+    pub fn token_counter(&self, model: &str) -> Arc<dyn TokenCounter> {
+        match self.model_names.get(&normalize(model)) {
+            Some(&i) => self.counters[i].clone(),
+            None => Arc::new(ByteLengthCounter),
+        }
+    }
+
+    pub fn count_tokens(&self, model: &str, text: &str) -> u32 {
+        self.token_counter(model).count(text)
+    }
+
+    pub fn gpu(&self, name: &str) -> Option<&GpuSpec> {
+        self.gpu_names.get(&normalize(name)).map(|&i| &self.gpus[i])
+    }
+
+    pub fn models(&self) -> &[ModelSpec] {
+        &self.models
+    }
+
+    /// Combined pricing multiplier of a model on a GPU type. Names the
+    /// catalog does not know are priced at 1x.
+    pub fn multiplier(&self, model: &str, gpu_type: &str) -> Decimal {
+        let model = self.model(model).map_or(Decimal::ONE, |m| m.multiplier);
+        let gpu = self.gpu(gpu_type).map_or(Decimal::ONE, |g| g.multiplier);
+        model * gpu
+    }
+
+    /// Canonical ID of a GPU type. Types the catalog does not know are
+    /// kept under their normalized name, so asks on new hardware can still
+    /// be listed and bid for, priced at 1x.
+    pub fn gpu_id(&self, name: &str) -> Result<String, String> {
+        match self.gpu(name) {
+            Some(gpu) => Ok(gpu.id.clone()),
+            None if normalize(name).is_empty() => Err("GPU type is required".to_string()),
+            None => Ok(normalize(name)),
+        }
+    }
+
+    /// Canonical IDs for a provider's quote, or why it cannot be listed.
+    pub fn resolve_ask(&self, model: &str, gpu_type: &str) -> Result<(String, String), String> {
+        let model = self.model(model).ok_or_else(|| format!("Unknown model: {}", model))?;
+        Ok((model.id.clone(), self.gpu_id(gpu_type)?))
+    }
+
+    /// Canonical model ID and GPU types for a bid, checking that each of its
+    /// prompts fits the model's context window.
+    pub fn resolve_bid<'a>(
+        &self,
+        model: &str,
+        gpu_types: &[String],
+        prompts: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(String, Vec<String>), String> {
+        let spec = self.model(model).ok_or_else(|| format!("Unknown model: {}", model))?;
+        let counter = self.token_counter(model);
+        for prompt in prompts {
+            let tokens = counter.count(prompt);
+            if tokens > spec.context_window {
+                return Err(format!(
+                    "Prompt of {} tokens exceeds the {} token context window of {}",
+                    tokens, spec.context_window, spec.id
+                ));
+            }
+        }
+
+        let gpu_types = gpu_types
+            .iter()
+            .map(|name| self.gpu_id(name))
+            .collect::<Result<_, _>>()?;
+        Ok((spec.id.clone(), gpu_types))
+    }
+}
+
+fn slot() -> &'static RwLock<Arc<Catalog>> {
+    static CURRENT: OnceLock<RwLock<Arc<Catalog>>> = OnceLock::new();
+    CURRENT.get_or_init(|| RwLock::new(Arc::new(Catalog::builtin())))
+}
+
+/// The catalog in effect; the built-in one until another is installed.
+pub fn current() -> Arc<Catalog> {
+    slot().read().unwrap().clone()
+}
+
+pub fn install(catalog: Catalog) {
+    *slot().write().unwrap() = Arc::new(catalog);
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reinstalls the catalog at `path` whenever its modification time changes.
+/// A file that fails to load is logged and the previous catalog stays in
+/// effect.
+pub fn spawn_reloader(path: PathBuf, interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut loaded = modified(&path);
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let seen = modified(&path);
+            if seen.is_none() || seen == loaded {
+                continue;
+            }
+            loaded = seen;
+            match Catalog::load(&path) {
+                Ok(catalog) => {
+                    println!("Reloaded model catalog from {}", path.display());
+                    install(catalog);
+                }
+                Err(e) => eprintln!("Keeping previous model catalog: {}", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_aliases_resolve_to_canonical_ids() {
+        let catalog = Catalog::builtin();
+        assert_eq!(catalog.model(" GPT-4 ").unwrap().id, "gpt4");
+        assert_eq!(catalog.multiplier("gpt-4", "A100"), dec!(3));
+        assert_eq!(catalog.multiplier("unknown", "h100"), dec!(2));
+
+        assert_eq!(
+            catalog.resolve_ask("claude-2", "nvidia-h100").unwrap(),
+            ("claude-v2".to_string(), "h100".to_string())
+        );
+        assert_eq!(catalog.resolve_ask("gpt4", "NVIDIA-L40S").unwrap().1, "l40s");
+        // Unknown GPU types are listed under their normalized name at 1x
+        assert_eq!(catalog.resolve_ask("gpt4", " TPU ").unwrap().1, "tpu");
+        assert_eq!(catalog.multiplier("gpt4", "tpu"), dec!(2));
+        assert!(catalog.resolve_ask("gpt4", " ").is_err());
+        assert!(catalog.resolve_ask("llama", "a100").is_err());
+    }
+
+    #[test]
+    fn test_bid_prompts_must_fit_context_window() {
+        let catalog = Catalog::builtin();
+        let (model, gpus) = catalog.resolve_bid("gpt-3", &["A100".to_string()], ["hi"]).unwrap();
+        assert_eq!((model.as_str(), gpus), ("gpt3", vec!["a100".to_string()]));
+
+        let long = "x".repeat(4096 * 4 + 1);
+        assert!(catalog.resolve_bid("gpt3", &[], [long.as_str()]).is_err());
+        assert!(catalog.resolve_bid("gpt4", &[], [long.as_str()]).is_ok());
+    }
+
+    #[test]
+    fn test_vocab_is_loaded_relative_to_catalog() {
+        let dir = std::env::temp_dir().join(format!("catalog-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("tiny.tiktoken"), "aGVsbG8= 0\n").unwrap();
+        std::fs::write(
+            dir.join("catalog.json"),
+            r#"{
+                "models": [{ "id": "tiny", "context_window": 2, "multiplier": "1", "vocab": "tiny.tiktoken" }],
+                "gpus": []
+            }"#,
+        ).unwrap();
+        let catalog = Catalog::load(&dir.join("catalog.json"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let catalog = catalog.unwrap();
+        assert_eq!(catalog.count_tokens("TINY", "hello"), 1);
+        assert!(catalog.resolve_bid("tiny", &[], ["hello hello"]).is_err());
+        assert_eq!(catalog.count_tokens("unknown", "hello"), 2);
+    }
+
+    #[test]
+    fn test_rejects_conflicting_names() {
+        let json = r#"{
+            "models": [
+                { "id": "a", "context_window": 10, "multiplier": "1" },
+                { "id": "b", "aliases": ["A"], "context_window": 10, "multiplier": "1" }
+            ],
+            "gpus": []
+        }"#;
+        assert!(Catalog::from_json(json).unwrap_err().contains("more than once"));
+    }
+}
@@ -1,6 +1,5 @@
-use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant, SystemTime}};
 use tokio::sync::RwLock;
-use rust_decimal::Decimal;
 
 #[derive(Debug)]
 struct ProviderHealth {
@@ -9,13 +8,24 @@ struct ProviderHealth {
     state: CircuitState,
 }
 
-#[derive(Debug, PartialEq)]
-enum CircuitState {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitState {
     Closed,
     Open,
     HalfOpen,
 }
 
+/// A provider's circuit as of one moment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitSnapshot {
+    pub state: CircuitState,
+    pub failures: u32,
+    /// Only set while failures are being counted
+    pub last_failure: Option<SystemTime>,
+    /// When an open circuit lets a trial request through
+    pub reset_at: Option<SystemTime>,
+}
+
 pub struct CircuitBreaker {
     providers: Arc<RwLock<HashMap<String, ProviderHealth>>>,
     failure_threshold: u32,
@@ -82,19 +92,53 @@ impl CircuitBreaker {
             }
         }
     }
+
+    pub async fn get_status(&self, provider_id: &str) -> CircuitSnapshot {
+        let providers = self.providers.read().await;
+        let Some(health) = providers.get(provider_id) else {
+            return CircuitSnapshot {
+                state: CircuitState::Closed,
+                failures: 0,
+                last_failure: None,
+                reset_at: None,
+            };
+        };
+
+        let last_failure = SystemTime::now() - health.last_failure.elapsed();
+        CircuitSnapshot {
+            state: health.state,
+            failures: health.failures,
+            last_failure: (health.failures > 0).then_some(last_failure),
+            reset_at: (health.state == CircuitState::Open).then_some(last_failure + self.reset_timeout),
+        }
+    }
 }
 
-// Add to orderbook.rs:
-impl OrderBook {
-    pub async fn find_matches_with_circuit_breaker(
-        &mut self, 
-        bid: &Bid,
-        circuit_breaker: &CircuitBreaker
-    ) -> redis::RedisResult<Vec<Ask>> {
-        let mut matches = self.find_matches(bid)?;
-        matches.retain(|ask| {
-            circuit_breaker.can_execute(&ask.provider_id).await
-        });
-        Ok(matches)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_opens_after_threshold_and_recovers() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(50), Duration::ZERO);
+        breaker.record_failure("p1").await;
+        assert!(breaker.can_execute("p1").await);
+        breaker.record_failure("p1").await;
+        assert!(!breaker.can_execute("p1").await);
+
+        let status = breaker.get_status("p1").await;
+        assert_eq!((status.state, status.failures), (CircuitState::Open, 2));
+        assert!(status.reset_at.unwrap() > status.last_failure.unwrap());
+        assert!(breaker.can_execute("p2").await);
+
+        // After the reset timeout one trial goes through, and a success
+        // closes the circuit
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(breaker.can_execute("p1").await);
+        assert_eq!(breaker.get_status("p1").await.state, CircuitState::HalfOpen);
+        breaker.record_success("p1").await;
+
+        let status = breaker.get_status("p1").await;
+        assert_eq!((status.state, status.failures, status.last_failure), (CircuitState::Closed, 0, None));
     }
-}
\ No newline at end of file
+}
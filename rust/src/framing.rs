@@ -0,0 +1,211 @@
+use futures::stream::{self, Stream, StreamExt};
+use std::collections::VecDeque;
+use std::fmt::Display;
+
+/// How a streamed response body delimits its messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// One JSON document per line
+    Ndjson,
+    /// Server-sent events: `data:` lines, ending at a blank line
+    Sse,
+}
+
+/// Reassembles messages from body chunks that may split lines, or UTF-8
+/// characters, at any byte.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    framing: Framing,
+    max_line_bytes: usize,
+    // Bytes after the last complete line
+    buffer: Vec<u8>,
+    // `data:` lines of the SSE event being read
+    data: Vec<String>,
+    // Set while dropping the rest of an over-long line
+    skipping: bool,
+}
+
+impl FrameDecoder {
+    pub fn new(framing: Framing, max_line_bytes: usize) -> Self {
+        Self {
+            framing,
+            max_line_bytes,
+            buffer: Vec::new(),
+            data: Vec::new(),
+            skipping: false,
+        }
+    }
+
+    /// Adds a chunk of the body and returns the messages it completed.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Result<String, String>> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut messages = Vec::new();
+        // A newline byte never occurs inside a multi-byte UTF-8 sequence, so
+        // complete lines always hold whole characters
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            if std::mem::take(&mut self.skipping) {
+                continue;
+            }
+            if let Some(message) = self.line(&line[..end]) {
+                messages.push(message);
+            }
+        }
+
+        if self.buffer.len() > self.max_line_bytes {
+            self.buffer.clear();
+            if !self.skipping {
+                self.skipping = true;
+                messages.push(Err(format!("Stream line exceeds {} bytes", self.max_line_bytes)));
+            }
+        }
+        messages
+    }
+
+    /// Ends the body, returning a final message that was not terminated by
+    /// a newline (or, for SSE, by a blank line).
+    pub fn finish(&mut self) -> Option<Result<String, String>> {
+        let rest = std::mem::take(&mut self.buffer);
+        let skipped = std::mem::take(&mut self.skipping);
+        let last = if rest.is_empty() || skipped { None } else { self.line(&rest) };
+        last.or_else(|| self.dispatch())
+    }
+
+    fn line(&mut self, line: &[u8]) -> Option<Result<String, String>> {
+        if line.len() > self.max_line_bytes {
+            self.data.clear();
+            return Some(Err(format!("Stream line exceeds {} bytes", self.max_line_bytes)));
+        }
+        let line = match std::str::from_utf8(line) {
+            Ok(line) => line.strip_suffix('\r').unwrap_or(line),
+            Err(e) => return Some(Err(format!("UTF-8 decode error: {}", e))),
+        };
+
+        match self.framing {
+            Framing::Ndjson => {
+                let line = line.trim();
+                (!line.is_empty()).then(|| Ok(line.to_string()))
+            }
+            Framing::Sse => {
+                if line.is_empty() {
+                    return self.dispatch();
+                }
+                // Comments start with a colon; fields other than data
+                // (event, id, retry) are not used
+                let (field, value) = line.split_once(':').unwrap_or((line, ""));
+                if field == "data" {
+                    self.data.push(value.strip_prefix(' ').unwrap_or(value).to_string());
+                }
+                None
+            }
+        }
+    }
+
+    // Completes the SSE event read so far
+    fn dispatch(&mut self) -> Option<Result<String, String>> {
+        if self.data.is_empty() {
+            return None;
+        }
+        Some(Ok(std::mem::take(&mut self.data).join("\n")))
+    }
+}
+
+/// Decodes a body stream into its messages. A body that fails ends the
+/// stream after yielding the error.
+pub fn frames<S, B, E>(body: S, decoder: FrameDecoder) -> impl Stream<Item = Result<String, String>>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: Display,
+{
+    let state = (Some(body), decoder, VecDeque::new());
+    stream::unfold(state, |(mut body, mut decoder, mut ready)| async move {
+        loop {
+            if let Some(message) = ready.pop_front() {
+                return Some((message, (body, decoder, ready)));
+            }
+            let chunk = body.as_mut()?.next().await;
+            match chunk {
+                Some(Ok(bytes)) => ready.extend(decoder.push(bytes.as_ref())),
+                Some(Err(e)) => {
+                    body = None;
+                    ready.push_back(Err(format!("Provider stream failed: {}", e)));
+                }
+                None => {
+                    body = None;
+                    ready.extend(decoder.finish());
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(framing: Framing, chunks: &[&[u8]]) -> Vec<Result<String, String>> {
+        let mut decoder = FrameDecoder::new(framing, 64);
+        let mut messages: Vec<_> = chunks.iter().flat_map(|c| decoder.push(c)).collect();
+        messages.extend(decoder.finish());
+        messages
+    }
+
+    fn ok(messages: &[&str]) -> Vec<Result<String, String>> {
+        messages.iter().map(|m| Ok(m.to_string())).collect()
+    }
+
+    #[test]
+    fn test_ndjson_across_chunks() {
+        let messages = decode(Framing::Ndjson, &[b"{\"a\":1}\n{\"b\"", b":2}\r\n\n{\"c\":3}\n{\"d\":4}"]);
+        assert_eq!(messages, ok(&["{\"a\":1}", "{\"b\":2}", "{\"c\":3}", "{\"d\":4}"]));
+    }
+
+    #[test]
+    fn test_split_utf8_character() {
+        let text = "{\"response\":\"héllo\"}\n".as_bytes();
+        // Split inside the two bytes of "é"
+        let split = text.iter().position(|b| *b == 0xC3).unwrap() + 1;
+        let messages = decode(Framing::Ndjson, &[&text[..split], &text[split..]]);
+        assert_eq!(messages, ok(&["{\"response\":\"héllo\"}"]));
+    }
+
+    #[test]
+    fn test_sse_events() {
+        let messages = decode(Framing::Sse, &[
+            b": keep-alive\n\nevent: message\nda",
+            b"ta: {\"x\":1}\n\ndata: first\ndata:second\n\n",
+            b"data: [DONE]\n\n",
+        ]);
+        assert_eq!(messages, ok(&["{\"x\":1}", "first\nsecond", "[DONE]"]));
+
+        // An event cut off at the end of the body is still delivered
+        assert_eq!(decode(Framing::Sse, &[b"data: last"]), ok(&["last"]));
+    }
+
+    #[test]
+    fn test_rejects_long_lines() {
+        let long = [b'x'; 100];
+        let tail = [&long[80..], b"\n{}\n".as_slice()].concat();
+        let messages = decode(Framing::Ndjson, &[&long[..40], &long[40..80], &tail]);
+        assert!(messages[0].as_ref().unwrap_err().contains("exceeds 64 bytes"));
+        // Decoding resumes at the next line
+        assert_eq!(messages[1..].to_vec(), ok(&["{}"]));
+    }
+
+    #[tokio::test]
+    async fn test_frames_from_stream() {
+        let body = stream::iter(vec![
+            Ok::<_, String>(b"{\"a\":".to_vec()),
+            Ok(b"1}\n".to_vec()),
+            Err("connection reset".to_string()),
+            Ok(b"{\"b\":2}\n".to_vec()),
+        ]);
+        let messages: Vec<_> = frames(body, FrameDecoder::new(Framing::Ndjson, 64)).collect().await;
+        assert_eq!(messages, vec![
+            Ok("{\"a\":1}".to_string()),
+            Err("Provider stream failed: connection reset".to_string()),
+        ]);
+    }
+}
@@ -0,0 +1,717 @@
+use crate::framing::{frames, FrameDecoder, Framing};
+use crate::latency::LatencyRouter;
+use crate::orderbook::{Ask, Bid};
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Longest line accepted from a provider stream
+const MAX_LINE_BYTES: usize = 1 << 20;
+
+// Bid metadata keys for generation settings passed on to the provider
+pub const MAX_TOKENS_KEY: &str = "max_tokens";
+pub const TEMPERATURE_KEY: &str = "temperature";
+// A JSON array of strings
+pub const STOP_KEY: &str = "stop";
+
+/// Wire format a provider serves inference in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// Ollama's `/api/generate`: JSON lines carrying `response` and `done`
+    #[default]
+    Ollama,
+    /// OpenAI-compatible `/v1/completions`: `data:` events carrying `choices`
+    #[serde(rename = "openai")]
+    OpenAi,
+}
+
+impl Protocol {
+    fn framing(self) -> Framing {
+        match self {
+            Protocol::Ollama => Framing::Ndjson,
+            Protocol::OpenAi => Framing::Sse,
+        }
+    }
+}
+
+/// Where the matcher forwards bids filled against a provider's ask.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProviderEndpoint {
+    pub url: String,
+    /// Sent as a bearer token
+    #[serde(default)]
+    pub auth_token: Option<String>,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Model name the endpoint serves, when it differs from the catalog ID
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+// Keeps auth tokens out of logs
+impl fmt::Debug for ProviderEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProviderEndpoint")
+            .field("url", &self.url)
+            .field("auth_token", &self.auth_token.as_ref().map(|_| "<redacted>"))
+            .field("protocol", &self.protocol)
+            .field("model", &self.model)
+            .finish()
+    }
+}
+
+/// Generation settings a bid passes on to the provider, read from its
+/// metadata.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GenerationOptions {
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f64>,
+    pub stop: Vec<String>,
+}
+
+impl GenerationOptions {
+    pub fn from_metadata(metadata: &HashMap<String, String>) -> Result<Self, String> {
+        let max_tokens = metadata
+            .get(MAX_TOKENS_KEY)
+            .map(|v| v.parse::<u32>().ok().filter(|t| *t > 0).ok_or("max_tokens must be a positive integer"))
+            .transpose()?;
+        let temperature = metadata
+            .get(TEMPERATURE_KEY)
+            .map(|v| {
+                v.parse::<f64>()
+                    .ok()
+                    .filter(|t| (0.0..=2.0).contains(t))
+                    .ok_or("temperature must be a number from 0 to 2")
+            })
+            .transpose()?;
+        let stop = metadata
+            .get(STOP_KEY)
+            .map(|v| serde_json::from_str(v).map_err(|_| "stop must be a JSON array of strings"))
+            .transpose()?
+            .unwrap_or_default();
+        Ok(Self { max_tokens, temperature, stop })
+    }
+
+    /// Adds the settings to a request body in the provider's protocol.
+    fn apply(&self, protocol: Protocol, body: &mut Value) {
+        let mut settings = serde_json::Map::new();
+        let max_tokens_field = match protocol {
+            Protocol::Ollama => "num_predict",
+            Protocol::OpenAi => "max_tokens",
+        };
+        if let Some(max_tokens) = self.max_tokens {
+            settings.insert(max_tokens_field.into(), json!(max_tokens));
+        }
+        if let Some(temperature) = self.temperature {
+            settings.insert("temperature".into(), json!(temperature));
+        }
+        if !self.stop.is_empty() {
+            settings.insert("stop".into(), json!(self.stop));
+        }
+
+        match protocol {
+            // Ollama takes sampling settings under `options`
+            Protocol::Ollama if !settings.is_empty() => body["options"] = Value::Object(settings),
+            Protocol::Ollama => {}
+            Protocol::OpenAi => body.as_object_mut().unwrap().extend(settings),
+        }
+    }
+}
+
+/// Output generated by a provider: the whole completion for a unary bid,
+/// or one piece of a stream.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Generated {
+    pub model: String,
+    pub created_at: String,
+    pub text: String,
+    pub done: bool,
+    pub done_reason: Option<String>,
+    /// Output tokens counted by the provider, when it reports them
+    pub output_tokens: Option<u64>,
+}
+
+/// Pieces of a streamed completion.
+pub type GeneratedStream = BoxStream<'static, Result<Generated, String>>;
+
+/// Forwards filled bids to provider endpoints and records each provider's
+/// latency (time to first token for streams) and request outcomes. Time to
+/// first token is also kept apart in `first_token`, since unary latencies
+/// cover whole completions.
+pub struct ProviderGateway {
+    client: reqwest::Client,
+    latency: Arc<LatencyRouter>,
+    first_token: Arc<LatencyRouter>,
+    request_timeout: Duration,
+    first_token_timeout: Duration,
+    idle_timeout: Duration,
+}
+
+impl ProviderGateway {
+    /// `request_timeout` bounds unary requests. Streams must produce their
+    /// first piece within `first_token_timeout` and each later piece within
+    /// `idle_timeout` of the one before, but are not bounded overall, since
+    /// generation may run long.
+    pub fn new(
+        latency: Arc<LatencyRouter>,
+        first_token: Arc<LatencyRouter>,
+        connect_timeout: Duration,
+        request_timeout: Duration,
+        first_token_timeout: Duration,
+        idle_timeout: Duration,
+    ) -> Self {
+        let client = reqwest::Client::builder()
+            .connect_timeout(connect_timeout)
+            .build()
+            .expect("HTTP client configuration is valid");
+        Self {
+            client,
+            latency,
+            first_token,
+            request_timeout,
+            first_token_timeout,
+            idle_timeout,
+        }
+    }
+
+    /// Runs a bid to completion on the ask's provider.
+    pub async fn complete(&self, ask: &Ask, bid: &Bid) -> Result<Generated, String> {
+        let started = Instant::now();
+        let result = async {
+            let (protocol, response) = self.send(ask, bid, false).await?;
+            let body: Value = response
+                .json()
+                .await
+                .map_err(|e| format!("Invalid provider response: {}", e))?;
+            Ok(Generated {
+                done: true,
+                ..parse_generated(protocol, &body)?
+            })
+        }
+        .await;
+
+        if result.is_ok() {
+            self.latency.record_latency(&ask.provider_id, started.elapsed()).await;
+        }
+        self.latency.record_outcome(&ask.provider_id, result.is_ok()).await;
+        result
+    }
+
+    /// Starts streaming a bid from the ask's provider, returning once its
+    /// first piece arrives. A provider that fails or stalls before then has
+    /// delivered nothing, so the bid can still go elsewhere. The stream ends
+    /// after the piece marked `done`; a provider that stops before then, or
+    /// goes `idle_timeout` without sending a piece, yields an error.
+    pub async fn stream(
+        &self,
+        ask: &Ask,
+        bid: &Bid,
+    ) -> Result<GeneratedStream, String> {
+        let started = Instant::now();
+        // Connecting, the response headers and the first piece share one
+        // deadline, so a provider that never answers is given up on too
+        let first = async {
+            let (protocol, response) = match self.send(ask, bid, true).await {
+                Ok(sent) => sent,
+                Err(e) => {
+                    self.latency.record_outcome(&ask.provider_id, false).await;
+                    return Err(e);
+                }
+            };
+            let decoder = FrameDecoder::new(protocol.framing(), MAX_LINE_BYTES);
+            let pieces = frames(response.bytes_stream(), decoder)
+                .map(move |message| message.and_then(|payload| parse_message(protocol, &payload)))
+                .boxed();
+            let pieces = idle_limited(pieces, self.idle_timeout);
+
+            let mut pieces = track_outcome(pieces, self.latency.clone(), ask.provider_id.clone());
+            let first = pieces.next().await;
+            Ok((first, pieces))
+        };
+        match tokio::time::timeout(self.first_token_timeout, first).await {
+            Ok(Err(e)) => Err(e),
+            Ok(Ok((Some(Ok(first)), pieces))) => {
+                // Time to first token
+                let elapsed = started.elapsed();
+                self.latency.record_latency(&ask.provider_id, elapsed).await;
+                self.first_token.record_latency(&ask.provider_id, elapsed).await;
+                Ok(stream::once(async { Ok(first) }).chain(pieces).boxed())
+            }
+            Ok(Ok((Some(Err(e)), _))) => Err(e),
+            Ok(Ok((None, _))) => Err("Provider stream ended before completion".to_string()),
+            Err(_) => {
+                self.latency.record_outcome(&ask.provider_id, false).await;
+                Err(format!(
+                    "Provider sent nothing within {} ms",
+                    self.first_token_timeout.as_millis()
+                ))
+            }
+        }
+    }
+
+    async fn send(&self, ask: &Ask, bid: &Bid, stream: bool) -> Result<(Protocol, reqwest::Response), String> {
+        let endpoint = ask.endpoint
+            .as_ref()
+            .ok_or_else(|| format!("Provider {} has no registered endpoint", ask.provider_id))?;
+
+        let mut request = self.client
+            .post(&endpoint.url)
+            .json(&request_body(ask, endpoint, bid, stream)?);
+        if let Some(token) = &endpoint.auth_token {
+            request = request.bearer_auth(token);
+        }
+        if !stream {
+            request = request.timeout(self.request_timeout);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("Provider request failed: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("Provider returned {}", response.status()));
+        }
+        Ok((endpoint.protocol, response))
+    }
+}
+
+/// The generation request for a bid, in the endpoint's protocol.
+fn request_body(ask: &Ask, endpoint: &ProviderEndpoint, bid: &Bid, stream: bool) -> Result<Value, String> {
+    let mut body = json!({
+        "model": endpoint.model.as_ref().unwrap_or(&bid.model),
+        "prompt": bid.prompt,
+        "stream": stream,
+    });
+    // Providers default to short outputs, so the output is always limited,
+    // to what the bid's hold pays for unless it asks for less
+    let mut options = GenerationOptions::from_metadata(&bid.metadata)?;
+    let allowance = bid.output_allowance(ask).max(1);
+    options.max_tokens = Some(options.max_tokens.map_or(allowance, |t| t.min(allowance)));
+    options.apply(endpoint.protocol, &mut body);
+    Ok(body)
+}
+
+/// Fails a stream whose provider goes `idle` without sending a piece after
+/// its first, which the first-token deadline already bounds.
+fn idle_limited(pieces: GeneratedStream, idle: Duration) -> GeneratedStream {
+    stream::unfold(Some((pieces, true)), move |state| async move {
+        let (mut pieces, first) = state?;
+        let next = if first {
+            pieces.next().await
+        } else {
+            match tokio::time::timeout(idle, pieces.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    let error = format!("Provider sent nothing for {} ms", idle.as_millis());
+                    return Some((Err(error), None));
+                }
+            }
+        };
+        next.map(|piece| (piece, Some((pieces, false))))
+    })
+    .boxed()
+}
+
+/// Records a stream's outcome once it completes, fails or ends early.
+fn track_outcome(
+    pieces: BoxStream<'static, Result<Generated, String>>,
+    latency: Arc<LatencyRouter>,
+    provider_id: String,
+) -> BoxStream<'static, Result<Generated, String>> {
+    stream::unfold(Some(pieces), move |pieces| {
+        let latency = latency.clone();
+        let provider_id = provider_id.clone();
+        async move {
+            let mut pieces = pieces?;
+            match pieces.next().await {
+                Some(Ok(piece)) if piece.done => {
+                    latency.record_outcome(&provider_id, true).await;
+                    Some((Ok(piece), None))
+                }
+                Some(Ok(piece)) => Some((Ok(piece), Some(pieces))),
+                Some(Err(e)) => {
+                    latency.record_outcome(&provider_id, false).await;
+                    Some((Err(e), None))
+                }
+                None => {
+                    latency.record_outcome(&provider_id, false).await;
+                    Some((Err("Provider stream ended before completion".to_string()), None))
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Reads one streamed message.
+fn parse_message(protocol: Protocol, payload: &str) -> Result<Generated, String> {
+    // OpenAI-compatible streams end with a `[DONE]` event
+    if protocol == Protocol::OpenAi && payload == "[DONE]" {
+        return Ok(Generated {
+            done: true,
+            ..Default::default()
+        });
+    }
+    let value: Value = serde_json::from_str(payload)
+        .map_err(|e| format!("JSON parse error: {}", e))?;
+    parse_generated(protocol, &value)
+}
+
+/// Reads generated output from a provider's JSON body or stream event.
+fn parse_generated(protocol: Protocol, value: &Value) -> Result<Generated, String> {
+    if let Some(error) = value.get("error") {
+        let message = error
+            .get("message")
+            .and_then(Value::as_str)
+            .or_else(|| error.as_str())
+            .unwrap_or("unknown error");
+        return Err(format!("Provider error: {}", message));
+    }
+
+    let string = |value: &Value| value.as_str().map(String::from);
+    match protocol {
+        Protocol::Ollama => Ok(Generated {
+            model: string(&value["model"]).unwrap_or_default(),
+            created_at: string(&value["created_at"]).unwrap_or_default(),
+            text: string(&value["response"]).unwrap_or_default(),
+            done: value["done"].as_bool().unwrap_or(false),
+            done_reason: string(&value["done_reason"]),
+            // Ollama reports the generated token count on its final chunk
+            output_tokens: value["eval_count"].as_u64(),
+        }),
+        Protocol::OpenAi => {
+            let choice = &value["choices"][0];
+            // Completions carry `text`; chat completions a message or delta
+            let text = string(&choice["text"])
+                .or_else(|| string(&choice["message"]["content"]))
+                .or_else(|| string(&choice["delta"]["content"]))
+                .unwrap_or_default();
+            let done_reason = string(&choice["finish_reason"]);
+            Ok(Generated {
+                model: string(&value["model"]).unwrap_or_default(),
+                created_at: value["created"].as_u64().map(|t| t.to_string()).unwrap_or_default(),
+                text,
+                done: done_reason.is_some(),
+                done_reason,
+                output_tokens: value["usage"]["completion_tokens"].as_u64(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::test_ask;
+    use rust_decimal_macros::dec;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Answers one request with `body` and returns the URL it listens on.
+    /// `None` sends the headers of a chunked response and then stalls.
+    async fn serve_once(body: Option<&'static str>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/generate", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            read_request(&mut socket).await;
+            let Some(body) = body else {
+                let headers = "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n";
+                socket.write_all(headers.as_bytes()).await.unwrap();
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                return;
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+        url
+    }
+
+    /// Answers one request with a streamed `first` piece and then stalls.
+    async fn serve_then_stall(first: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/generate", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            read_request(&mut socket).await;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n{:x}\r\n{}\r\n",
+                first.len(),
+                first
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+        url
+    }
+
+    // Reads through the JSON request body before answering
+    async fn read_request(socket: &mut tokio::net::TcpStream) {
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 1024];
+        while !request.ends_with(b"}") {
+            let n = socket.read(&mut buffer).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buffer[..n]);
+        }
+    }
+
+    /// Accepts one connection and never answers it.
+    async fn serve_silently() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/generate", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+        url
+    }
+
+    fn gateway(latency: Arc<LatencyRouter>) -> ProviderGateway {
+        gateway_with_first_token(latency, Arc::new(LatencyRouter::new()))
+    }
+
+    fn gateway_with_first_token(latency: Arc<LatencyRouter>, first_token: Arc<LatencyRouter>) -> ProviderGateway {
+        ProviderGateway::new(
+            latency,
+            first_token,
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            Duration::from_millis(200),
+            Duration::from_millis(200),
+        )
+    }
+
+    fn endpoint(url: String) -> Option<ProviderEndpoint> {
+        Some(ProviderEndpoint {
+            url,
+            auth_token: Some("secret".into()),
+            protocol: Protocol::Ollama,
+            model: None,
+        })
+    }
+
+    fn ask(endpoint: Option<ProviderEndpoint>) -> Ask {
+        Ask {
+            endpoint,
+            ..test_ask("p1", dec!(0.001))
+        }
+    }
+
+    fn bid() -> Bid {
+        Bid {
+            model: "gpt4".into(),
+            prompt: "Hello".into(),
+            max_price: dec!(0.002),
+            max_latency: 1000,
+            timestamp: 0,
+            user_id: "u1".into(),
+            required_credits: Some(dec!(10)),
+            metadata: HashMap::new(),
+            gpu_types: Vec::new(),
+            requirements: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_complete_records_outcomes() {
+        let latency = Arc::new(LatencyRouter::new());
+        let gateway = gateway(latency.clone());
+
+        let url = serve_once(Some(r#"{"model":"gpt4","response":"Hi there","done":true,"eval_count":3}"#)).await;
+        let generated = gateway.complete(&ask(endpoint(url)), &bid()).await.unwrap();
+        assert_eq!(generated.text, "Hi there");
+        assert_eq!(generated.output_tokens, Some(3));
+        assert!(generated.done);
+
+        assert!(gateway.complete(&ask(None), &bid()).await.is_err());
+
+        let metrics = latency.get_metrics("p1", Duration::from_secs(60)).await;
+        assert_eq!((metrics.samples, metrics.successes, metrics.failures), (1, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_stream_waits_for_first_piece() {
+        let latency = Arc::new(LatencyRouter::new());
+        let gateway = gateway(latency.clone());
+
+        let url = serve_once(Some("{\"response\":\"Hi\",\"done\":false}\n{\"response\":\"!\",\"done\":true}\n")).await;
+        let pieces: Vec<_> = gateway.stream(&ask(endpoint(url)), &bid()).await.unwrap().collect().await;
+        let text: String = pieces.into_iter().map(|p| p.unwrap().text).collect();
+        assert_eq!(text, "Hi!");
+
+        // A provider that accepts the request but never streams is given up on
+        let url = serve_once(None).await;
+        let err = gateway.stream(&ask(endpoint(url)), &bid()).await.err().unwrap();
+        assert!(err.contains("within 200 ms"));
+
+        let metrics = latency.get_metrics("p1", Duration::from_secs(60)).await;
+        assert_eq!((metrics.successes, metrics.failures), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_first_token_latency_leaves_out_unary_completions() {
+        let latency = Arc::new(LatencyRouter::new());
+        let first_token = Arc::new(LatencyRouter::new());
+        let gateway = gateway_with_first_token(latency.clone(), first_token.clone());
+
+        let url = serve_once(Some(r#"{"response":"Hi","done":true}"#)).await;
+        gateway.complete(&ask(endpoint(url)), &bid()).await.unwrap();
+        assert!(first_token.recent_percentile("p1", 0.5).await.is_none());
+
+        let url = serve_once(Some("{\"response\":\"Hi\",\"done\":true}\n")).await;
+        gateway.stream(&ask(endpoint(url)), &bid()).await.unwrap().collect::<Vec<_>>().await;
+        assert_eq!(first_token.get_metrics("p1", Duration::from_secs(60)).await.samples, 1);
+        assert_eq!(latency.get_metrics("p1", Duration::from_secs(60)).await.samples, 2);
+    }
+
+    #[tokio::test]
+    async fn test_stream_gives_up_on_provider_without_headers() {
+        let latency = Arc::new(LatencyRouter::new());
+        let gateway = gateway(latency.clone());
+
+        let url = serve_silently().await;
+        let started = Instant::now();
+        let err = gateway.stream(&ask(endpoint(url)), &bid()).await.err().unwrap();
+        assert!(err.contains("within 200 ms"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let metrics = latency.get_metrics("p1", Duration::from_secs(60)).await;
+        assert_eq!((metrics.successes, metrics.failures), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_stream_fails_when_provider_stalls_mid_stream() {
+        let latency = Arc::new(LatencyRouter::new());
+        let gateway = gateway(latency.clone());
+
+        let url = serve_then_stall("{\"response\":\"Hi\",\"done\":false}\n").await;
+        let started = Instant::now();
+        let pieces: Vec<_> = gateway.stream(&ask(endpoint(url)), &bid()).await.unwrap().collect().await;
+        assert_eq!(pieces[0].as_ref().unwrap().text, "Hi");
+        assert!(pieces[1].as_ref().unwrap_err().contains("nothing for 200 ms"));
+        assert_eq!(pieces.len(), 2);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let metrics = latency.get_metrics("p1", Duration::from_secs(60)).await;
+        assert_eq!((metrics.successes, metrics.failures), (0, 1));
+    }
+
+    #[test]
+    fn test_request_names_served_model_and_limits_output() {
+        let mut endpoint = endpoint("http://p1/api/generate".into()).unwrap();
+        let ask = Ask {
+            credit_rate: Some(dec!(1)),
+            ..ask(None)
+        };
+        // 10 credits pay for the prompt and the rest in output tokens at 1
+        // credit each
+        let output = 10 - bid().estimated_tokens();
+        let body = request_body(&ask, &endpoint, &bid(), true).unwrap();
+        assert_eq!((body["model"].clone(), body["options"]["num_predict"].clone()), (json!("gpt4"), json!(output)));
+
+        endpoint.model = Some("llama3:70b".into());
+        let asking_less = Bid {
+            metadata: [(MAX_TOKENS_KEY.to_string(), "4".to_string())].into(),
+            ..bid()
+        };
+        let body = request_body(&ask, &endpoint, &asking_less, true).unwrap();
+        assert_eq!((body["model"].clone(), body["options"]["num_predict"].clone()), (json!("llama3:70b"), json!(4)));
+    }
+
+    #[test]
+    fn test_generation_options_per_protocol() {
+        let metadata: HashMap<String, String> = [
+            (MAX_TOKENS_KEY.to_string(), "64".to_string()),
+            (STOP_KEY.to_string(), r#"["\n\n"]"#.to_string()),
+        ]
+        .into();
+        let options = GenerationOptions::from_metadata(&metadata).unwrap();
+
+        let mut ollama = json!({ "model": "gpt4" });
+        options.apply(Protocol::Ollama, &mut ollama);
+        assert_eq!(ollama["options"], json!({ "num_predict": 64, "stop": ["\n\n"] }));
+
+        let mut openai = json!({ "model": "gpt4" });
+        options.apply(Protocol::OpenAi, &mut openai);
+        assert_eq!((openai["max_tokens"].clone(), openai["stop"].clone()), (json!(64), json!(["\n\n"])));
+
+        let mut plain = json!({ "model": "gpt4" });
+        GenerationOptions::default().apply(Protocol::Ollama, &mut plain);
+        assert_eq!(plain, json!({ "model": "gpt4" }));
+
+        let invalid: HashMap<String, String> = [(TEMPERATURE_KEY.to_string(), "hot".to_string())].into();
+        assert!(GenerationOptions::from_metadata(&invalid).is_err());
+    }
+
+    fn decode_chunk(protocol: Protocol, chunk: &[u8]) -> Vec<Result<Generated, String>> {
+        let mut decoder = FrameDecoder::new(protocol.framing(), MAX_LINE_BYTES);
+        let mut messages = decoder.push(chunk);
+        messages.extend(decoder.finish());
+        messages
+            .into_iter()
+            .map(|message| message.and_then(|payload| parse_message(protocol, &payload)))
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_ollama_lines() {
+        let chunk = b"{\"model\":\"llama\",\"response\":\"Hel\",\"done\":false}\n\
+                      {\"model\":\"llama\",\"response\":\"lo\",\"done\":true,\"done_reason\":\"stop\",\"eval_count\":2}\n";
+        let pieces: Vec<Generated> = decode_chunk(Protocol::Ollama, chunk)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(pieces[0].text, "Hel");
+        assert!(!pieces[0].done);
+        assert_eq!(pieces[1].done_reason.as_deref(), Some("stop"));
+        assert_eq!(pieces[1].output_tokens, Some(2));
+    }
+
+    #[test]
+    fn test_decode_openai_events() {
+        let chunk = b": keep-alive\n\
+                      data: {\"model\":\"gpt4\",\"created\":7,\"choices\":[{\"text\":\"Hi\",\"finish_reason\":null}]}\n\n\
+                      data: {\"choices\":[{\"delta\":{\"content\":\"!\"},\"finish_reason\":\"stop\"}],\"usage\":{\"completion_tokens\":2}}\n\n\
+                      data: [DONE]\n\n";
+        let pieces: Vec<Generated> = decode_chunk(Protocol::OpenAi, chunk)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(pieces.len(), 3);
+        assert_eq!((pieces[0].text.as_str(), pieces[0].created_at.as_str()), ("Hi", "7"));
+        assert!(!pieces[0].done);
+        assert_eq!(pieces[1].text, "!");
+        assert!(pieces[1].done);
+        assert_eq!(pieces[1].output_tokens, Some(2));
+        assert!(pieces[2].done);
+    }
+
+    #[test]
+    fn test_provider_errors_and_endpoint_debug() {
+        let error = serde_json::json!({ "error": { "message": "model not loaded" } });
+        assert_eq!(
+            parse_generated(Protocol::OpenAi, &error).unwrap_err(),
+            "Provider error: model not loaded"
+        );
+        assert!(decode_chunk(Protocol::Ollama, b"not json\n")[0].is_err());
+
+        let endpoint: ProviderEndpoint = serde_json::from_str(
+            r#"{ "url": "http://p1:11434/api/generate", "auth_token": "secret" }"#,
+        ).unwrap();
+        assert_eq!(endpoint.protocol, Protocol::Ollama);
+        assert!(!format!("{:?}", endpoint).contains("secret"));
+    }
+}
@@ -1,89 +1,180 @@
-use std::{collections::HashMap, time::{Duration, Instant}};
+use chrono::{DateTime, Utc};
+use std::{collections::{HashMap, VecDeque}, time::Duration};
 use tokio::sync::RwLock;
 use std::sync::Arc;
 
-#[derive(Debug, Clone)]
+// Samples kept per provider, and how recent they must be to steer routing
+const MAX_SAMPLES: usize = 1000;
+const ROUTING_WINDOW: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Default)]
 struct LatencyStats {
-    p95_latency: Duration,
-    samples: Vec<Duration>,
-    last_update: Instant,
+    samples: VecDeque<(DateTime<Utc>, Duration)>,
+    // Whether each request succeeded
+    outcomes: VecDeque<(DateTime<Utc>, bool)>,
 }
 
 impl LatencyStats {
-    fn new() -> Self {
-        Self {
-            p95_latency: Duration::from_millis(100), // Default assumption
-            samples: Vec::with_capacity(100),
-            last_update: Instant::now(),
+    fn add_sample(&mut self, at: DateTime<Utc>, latency: Duration) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
         }
+        self.samples.push_back((at, latency));
     }
 
-    fn add_sample(&mut self, latency: Duration) {
-        self.samples.push(latency);
-        if self.samples.len() >= 100 {
-            self.samples.sort_unstable();
-            let p95_idx = (self.samples.len() as f64 * 0.95) as usize;
-            self.p95_latency = self.samples[p95_idx];
-            self.samples.clear();
+    fn add_outcome(&mut self, at: DateTime<Utc>, succeeded: bool) {
+        if self.outcomes.len() == MAX_SAMPLES {
+            self.outcomes.pop_front();
         }
-        self.last_update = Instant::now();
+        self.outcomes.push_back((at, succeeded));
+    }
+
+    /// Successful and failed requests since `since`.
+    fn outcomes_since(&self, since: DateTime<Utc>) -> (u32, u32) {
+        self.outcomes
+            .iter()
+            .filter(|(at, _)| *at >= since)
+            .fold((0, 0), |(ok, failed), (_, succeeded)| {
+                if *succeeded { (ok + 1, failed) } else { (ok, failed + 1) }
+            })
     }
 
-    fn is_stale(&self) -> bool {
-        self.last_update.elapsed() > Duration::from_secs(300)
+    /// Latencies recorded after `since`, sorted ascending.
+    fn sorted_since(&self, since: DateTime<Utc>) -> Vec<Duration> {
+        let mut latencies: Vec<Duration> = self.samples
+            .iter()
+            .filter(|(at, _)| *at >= since)
+            .map(|(_, latency)| *latency)
+            .collect();
+        latencies.sort_unstable();
+        latencies
     }
 }
 
+/// Nearest-rank percentile of ascending `sorted` latencies.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (sorted.len() as f64 * p).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[derive(Debug, Clone)]
+pub struct LatencyMetrics {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub samples: u32,
+    pub successes: u32,
+    pub failures: u32,
+    pub window_start: DateTime<Utc>,
+    pub window_end: DateTime<Utc>,
+}
+
+/// Measured request latencies and outcomes per provider.
+#[derive(Default)]
 pub struct LatencyRouter {
     stats: Arc<RwLock<HashMap<String, LatencyStats>>>,
 }
 
 impl LatencyRouter {
     pub fn new() -> Self {
-        Self {
-            stats: Arc::new(RwLock::new(HashMap::new()))
-        }
+        Self::default()
     }
 
     pub async fn record_latency(&self, provider_id: &str, latency: Duration) {
         let mut stats = self.stats.write().await;
-        let provider_stats = stats.entry(provider_id.to_string())
-            .or_insert_with(LatencyStats::new);
-        provider_stats.add_sample(latency);
+        stats.entry(provider_id.to_string())
+            .or_default()
+            .add_sample(Utc::now(), latency);
     }
 
-    pub async fn filter_by_latency(&self, asks: &mut Vec<Ask>, max_latency: Duration) {
+    pub async fn record_outcome(&self, provider_id: &str, succeeded: bool) {
+        let mut stats = self.stats.write().await;
+        stats.entry(provider_id.to_string())
+            .or_default()
+            .add_outcome(Utc::now(), succeeded);
+    }
+
+    /// p95 latency of every provider with samples in the routing window.
+    /// Providers without recent samples are left out.
+    pub async fn p95_snapshot(&self) -> HashMap<String, Duration> {
+        let since = Utc::now() - chrono::Duration::from_std(ROUTING_WINDOW).unwrap();
         let stats = self.stats.read().await;
-        asks.retain(|ask| {
-            if let Some(provider_stats) = stats.get(&ask.provider_id) {
-                if provider_stats.is_stale() {
-                    true // Keep providers with stale stats
-                } else {
-                    provider_stats.p95_latency <= max_latency
-                }
-            } else {
-                true // Keep providers with no stats
-            }
-        });
+        stats
+            .iter()
+            .filter_map(|(provider_id, stats)| {
+                let sorted = stats.sorted_since(since);
+                (!sorted.is_empty()).then(|| (provider_id.clone(), percentile(&sorted, 0.95)))
+            })
+            .collect()
+    }
+
+    /// Latency of a provider at percentile `p` (0 to 1) over the routing
+    /// window, or `None` without recent samples.
+    pub async fn recent_percentile(&self, provider_id: &str, p: f64) -> Option<Duration> {
+        let since = Utc::now() - chrono::Duration::from_std(ROUTING_WINDOW).unwrap();
+        let stats = self.stats.read().await;
+        let sorted = stats.get(provider_id)?.sorted_since(since);
+        (!sorted.is_empty()).then(|| percentile(&sorted, p))
+    }
+
+    pub async fn get_metrics(&self, provider_id: &str, window: Duration) -> LatencyMetrics {
+        let window_end = Utc::now();
+        let window_start = window_end
+            - chrono::Duration::from_std(window).unwrap_or_else(|_| chrono::Duration::zero());
+
+        let stats = self.stats.read().await;
+        let provider = stats.get(provider_id);
+        let sorted = provider
+            .map(|s| s.sorted_since(window_start))
+            .unwrap_or_default();
+        let (successes, failures) = provider
+            .map(|s| s.outcomes_since(window_start))
+            .unwrap_or_default();
+
+        LatencyMetrics {
+            p50: percentile(&sorted, 0.50),
+            p95: percentile(&sorted, 0.95),
+            p99: percentile(&sorted, 0.99),
+            samples: sorted.len() as u32,
+            successes,
+            failures,
+            window_start,
+            window_end,
+        }
     }
 }
 
-// Modify OrderBook to use LatencyRouter
-impl OrderBook {
-    pub async fn find_matches_with_routing(
-        &mut self,
-        bid: &Bid,
-        circuit_breaker: &CircuitBreaker,
-        latency_router: &LatencyRouter
-    ) -> redis::RedisResult<Vec<Ask>> {
-        let mut matches = self.find_matches(bid)?;
-        
-        // Filter by circuit breaker
-        matches.retain(|ask| circuit_breaker.can_execute(&ask.provider_id).await);
-        
-        // Filter by latency requirements
-        latency_router.filter_by_latency(&mut matches, Duration::from_millis(bid.max_latency as u64)).await;
-        
-        Ok(matches)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_percentiles_over_window() {
+        let router = LatencyRouter::new();
+        for ms in 1..=100 {
+            router.record_latency("p1", Duration::from_millis(ms)).await;
+        }
+
+        let metrics = router.get_metrics("p1", Duration::from_secs(60)).await;
+        assert_eq!(metrics.samples, 100);
+        assert_eq!(metrics.p50, Duration::from_millis(50));
+        assert_eq!(metrics.p95, Duration::from_millis(95));
+        assert_eq!(metrics.p99, Duration::from_millis(99));
+
+        router.record_outcome("p1", true).await;
+        router.record_outcome("p1", false).await;
+        router.record_outcome("p1", true).await;
+        let metrics = router.get_metrics("p1", Duration::from_secs(60)).await;
+        assert_eq!((metrics.successes, metrics.failures), (2, 1));
+
+        let snapshot = router.p95_snapshot().await;
+        assert_eq!(snapshot.get("p1"), Some(&Duration::from_millis(95)));
+        assert_eq!(router.recent_percentile("p1", 0.8).await, Some(Duration::from_millis(80)));
+        assert_eq!(router.recent_percentile("p2", 0.8).await, None);
+        assert!(!snapshot.contains_key("p2"));
+        assert_eq!(router.get_metrics("p2", Duration::from_secs(60)).await.samples, 0);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,322 @@
+use crate::orderbook::{CreditReservation, CreditTransaction};
+use crate::store::OrderBookStore;
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// A ledger account. Credits only ever move between accounts, so the
+/// postings of every transaction sum to zero; `External` stands for the
+/// world outside the platform (purchases in, payouts out) and has no stored
+/// balance.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Account {
+    User(String),
+    Escrow(String),
+    Provider(String),
+    PlatformFees,
+    External,
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Account::User(id) => write!(f, "user:{}", id),
+            Account::Escrow(id) => write!(f, "escrow:{}", id),
+            Account::Provider(id) => write!(f, "provider:{}", id),
+            Account::PlatformFees => write!(f, "platform:fees"),
+            Account::External => write!(f, "external"),
+        }
+    }
+}
+
+impl From<Account> for String {
+    fn from(account: Account) -> Self {
+        account.to_string()
+    }
+}
+
+impl TryFrom<String> for Account {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.split_once(':') {
+            Some(("user", id)) => Ok(Account::User(id.to_string())),
+            Some(("escrow", id)) => Ok(Account::Escrow(id.to_string())),
+            Some(("provider", id)) => Ok(Account::Provider(id.to_string())),
+            Some(("platform", "fees")) => Ok(Account::PlatformFees),
+            None if value == "external" => Ok(Account::External),
+            _ => Err(format!("Unknown ledger account: {}", value)),
+        }
+    }
+}
+
+/// Signed movement on one account; credits are positive.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Posting {
+    pub account: Account,
+    pub amount: Decimal,
+}
+
+impl Posting {
+    fn new(account: Account, amount: Decimal) -> Self {
+        Self { account, amount }
+    }
+}
+
+pub fn new_transaction_id() -> String {
+    format!("txn_{}", Uuid::new_v4().simple())
+}
+
+pub fn is_balanced(postings: &[Posting]) -> bool {
+    postings.iter().map(|p| p.amount).sum::<Decimal>().is_zero()
+}
+
+/// Purchases, refunds and adjustments: credits enter from outside.
+pub fn deposit_postings(user_id: &str, amount: Decimal) -> Vec<Posting> {
+    vec![
+        Posting::new(Account::External, -amount),
+        Posting::new(Account::User(user_id.to_string()), amount),
+    ]
+}
+
+/// A hold moves credits from the user's balance into their escrow.
+pub fn hold_postings(reservation: &CreditReservation) -> Vec<Posting> {
+    vec![
+        Posting::new(Account::User(reservation.user_id.clone()), -reservation.amount),
+        Posting::new(Account::Escrow(reservation.user_id.clone()), reservation.amount),
+    ]
+}
+
+/// Empties a reservation's escrow: `charge` goes to the provider less
+/// `platform_fee`, and the rest returns to the user.
+pub fn settle_postings(
+    reservation: &CreditReservation,
+    charge: Decimal,
+    platform_fee: Decimal,
+) -> Vec<Posting> {
+    [
+        Posting::new(Account::Escrow(reservation.user_id.clone()), -reservation.amount),
+        Posting::new(Account::User(reservation.user_id.clone()), reservation.amount - charge),
+        Posting::new(Account::Provider(reservation.provider_id.clone()), charge - platform_fee),
+        Posting::new(Account::PlatformFees, platform_fee),
+    ]
+    .into_iter()
+    .filter(|p| !p.amount.is_zero())
+    .collect()
+}
+
+pub fn payout_postings(provider_id: &str, amount: Decimal) -> Vec<Posting> {
+    vec![
+        Posting::new(Account::Provider(provider_id.to_string()), -amount),
+        Posting::new(Account::External, amount),
+    ]
+}
+
+/// Brings a balance that predates the journal onto the ledger, as if it had
+/// entered from outside.
+fn opening_balance_transaction(account: &Account, amount: Decimal) -> CreditTransaction {
+    let (user_id, provider_id) = match account {
+        Account::User(id) | Account::Escrow(id) => (id.clone(), String::new()),
+        Account::Provider(id) => (String::new(), id.clone()),
+        Account::PlatformFees | Account::External => (String::new(), String::new()),
+    };
+    CreditTransaction {
+        transaction_id: new_transaction_id(),
+        user_id,
+        amount,
+        balance_after: amount,
+        provider_id,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        transaction_type: "opening_balance".to_string(),
+        postings: vec![
+            Posting::new(Account::External, -amount),
+            Posting::new(account.clone(), amount),
+        ],
+    }
+}
+
+/// Replayed account balances as of a journal position, so reconciliation
+/// only replays the entries written since.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LedgerCheckpoint {
+    pub position: usize,
+    pub balances: BTreeMap<Account, Decimal>,
+}
+
+/// An account whose stored balance differs from what the journal implies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discrepancy {
+    pub account: Account,
+    pub stored: Decimal,
+    pub replayed: Decimal,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReconciliationReport {
+    pub entries_checked: usize,
+    /// Opening-balance entries written for accounts that predate the journal
+    pub opening_entries: usize,
+    /// IDs of journal entries whose postings do not sum to zero
+    pub unbalanced: Vec<String>,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl ReconciliationReport {
+    pub fn is_clean(&self) -> bool {
+        self.unbalanced.is_empty() && self.discrepancies.is_empty()
+    }
+}
+
+// How many journal entries are replayed per round trip
+const JOURNAL_CHUNK: usize = 500;
+
+/// Replays the journal from the last checkpoint and compares every account
+/// on the ledger against its stored balance, then checkpoints the replayed
+/// balances. Writes that land mid-replay can show up as
+/// transient discrepancies, so a mismatch should be confirmed by a second
+/// run.
+///
+/// The first run, with no checkpoint yet, replays the whole journal and
+/// writes an opening-balance entry for each stored account the journal does
+/// not fully account for, such as balances from before the ledger existed.
+pub async fn reconcile(store: &dyn OrderBookStore) -> redis::RedisResult<ReconciliationReport> {
+    let checkpoint = store.ledger_checkpoint().await?;
+    let opening = checkpoint.is_none();
+    let LedgerCheckpoint { position, mut balances } = checkpoint.unwrap_or_default();
+
+    let len = store.ledger_len().await?;
+    let mut report = ReconciliationReport {
+        entries_checked: len.saturating_sub(position),
+        ..Default::default()
+    };
+
+    for start in (position..len).step_by(JOURNAL_CHUNK) {
+        let stop = (start + JOURNAL_CHUNK).min(len) - 1;
+        for transaction in store.ledger_range(start, stop).await? {
+            if !is_balanced(&transaction.postings) {
+                report.unbalanced.push(transaction.transaction_id.clone());
+            }
+            for posting in transaction.postings {
+                *balances.entry(posting.account).or_default() += posting.amount;
+            }
+        }
+    }
+
+    // Opening entries land after `len`, so the checkpoint leaves them to the
+    // next run's replay and only this run's comparison counts them
+    let mut expected = balances.clone();
+    if opening {
+        let mut openings = Vec::new();
+        for account in store.ledger_accounts().await? {
+            let Some(stored) = stored_balance(store, &account).await? else {
+                continue;
+            };
+            let replayed = balances.get(&account).copied().unwrap_or_default();
+            if stored != replayed {
+                openings.push(opening_balance_transaction(&account, stored - replayed));
+            }
+        }
+        // A write since the journal was read would be counted twice, so the
+        // accounts are opened by a later run instead
+        if store.ledger_len().await? != len {
+            return Ok(report);
+        }
+        for entry in &openings {
+            store.append_ledger_entry(entry).await?;
+            for posting in &entry.postings {
+                *expected.entry(posting.account.clone()).or_default() += posting.amount;
+            }
+        }
+        report.opening_entries = openings.len();
+    }
+
+    for (account, replayed) in expected {
+        if let Some(stored) = stored_balance(store, &account).await? {
+            if stored != replayed {
+                report.discrepancies.push(Discrepancy { account, stored, replayed });
+            }
+        }
+    }
+
+    store
+        .save_ledger_checkpoint(&LedgerCheckpoint { position: len, balances })
+        .await?;
+    Ok(report)
+}
+
+async fn stored_balance(
+    store: &dyn OrderBookStore,
+    account: &Account,
+) -> redis::RedisResult<Option<Decimal>> {
+    Ok(match account {
+        Account::User(id) => Some(store.get_credit_balance(id).await?),
+        Account::Escrow(id) => Some(store.get_reserved_credits(id).await?),
+        Account::Provider(id) => Some(store.get_provider_earnings(id).await?.pending_payout),
+        Account::PlatformFees => Some(store.get_platform_fees().await?),
+        Account::External => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use rust_decimal_macros::dec;
+
+    fn reservation() -> CreditReservation {
+        CreditReservation {
+            reservation_id: "r1".into(),
+            user_id: "u1".into(),
+            provider_id: "p1".into(),
+            amount: dec!(50),
+            created_at: 0,
+            expires_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_account_round_trips_through_json() {
+        let posting = Posting::new(Account::Provider("p1:gpu0".into()), dec!(1.5));
+        let json = serde_json::to_string(&posting).unwrap();
+        assert!(json.contains("\"provider:p1:gpu0\""));
+        assert_eq!(serde_json::from_str::<Posting>(&json).unwrap(), posting);
+    }
+
+    #[test]
+    fn test_settle_postings_balance() {
+        let postings = settle_postings(&reservation(), dec!(20), dec!(2));
+        assert!(is_balanced(&postings));
+        assert_eq!(postings.len(), 4);
+
+        // A release only moves escrow back to the user
+        let postings = settle_postings(&reservation(), Decimal::ZERO, Decimal::ZERO);
+        assert!(is_balanced(&postings));
+        assert_eq!(postings.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_clean_ledger() {
+        let store = MemoryStore::new();
+        store.deposit_credits("u1", dec!(100), "credit_purchase").await.unwrap();
+
+        let first = reservation();
+        store.reserve_credits(&first).await.unwrap();
+        store.capture_reservation(&first, dec!(20), dec!(2)).await.unwrap();
+
+        let second = CreditReservation { reservation_id: "r2".into(), ..reservation() };
+        store.reserve_credits(&second).await.unwrap();
+        store.release_reservation(&second).await.unwrap();
+        store.record_payout("p1", dec!(10)).await.unwrap();
+
+        let report = reconcile(&store).await.unwrap();
+        assert_eq!(report.entries_checked, 6);
+        assert!(report.is_clean(), "{:?}", report);
+    }
+}
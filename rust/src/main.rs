@@ -1,168 +1,757 @@
+// RPC helpers return tonic's `Status`, which is larger than clippy likes
+#![allow(clippy::result_large_err)]
+
+mod batch;
+mod capabilities;
+mod catalog;
+mod circuit_breaker;
+mod framing;
+mod gateway;
+mod latency;
+mod ledger;
+mod metering;
+mod openai;
+mod orderbook;
+mod redis_pool;
+mod selection;
+mod store;
+mod tokenizer;
+
 use tonic::{transport::Server, Request, Response, Status};
-use redis::{Client, Commands, Connection};
-use serde::{Deserialize, Serialize};
+use redis::Client;
 use rust_decimal::Decimal;
-use tokio::sync::mpsc;
 use futures::StreamExt;
-use redis::{Client, Commands, Connection, RedisError};
+use prost::Message;
 use rust_decimal::prelude::*;
-use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+
+use batch::BatchBid;
+use capabilities::Requirement;
+use circuit_breaker::{CircuitBreaker, CircuitState};
+use gateway::{GenerationOptions, Generated, GeneratedStream, ProviderEndpoint, ProviderGateway};
+use latency::LatencyRouter;
+use metering::{StreamSettlement, UsageMeter};
+use orderbook::{
+    is_below_payout_threshold, is_insufficient_credits, Ask, Bid, BidState, CapacityClaim, CreditReservation,
+    CreditTransaction, EarningsConfig, OrderBook, RestingBid, TransactionFilter,
+};
+use redis_pool::RedisPoolConfig;
+use store::{IdempotencyState, MemoryStore, OrderBookStore, RedisStore};
 
 pub mod matcher {
     tonic::include_proto!("matcher");
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Ask {
-    provider_id: String,
-    model: String,
-    gpu_type: String,
-    price: Decimal,
-    max_latency: u32,
-    available_tokens: u32,
-    last_heartbeat: u64
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Bid {
-    model: String,
-    prompt: String, 
-    max_price: Decimal,
-    max_latency: u32,
-    timestamp: u64,
-    user_id: String,
-    required_credits: Decimal,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct CreditBalance {
-    user_id: String,
-    balance: Decimal,
-    last_updated: u64,
+/// Credits and capacity held for one provider's attempt at a forwarded
+/// bid until it is charged or given up.
+struct AttemptHold {
+    ask: Ask,
+    claim: CapacityClaim,
+    reservation: CreditReservation,
 }
 
-#[derive(Debug)]
 struct MatcherService {
-    redis: Client,
+    store: Arc<dyn OrderBookStore>,
+    orderbook: OrderBook,
+    latency_router: Arc<LatencyRouter>,
+    first_token_router: Arc<LatencyRouter>,
+    gateway: ProviderGateway,
+    circuit_breaker: Arc<CircuitBreaker>,
+    failover_attempts: u32,
     stale_threshold: u64,
+    idempotency_ttl: u64,
+    idempotency_claim_ttl: u64,
 }
 
 impl MatcherService {
-    fn new(redis: Client) -> Self {
+    fn new(store: Arc<dyn OrderBookStore>) -> Self {
+        let stale_threshold = 120; // 2 minutes
+        let idempotency_ttl = std::env::var("IDEMPOTENCY_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_IDEMPOTENCY_TTL);
+        let idempotency_claim_ttl = std::env::var("IDEMPOTENCY_CLAIM_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_IDEMPOTENCY_CLAIM_TTL);
+        let latency_router = Arc::new(LatencyRouter::new());
+        // Streams' time to first token alone, which hedging waits on
+        let first_token_router = Arc::new(LatencyRouter::new());
+        // Unary provider calls time out after PROVIDER_REQUEST_TIMEOUT_SECS;
+        // streams must start within PROVIDER_FIRST_TOKEN_TIMEOUT_MS and not
+        // pause longer than PROVIDER_STREAM_IDLE_TIMEOUT_MS
+        let env_u64 = |name: &str, default: u64| {
+            std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+        };
+        let gateway = ProviderGateway::new(
+            latency_router.clone(),
+            first_token_router.clone(),
+            std::time::Duration::from_millis(env_u64("PROVIDER_CONNECT_TIMEOUT_MS", 2000)),
+            std::time::Duration::from_secs(env_u64("PROVIDER_REQUEST_TIMEOUT_SECS", 120)),
+            std::time::Duration::from_millis(env_u64("PROVIDER_FIRST_TOKEN_TIMEOUT_MS", 30000)),
+            std::time::Duration::from_millis(env_u64("PROVIDER_STREAM_IDLE_TIMEOUT_MS", 30000)),
+        );
+        let circuit_breaker = Arc::new(CircuitBreaker::new(
+            env_u64("CIRCUIT_FAILURE_THRESHOLD", 5) as u32,
+            std::time::Duration::from_secs(env_u64("CIRCUIT_RESET_SECS", 30)),
+            std::time::Duration::from_secs(env_u64("CIRCUIT_HALF_OPEN_SECS", 5)),
+        ));
         Self {
-            redis,
-            stale_threshold: 120, // 2 minutes
+            orderbook: OrderBook::new(store.clone(), stale_threshold)
+                .with_reservation_ttl(env_u64("RESERVATION_TTL_SECS", orderbook::DEFAULT_RESERVATION_TTL_SECS))
+                .with_earnings_config(EarningsConfig::from_env())
+                .with_latency_router(latency_router.clone())
+                .with_circuit_breaker(circuit_breaker.clone()),
+            latency_router,
+            first_token_router,
+            gateway,
+            circuit_breaker,
+            failover_attempts: env_u64("FAILOVER_MAX_ATTEMPTS", 3).max(1) as u32,
+            store,
+            stale_threshold,
+            idempotency_ttl,
+            idempotency_claim_ttl,
         }
     }
 
-    async fn verify_credits(&self, user_id: &str, required_credits: Decimal) -> Result<bool, RedisError> {
-        let mut conn = self.redis.get_connection()?;
-        let balance_key = format!("credit:balance:{}", user_id);
-        
-        let balance: Option<String> = conn.get(&balance_key)?;
-        let current_balance = match balance {
-            Some(b) => Decimal::from_str(&b).unwrap_or(Decimal::ZERO),
-            None => Decimal::ZERO,
+    /// Matches a bid to a live ask using the selection policy named in its
+    /// metadata.
+    async fn find_best_match(&self, bid: &Bid) -> Result<Option<Ask>, Status> {
+        let policy = selection::policy_for_bid(&bid.metadata).map_err(Status::invalid_argument)?;
+        let selected = self.orderbook
+            .select_match(bid, policy.as_ref())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(selected.map(|(ask, _)| ask))
+    }
+
+    /// Matches a bid like `find_best_match` and takes its tokens from the
+    /// matched ask's capacity.
+    async fn claim_best_match(&self, bid: &Bid) -> Result<Option<(Ask, CapacityClaim)>, Status> {
+        let policy = selection::policy_for_bid(&bid.metadata).map_err(Status::invalid_argument)?;
+        let claimed = self.orderbook
+            .claim_match(bid, policy.as_ref())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(claimed.map(|(ask, _, claim)| (ask, claim)))
+    }
+
+    /// Matches a bid to a live ask with a registered endpoint, passing over
+    /// the providers in `failed`.
+    async fn claim_forwardable(&self, bid: &Bid, failed: &[String]) -> Result<Option<(Ask, CapacityClaim)>, Status> {
+        let policy = selection::policy_for_bid(&bid.metadata).map_err(Status::invalid_argument)?;
+        let claimed = self.orderbook
+            .claim_match_where(bid, policy.as_ref(), |ask| {
+                ask.endpoint.is_some() && !failed.contains(&ask.provider_id)
+            })
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(claimed.map(|(ask, _, claim)| (ask, claim)))
+    }
+
+    /// How many providers a forwarded bid may be tried on: the
+    /// `max_attempts` in its metadata, capped at FAILOVER_MAX_ATTEMPTS.
+    fn attempt_budget(&self, bid: &Bid) -> Result<usize, Status> {
+        let attempts = match bid.metadata.get(MAX_ATTEMPTS_KEY) {
+            Some(attempts) => attempts
+                .parse::<u32>()
+                .ok()
+                .filter(|a| *a > 0)
+                .ok_or_else(|| Status::invalid_argument("max_attempts must be a positive integer"))?
+                .min(self.failover_attempts),
+            None => self.failover_attempts,
         };
+        Ok(attempts as usize)
+    }
 
-        Ok(current_balance >= required_credits)
+    /// Holds the bid's credits for a claimed ask, returning the claimed
+    /// capacity if they cannot be held.
+    async fn hold_attempt(&self, bid: &Bid, ask: Ask, claim: CapacityClaim) -> Result<AttemptHold, Status> {
+        match self.orderbook.reserve_credits(&bid.user_id, bid.credits_for(&ask), &ask.provider_id).await {
+            Ok(reservation) => Ok(AttemptHold { ask, claim, reservation }),
+            Err(e) => {
+                self.release_capacity(&claim).await;
+                Err(credit_status(e))
+            }
+        }
+    }
+
+    /// Returns the credits and capacity held for an attempt that will not
+    /// be charged.
+    async fn release_attempt(&self, hold: &AttemptHold) {
+        if let Err(e) = self.orderbook.release_credits(&hold.reservation).await {
+            eprintln!("Failed to release reservation {}: {}", hold.reservation.reservation_id, e);
+        }
+        self.release_capacity(&hold.claim).await;
     }
 
-    async fn deduct_credits(
+    /// Gives up on a provider that failed before producing output, counting
+    /// the failure against its circuit.
+    async fn abandon_attempt(&self, hold: &AttemptHold) {
+        self.release_attempt(hold).await;
+        self.circuit_breaker.record_failure(&hold.ask.provider_id).await;
+    }
+
+    /// Claims the next-best ask for a bid after `failed_provider` went
+    /// nowhere, passing over every provider in `tried`, or fails with that
+    /// provider's `error` once the bid's attempt budget is spent or no
+    /// other ask is left.
+    async fn failover(
         &self,
-        user_id: &str,
-        amount: Decimal,
-        provider_id: &str,
-    ) -> Result<(), RedisError> {
-        let mut conn = self.redis.get_connection()?;
-        let balance_key = format!("credit:balance:{}", user_id);
-        
-        let balance: Option<String> = conn.get(&balance_key)?;
-        let current_balance = match balance {
-            Some(b) => Decimal::from_str(&b).unwrap_or(Decimal::ZERO),
-            None => Decimal::ZERO,
+        bid: &Bid,
+        budget: usize,
+        tried: &[String],
+        failed_provider: &str,
+        error: String,
+    ) -> Result<(Ask, CapacityClaim), Status> {
+        if tried.len() >= budget {
+            return Err(Status::unavailable(error));
+        }
+        let (ask, claim) = self.claim_forwardable(bid, tried).await?
+            .ok_or_else(|| Status::unavailable(error.clone()))?;
+        eprintln!("Provider {} failed ({}); retrying with {}", failed_provider, error, ask.provider_id);
+        Ok((ask, claim))
+    }
+
+    /// How long a hedged bid waits on `ask` for a first token before
+    /// starting another provider: the `percentile` of the provider's recent
+    /// time to first token, or its quoted max latency until it has samples,
+    /// capped at the bid's own max latency.
+    async fn hedge_delay(&self, bid: &Bid, ask: &Ask, percentile: f64) -> std::time::Duration {
+        let expected = self.first_token_router
+            .recent_percentile(&ask.provider_id, percentile)
+            .await
+            .unwrap_or(std::time::Duration::from_millis(ask.max_latency as u64));
+        expected.min(std::time::Duration::from_millis(bid.max_latency as u64))
+    }
+
+    /// Holds the next-best ask for a hedge, or `None` when there is none or
+    /// the user cannot cover a second hold.
+    async fn hedge(&self, bid: &Bid, tried: &[String]) -> Option<AttemptHold> {
+        let (ask, claim) = match self.claim_forwardable(bid, tried).await {
+            Ok(claimed) => claimed?,
+            Err(status) => {
+                eprintln!("Failed to match a hedge: {}", status.message());
+                return None;
+            }
         };
+        self.hold_attempt(bid, ask, claim).await.ok()
+    }
 
-        let new_balance = (current_balance - amount)
-            .round_dp_with_strategy(8, RoundingStrategy::ToZero);
+    // One provider's attempt at a stream, tagged with the provider
+    async fn attempt_stream(&self, bid: &Bid, ask: Ask) -> (String, Result<GeneratedStream, String>) {
+        let result = self.gateway.stream(&ask, bid).await;
+        (ask.provider_id, result)
+    }
 
-        if new_balance < Decimal::ZERO {
-            return Err(RedisError::from((
-                redis::ErrorKind::ResponseError,
-                "Insufficient credits",
-            )));
+    /// Returns capacity for a fill that did not go ahead.
+    async fn release_capacity(&self, claim: &CapacityClaim) {
+        if let Err(e) = self.orderbook.release_capacity(claim).await {
+            eprintln!("Failed to return capacity to {}: {}", claim.provider_id, e);
         }
+    }
 
-        let transaction = serde_json::json!({
-            "user_id": user_id,
-            "amount": amount.to_string(),
-            "balance_after": new_balance.to_string(),
-            "provider_id": provider_id,
-            "timestamp": SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
-        });
+    /// Matches a bid and charges for it. Unmatched GTT bids rest on the book
+    /// until `expires_at`; other time-in-force values fail without a match.
+    /// Bids matched to a provider with a registered endpoint are forwarded
+    /// to it and charged for what it generated. Otherwise the client calls
+    /// the provider directly, so the claimed capacity is only returned if
+    /// charging fails and is otherwise left for the provider's next
+    /// heartbeat to report.
+    async fn execute_bid(
+        &self,
+        bid: matcher::Bid,
+        time_in_force: matcher::TimeInForce,
+        expires_at: u64,
+    ) -> Result<matcher::BidResponse, Status> {
+        let internal_bid = self.parse_bid(bid).await?;
+        let (best_ask, claim) = match self.claim_best_match(&internal_bid).await? {
+            Some(matched) => matched,
+            None if time_in_force == matcher::TimeInForce::Gtt => {
+                if internal_bid.required_credits.is_none() {
+                    return Err(Status::invalid_argument("GTT bids must set required_credits"));
+                }
+                return self.rest_bid(internal_bid, expires_at).await;
+            }
+            None => return Err(no_match()),
+        };
 
-        let mut pipe = redis::pipe();
-        pipe.atomic()
-            .set(&balance_key, new_balance.to_string())
-            .rpush(
-                format!("credit:transactions:{}", user_id),
-                transaction.to_string(),
-            );
+        if best_ask.endpoint.is_some() {
+            return self.forward_bid(&internal_bid, best_ask, claim).await;
+        }
 
-        pipe.query(&mut conn)?;
+        let transaction = match self.charge_bid(&internal_bid, &best_ask).await {
+            Ok(transaction) => transaction,
+            Err(status) => {
+                self.release_capacity(&claim).await;
+                return Err(status);
+            }
+        };
+        if let Err(e) = self.orderbook.settle_capacity(&claim).await {
+            eprintln!("Failed to settle capacity with {}: {}", claim.provider_id, e);
+        }
 
-        Ok(())
+        Ok(matcher::BidResponse {
+            provider_id: best_ask.provider_id,
+            status: "matched".to_string(),
+            credits_used: transaction.amount.to_string(),
+            credits_remaining: transaction.balance_after.to_string(),
+            transaction_id: transaction.transaction_id,
+            payment_status: matcher::PaymentStatus::Succeeded as i32,
+            ..Default::default()
+        })
     }
 
-    fn update_ask(&self, conn: &mut Connection, ask: &Ask) -> redis::RedisResult<()> {
-        let key = format!("ask:{}:{}", ask.provider_id, ask.model);
-        conn.set(&key, serde_json::to_string(ask).unwrap())?;
+    /// Holds the bid's credits while the provider generates, then charges
+    /// for the prompt and the output it returned and refunds the rest. A
+    /// provider that fails is passed over for the next-best ask within the
+    /// bid's attempt budget; only the provider that answers is charged.
+    async fn forward_bid(
+        &self,
+        bid: &Bid,
+        ask: Ask,
+        claim: CapacityClaim,
+    ) -> Result<matcher::BidResponse, Status> {
+        let budget = match self.attempt_budget(bid) {
+            Ok(budget) => budget,
+            Err(status) => {
+                self.release_capacity(&claim).await;
+                return Err(status);
+            }
+        };
+        let mut tried = vec![ask.provider_id.clone()];
+        let mut hold = self.hold_attempt(bid, ask, claim).await?;
+        loop {
+            match self.gateway.complete(&hold.ask, bid).await {
+                Ok(generated) => {
+                    self.circuit_breaker.record_success(&hold.ask.provider_id).await;
+                    let result = self.settle_forwarded(bid, &hold.ask, &hold.reservation, generated).await;
+                    if result.is_err() {
+                        // Nothing was charged, so the hold goes back too
+                        self.release_attempt(&hold).await;
+                    } else {
+                        self.release_capacity(&hold.claim).await;
+                    }
+                    return result;
+                }
+                Err(e) => {
+                    self.abandon_attempt(&hold).await;
+                    let (ask, claim) = self.failover(bid, budget, &tried, &hold.ask.provider_id, e).await?;
+                    tried.push(ask.provider_id.clone());
+                    hold = self.hold_attempt(bid, ask, claim).await?;
+                }
+            }
+        }
+    }
 
-        conn.zadd(
-            format!("price:{}:{}", ask.model, ask.gpu_type),
-            &ask.provider_id,
-            ask.price.to_string()
-        )?;
+    /// Charges for a completed forwarded bid. The reservation is left in
+    /// place if the charge fails.
+    async fn settle_forwarded(
+        &self,
+        bid: &Bid,
+        ask: &Ask,
+        reservation: &CreditReservation,
+        generated: Generated,
+    ) -> Result<matcher::BidResponse, Status> {
+        let mut meter = UsageMeter::new(ask, &bid.prompt, reservation.amount);
+        meter.record_output(&generated.text);
+        if let Some(tokens) = generated.output_tokens {
+            meter.set_reported_output_tokens(tokens);
+        }
+        let transaction = self.orderbook
+            .capture_credits(reservation, meter.credits_used())
+            .await
+            .map_err(credit_status)?;
 
-        conn.zadd(
-            format!("latency:{}:{}", ask.model, ask.gpu_type),
-            &ask.provider_id,
-            ask.max_latency
-        )
+        Ok(matcher::BidResponse {
+            provider_id: ask.provider_id.clone(),
+            status: "matched".to_string(),
+            credits_used: transaction.amount.to_string(),
+            credits_remaining: transaction.balance_after.to_string(),
+            transaction_id: transaction.transaction_id,
+            payment_status: matcher::PaymentStatus::Succeeded as i32,
+            provider_metadata: provider_metadata(&generated),
+            response: generated.text,
+            ..Default::default()
+        })
     }
 
-    fn find_best_match(&self, conn: &mut Connection, bid: &Bid) -> redis::RedisResult<Option<Ask>> {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        
-        let asks: Vec<String> = conn.zrangebyscore(
-            format!("price:{}:*", bid.model),
-            "-inf",
-            bid.max_price.to_string()
-        )?;
-
-        let mut valid_asks: Vec<Ask> = Vec::new();
-        for provider_id in asks {
-            let key = format!("ask:{}:{}", provider_id, bid.model);
-            if let Ok(ask_data) = conn.get::<_, String>(&key) {
-                if let Ok(ask) = serde_json::from_str::<Ask>(&ask_data) {
-                    // Maintain stale threshold check
-                    if ask.max_latency <= bid.max_latency 
-                        && now - ask.last_heartbeat <= self.stale_threshold {
-                        valid_asks.push(ask);
+    /// Streams a bid from the best matched ask with a registered endpoint,
+    /// holding its credits for that provider. A provider that fails or
+    /// stalls before its first token is passed over for the next-best ask
+    /// within the bid's attempt budget, with its hold and capacity returned.
+    /// Bids with a `hedge_percentile` also start the next-best ask once the
+    /// provider in flight has gone `hedge_delay` without a token; the first
+    /// to produce one wins, and the other is cancelled without being charged
+    /// or counted as failed.
+    async fn open_stream(&self, bid: &Bid) -> Result<(AttemptHold, GeneratedStream), Status> {
+        let budget = self.attempt_budget(bid)?;
+        let hedge_percentile = hedge_percentile(&bid.metadata)?;
+        let (ask, claim) = self.claim_forwardable(bid, &[]).await?
+            .ok_or_else(no_match)?;
+        let first = self.hold_attempt(bid, ask, claim).await?;
+
+        let mut hedge_at = match hedge_percentile {
+            Some(p) => Some(tokio::time::Instant::now() + self.hedge_delay(bid, &first.ask, p).await),
+            None => None,
+        };
+        let mut tried = vec![first.ask.provider_id.clone()];
+        let mut in_flight = futures::stream::FuturesUnordered::new();
+        in_flight.push(self.attempt_stream(bid, first.ask.clone()));
+        let mut holds = std::collections::HashMap::from([(first.ask.provider_id.clone(), first)]);
+        // The hedge is matched alongside the attempt in flight rather than
+        // in the select arm, so a first token arriving meanwhile is not held up
+        let mut hedging = futures::stream::FuturesUnordered::new();
+
+        loop {
+            // Only one hedge is sent, and only while a single attempt is in flight
+            let hedge_due = hedge_at.filter(|_| in_flight.len() == 1 && tried.len() < budget);
+            tokio::select! {
+                Some((provider_id, result)) = in_flight.next() => {
+                    let hold = holds.remove(&provider_id).expect("every attempt has a hold");
+                    match result {
+                        Ok(stream) => {
+                            self.circuit_breaker.record_success(&provider_id).await;
+                            // The losing request is cancelled when in_flight is
+                            // dropped, and a hedge still being matched is let go
+                            for other in holds.values() {
+                                self.release_attempt(other).await;
+                            }
+                            while let Some((_, hedge)) = hedging.next().await {
+                                if let Some(hedge) = hedge {
+                                    self.release_attempt(&hedge).await;
+                                }
+                            }
+                            return Ok((hold, stream));
+                        }
+                        Err(e) => {
+                            self.abandon_attempt(&hold).await;
+                            if !in_flight.is_empty() {
+                                continue;
+                            }
+                            // A hedge being matched takes over from the failed attempt
+                            if let Some((_, Some(hedge))) = hedging.next().await {
+                                tried.push(hedge.ask.provider_id.clone());
+                                in_flight.push(self.attempt_stream(bid, hedge.ask.clone()));
+                                holds.insert(hedge.ask.provider_id.clone(), hedge);
+                                continue;
+                            }
+                            let (ask, claim) = self.failover(bid, budget, &tried, &provider_id, e).await?;
+                            tried.push(ask.provider_id.clone());
+                            let next = self.hold_attempt(bid, ask, claim).await?;
+                            if let (Some(p), Some(_)) = (hedge_percentile, hedge_at) {
+                                hedge_at = Some(tokio::time::Instant::now() + self.hedge_delay(bid, &next.ask, p).await);
+                            }
+                            in_flight.push(self.attempt_stream(bid, next.ask.clone()));
+                            holds.insert(next.ask.provider_id.clone(), next);
+                        }
                     }
                 }
+                Some((stalled, hedge)) = hedging.next() => {
+                    if let Some(hedge) = hedge {
+                        eprintln!(
+                            "Provider {} has not started streaming; hedging with {}",
+                            stalled,
+                            hedge.ask.provider_id
+                        );
+                        tried.push(hedge.ask.provider_id.clone());
+                        in_flight.push(self.attempt_stream(bid, hedge.ask.clone()));
+                        holds.insert(hedge.ask.provider_id.clone(), hedge);
+                    }
+                }
+                _ = tokio::time::sleep_until(hedge_due.unwrap_or_else(tokio::time::Instant::now)), if hedge_due.is_some() => {
+                    hedge_at = None;
+                    let stalled = holds.keys().next().cloned().expect("one attempt is in flight");
+                    let passed_over = tried.clone();
+                    hedging.push(async move { (stalled, self.hedge(bid, &passed_over).await) });
+                }
+            }
+        }
+    }
+
+    /// Reserves the bid's credits for the matched provider and captures them,
+    /// releasing the reservation if the capture fails. The client calls the
+    /// provider itself, so no output is metered and only the bid's required
+    /// credits or its prompt is charged.
+    async fn charge_bid(&self, bid: &Bid, ask: &Ask) -> Result<CreditTransaction, Status> {
+        let reservation = self.orderbook.reserve_credits(
+            &bid.user_id,
+            bid.unmetered_credits(ask),
+            &ask.provider_id
+        ).await.map_err(credit_status)?;
+
+        match self.orderbook.capture_credits(&reservation, reservation.amount).await {
+            Ok(transaction) => Ok(transaction),
+            Err(e) => {
+                if let Err(release_err) = self.orderbook.release_credits(&reservation).await {
+                    eprintln!("Failed to release reservation {}: {}", reservation.reservation_id, release_err);
+                }
+                Err(credit_status(e))
+            }
+        }
+    }
+
+    async fn rest_bid(&self, bid: Bid, expires_at: u64) -> Result<matcher::BidResponse, Status> {
+        let resting = self.orderbook
+            .rest_bid(bid, expires_at)
+            .await
+            .map_err(credit_status)?;
+        let balance = self.orderbook
+            .get_credit_balance(&resting.bid.user_id)
+            .await
+            .map_err(|e| Status::internal(format!("Credit lookup failed: {}", e)))?;
+
+        Ok(matcher::BidResponse {
+            credits_remaining: balance.to_string(),
+            ..resting_bid_response(&resting)
+        })
+    }
+
+    /// Looks up a resting bid on behalf of the user who placed it.
+    async fn owned_bid(&self, request: &matcher::BidStatusRequest) -> Result<RestingBid, Status> {
+        self.orderbook
+            .bid_status(&request.bid_id)
+            .await
+            .map_err(|e| Status::internal(format!("Bid lookup failed: {}", e)))?
+            .filter(|b| b.bid.user_id == request.user_id)
+            .ok_or_else(|| Status::not_found("Bid not found"))
+    }
+
+    /// Runs a bid through matching and pricing without reserving credits or
+    /// contacting the provider. A balance that cannot cover the bid is
+    /// reported in the estimate rather than as an error.
+    async fn estimate_bid(&self, bid: matcher::Bid) -> Result<matcher::BidResponse, Status> {
+        let internal_bid = self.parse_bid_terms(bid)?;
+        let best_ask = self.find_best_match(&internal_bid).await?
+            .ok_or_else(no_match)?;
+
+        let estimate = self.orderbook
+            .estimate_credits(&internal_bid, &best_ask)
+            .await
+            .map_err(|e| Status::internal(format!("Credit lookup failed: {}", e)))?;
+
+        let (payment_status, failure_reason) = if estimate.is_affordable() {
+            (matcher::PaymentStatus::Unspecified, None)
+        } else {
+            (matcher::PaymentStatus::InsufficientCredits, Some("Insufficient credits".to_string()))
+        };
+
+        Ok(matcher::BidResponse {
+            provider_id: best_ask.provider_id,
+            status: "estimated".to_string(),
+            credits_used: estimate.credits.to_string(),
+            credits_remaining: estimate.remaining().to_string(),
+            payment_status: payment_status as i32,
+            failure_reason,
+            ..Default::default()
+        })
+    }
+
+    /// Parses a bid and checks the user can cover its required credits,
+    /// when it sets them.
+    async fn parse_bid(&self, bid: matcher::Bid) -> Result<Bid, Status> {
+        let internal_bid = self.parse_bid_terms(bid)?;
+
+        // Verify credits before proceeding
+        if let Some(required_credits) = internal_bid.required_credits {
+            if !self.orderbook.verify_credits(&internal_bid.user_id, required_credits).await
+                .map_err(|e| Status::internal(format!("Credit verification failed: {}", e)))? {
+                return Err(Status::failed_precondition("Insufficient credits"));
             }
         }
 
-        valid_asks.sort_by(|a, b| a.price.cmp(&b.price));
-        Ok(valid_asks.first().cloned())
+        Ok(internal_bid)
+    }
+
+    /// Parses a bid without looking at the user's credits.
+    fn parse_bid_terms(&self, bid: matcher::Bid) -> Result<Bid, Status> {
+        let (model, gpu_types) = catalog::current()
+            .resolve_bid(&bid.model, &bid.gpu_types, [bid.prompt.as_str()])
+            .map_err(Status::invalid_argument)?;
+        GenerationOptions::from_metadata(&bid.metadata).map_err(Status::invalid_argument)?;
+
+        // Bids without required credits are held for what the matched ask
+        // charges for the prompt and an output allowance
+        let required_credits = match bid.required_credits.trim() {
+            "" => None,
+            credits => Some(Decimal::from_str(credits).map_err(|_| {
+                Status::invalid_argument("Invalid required_credits format")
+            })?),
+        };
+        Ok(Bid {
+            model,
+            prompt: bid.prompt.clone(),
+            max_price: Decimal::from_str(&bid.max_price).map_err(|_| {
+                Status::invalid_argument("Invalid price format")
+            })?,
+            max_latency: bid.max_latency,
+            timestamp: chrono::Utc::now().timestamp() as u64,
+            user_id: bid.user_id.clone(),
+            required_credits,
+            metadata: bid.metadata.clone(),
+            gpu_types,
+            requirements: parse_requirements(&bid.requirements)?,
+        })
+    }
+}
+
+/// The latency percentile a bid hedges at, a fraction in (0, 1], or `None`
+/// for bids that do not hedge.
+fn hedge_percentile(metadata: &std::collections::HashMap<String, String>) -> Result<Option<f64>, Status> {
+    let Some(value) = metadata.get(HEDGE_PERCENTILE_KEY) else {
+        return Ok(None);
+    };
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|p| *p > 0.0 && *p <= 1.0)
+        .map(Some)
+        .ok_or_else(|| Status::invalid_argument("hedge_percentile must be a fraction between 0 and 1"))
+}
+
+fn parse_requirements(requirements: &[String]) -> Result<Vec<Requirement>, Status> {
+    requirements
+        .iter()
+        .map(|r| r.parse().map_err(Status::invalid_argument))
+        .collect()
+}
+
+/// Where a provider takes forwarded bids; `None` when it registers no URL.
+fn parse_endpoint(status: &matcher::ProviderStatusRequest) -> Result<Option<ProviderEndpoint>, Status> {
+    let url = status.endpoint_url.trim();
+    if url.is_empty() {
+        return Ok(None);
+    }
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        return Err(Status::invalid_argument("endpoint_url must be an http(s) URL"));
+    }
+
+    let protocol = match matcher::ProviderProtocol::try_from(status.protocol) {
+        Ok(matcher::ProviderProtocol::Unspecified | matcher::ProviderProtocol::Ollama) => gateway::Protocol::Ollama,
+        Ok(matcher::ProviderProtocol::Openai) => gateway::Protocol::OpenAi,
+        Err(_) => return Err(Status::invalid_argument("Unknown provider protocol")),
+    };
+    Ok(Some(ProviderEndpoint {
+        url: url.to_string(),
+        auth_token: Some(status.auth_token.clone()).filter(|t| !t.is_empty()),
+        protocol,
+        model: Some(status.endpoint_model.trim().to_string()).filter(|m| !m.is_empty()),
+    }))
+}
+
+/// A provider's quoted credits per token; empty when it quotes none.
+fn parse_credit_rate(rate: &str) -> Result<Option<Decimal>, Status> {
+    if rate.trim().is_empty() {
+        return Ok(None);
+    }
+    match Decimal::from_str(rate.trim()) {
+        Ok(rate) if rate > Decimal::ZERO => Ok(Some(rate)),
+        _ => Err(Status::invalid_argument("Invalid credit rate")),
+    }
+}
+
+fn no_match() -> Status {
+    Status::not_found("No matching provider available")
+}
+
+/// Describes a bid that rested on the book as of its current state.
+fn resting_bid_response(resting: &RestingBid) -> matcher::BidResponse {
+    let response = matcher::BidResponse {
+        bid_id: resting.bid_id.clone(),
+        ..Default::default()
+    };
+
+    match &resting.state {
+        BidState::Resting => matcher::BidResponse {
+            status: "resting".to_string(),
+            payment_status: matcher::PaymentStatus::Pending as i32,
+            ..response
+        },
+        BidState::Filled { provider_id, transaction_id, credits_used, credits_remaining } => {
+            matcher::BidResponse {
+                provider_id: provider_id.clone(),
+                status: "matched".to_string(),
+                credits_used: credits_used.to_string(),
+                credits_remaining: credits_remaining.to_string(),
+                transaction_id: transaction_id.clone(),
+                payment_status: matcher::PaymentStatus::Succeeded as i32,
+                ..response
+            }
+        }
+        BidState::Expired => matcher::BidResponse {
+            status: "expired".to_string(),
+            payment_status: matcher::PaymentStatus::Refunded as i32,
+            ..response
+        },
+        BidState::Cancelled => matcher::BidResponse {
+            status: "cancelled".to_string(),
+            payment_status: matcher::PaymentStatus::Refunded as i32,
+            ..response
+        },
+        BidState::Failed { reason } => matcher::BidResponse {
+            status: "failed".to_string(),
+            payment_status: matcher::PaymentStatus::Failed as i32,
+            failure_reason: Some(reason.clone()),
+            ..response
+        },
+    }
+}
+
+// Bid.metadata entry that makes SubmitBid retries safe, how long a
+// completed response is replayed for, and how long a key stays locked by a
+// request that never finishes (e.g. the matcher crashed mid-request)
+const IDEMPOTENCY_KEY: &str = "idempotency_key";
+const DEFAULT_IDEMPOTENCY_TTL: u64 = 24 * 60 * 60;
+const DEFAULT_IDEMPOTENCY_CLAIM_TTL: u64 = 10 * 60;
+
+// Bid.metadata entry lowering how many providers a forwarded bid is tried on
+const MAX_ATTEMPTS_KEY: &str = "max_attempts";
+
+// Bid.metadata entry opting a streamed bid into hedging
+const HEDGE_PERCENTILE_KEY: &str = "hedge_percentile";
+
+const RECENT_TRANSACTIONS: usize = 5;
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+const MAX_HISTORY_LIMIT: usize = 500;
+
+// Ledger transaction_type strings and their wire enum
+const TRANSACTION_TYPES: &[(&str, matcher::TransactionType)] = &[
+    ("credit_purchase", matcher::TransactionType::Purchase),
+    ("inference_usage", matcher::TransactionType::Usage),
+    ("refund", matcher::TransactionType::Refund),
+    ("adjustment", matcher::TransactionType::Adjustment),
+    ("provider_payout", matcher::TransactionType::ProviderPayout),
+];
+
+fn transaction_type_name(kind: matcher::TransactionType) -> Option<&'static str> {
+    TRANSACTION_TYPES.iter()
+        .find(|(_, t)| *t == kind)
+        .map(|(name, _)| *name)
+}
+
+fn to_proto_transaction(transaction: &CreditTransaction) -> matcher::Transaction {
+    let kind = TRANSACTION_TYPES.iter()
+        .find(|(name, _)| *name == transaction.transaction_type)
+        .map_or(matcher::TransactionType::Unspecified, |(_, t)| *t);
+
+    matcher::Transaction {
+        transaction_id: transaction.transaction_id.clone(),
+        user_id: transaction.user_id.clone(),
+        amount: transaction.amount.to_string(),
+        balance_after: transaction.balance_after.to_string(),
+        provider_id: transaction.provider_id.clone(),
+        timestamp: transaction.timestamp,
+        r#type: kind as i32,
+        status: matcher::PaymentStatus::Succeeded as i32,
+        ..Default::default()
+    }
+}
+
+fn credit_status(err: redis::RedisError) -> Status {
+    if is_insufficient_credits(&err) {
+        Status::failed_precondition("Insufficient credits")
+    } else {
+        Status::internal(format!("Credit reservation failed: {}", err))
     }
 }
 
@@ -174,91 +763,138 @@ impl matcher::matcher_service_server::MatcherService for MatcherService {
         &self,
         request: Request<matcher::BidRequest>
     ) -> Result<Response<matcher::BidResponse>, Status> {
-        let bid = request.into_inner().bid;
-        let mut conn = self.redis.get_connection().map_err(|e| {
-            Status::internal(format!("Redis connection failed: {}", e))
-        })?;
+        let request = request.into_inner();
+        let bid = request.bid
+            .ok_or_else(|| Status::invalid_argument("bid is required"))?;
+        // Unary bids wait for the whole answer, so there is no first token
+        // to hedge on
+        if bid.metadata.contains_key(HEDGE_PERCENTILE_KEY) {
+            return Err(Status::invalid_argument("hedge_percentile is only supported by SubmitBidStream"));
+        }
+        if request.dry_run {
+            return self.estimate_bid(bid).await.map(Response::new);
+        }
 
-        // Parse bid with credit information
-        let internal_bid = Bid {
-            model: bid.model.clone(),
-            prompt: bid.prompt.clone(),
-            max_price: Decimal::from_str(&bid.max_price).map_err(|_| {
-                Status::invalid_argument("Invalid price format")
-            })?,
-            max_latency: bid.max_latency,
-            timestamp: chrono::Utc::now().timestamp() as u64,
-            user_id: bid.user_id.clone(),
-            required_credits: Decimal::from_str(&bid.required_credits).unwrap_or_else(|_| {
-                // Fallback credit calculation if not provided
-                Decimal::from(bid.prompt.len() as i64).div(Decimal::from(4))
-            }),
-        };
+        let time_in_force = matcher::TimeInForce::try_from(request.time_in_force)
+            .map_err(|_| Status::invalid_argument("Unknown time_in_force"))?;
+        if time_in_force == matcher::TimeInForce::Gtt
+            && request.expires_at <= chrono::Utc::now().timestamp() as u64
+        {
+            return Err(Status::invalid_argument("GTT bids need an expires_at in the future"));
+        }
 
-        // Verify credits before proceeding
-        if !self.verify_credits(&internal_bid.user_id, internal_bid.required_credits).await
-            .map_err(|e| Status::internal(format!("Credit verification failed: {}", e)))? {
-            return Err(Status::failed_precondition("Insufficient credits"));
+        // Retries carrying the same key get the first response back instead
+        // of matching and charging again
+        let idempotency_key = bid.metadata.get(IDEMPOTENCY_KEY)
+            .filter(|k| !k.is_empty())
+            .cloned();
+        if let Some(key) = &idempotency_key {
+            let claim = self.store
+                .claim_idempotency_key(&bid.user_id, key, self.idempotency_claim_ttl)
+                .await
+                .map_err(|e| Status::internal(format!("Idempotency check failed: {}", e)))?;
+
+            match claim {
+                IdempotencyState::Claimed => {}
+                IdempotencyState::InProgress => {
+                    return Err(Status::aborted("A request with this idempotency key is still in progress"));
+                }
+                IdempotencyState::Completed(encoded) => {
+                    let response = matcher::BidResponse::decode(encoded.as_slice())
+                        .map_err(|e| Status::internal(format!("Stored response is corrupt: {}", e)))?;
+                    return Ok(Response::new(response));
+                }
+            }
         }
 
-        // Find matching provider
-        let best_ask = self.find_best_match(&mut conn, &internal_bid)
-            .map_err(|e| Status::internal(e.to_string()))?
-            .ok_or_else(|| Status::not_found("No matching provider available"))?;
+        let user_id = bid.user_id.clone();
+        let result = self.execute_bid(bid, time_in_force, request.expires_at).await;
 
-        // Deduct credits only after finding a match
-        self.deduct_credits(
-            &internal_bid.user_id,
-            internal_bid.required_credits,
-            &best_ask.provider_id
-        ).await.map_err(|e| Status::internal(format!("Credit deduction failed: {}", e)))?;
+        if let Some(key) = &idempotency_key {
+            let recorded = match &result {
+                Ok(response) => {
+                    self.store
+                        .complete_idempotency_key(&user_id, key, &response.encode_to_vec(), self.idempotency_ttl)
+                        .await
+                }
+                Err(_) => self.store.release_idempotency_key(&user_id, key).await,
+            };
+            if let Err(e) = recorded {
+                eprintln!("Failed to record idempotency key {}: {}", key, e);
+            }
+        }
 
-        Ok(Response::new(matcher::BidResponse {
-            provider_id: best_ask.provider_id,
-            status: "matched".to_string()
-        }))
+        result.map(Response::new)
     }
 
     async fn submit_bid_stream(
         &self,
         request: Request<matcher::BidRequest>
     ) -> Result<Response<Self::SubmitBidStreamStream>, Status> {
-        let bid = request.into_inner().bid;
-        let mut conn = self.redis.get_connection().map_err(|e| {
-            Status::internal(format!("Redis connection failed: {}", e))
-        })?;
+        let bid = request.into_inner().bid
+            .ok_or_else(|| Status::invalid_argument("bid is required"))?;
 
-        let internal_bid = Bid {
-            model: bid.model.clone(),
-            prompt: bid.prompt.clone(),
-            max_price: Decimal::from_str(&bid.max_price).map_err(|_| {
-                Status::invalid_argument("Invalid price format")
-            })?,
-            max_latency: bid.max_latency,
-            timestamp: chrono::Utc::now().timestamp() as u64,
-            user_id: bid.user_id.clone(),
-            required_credits: Decimal::from_str(&bid.required_credits).unwrap_or_else(|_| {
-                Decimal::from(bid.prompt.len() as i64).div(Decimal::from(4))
-            }),
-        };
+        // Verify and reserve credits before streaming
+        let internal_bid = self.parse_bid(bid).await?;
+        let (hold, stream) = self.open_stream(&internal_bid).await?;
+        let stream = stream.map(|piece| piece.map(stream_response).map_err(Status::unavailable));
 
-        // Verify and deduct credits before streaming
-        if !self.verify_credits(&internal_bid.user_id, internal_bid.required_credits).await
-            .map_err(|e| Status::internal(format!("Credit verification failed: {}", e)))? {
-            return Err(Status::failed_precondition("Insufficient credits"));
-        }
+        let meter = UsageMeter::new(&hold.ask, &internal_bid.prompt, hold.reservation.amount);
 
-        let best_ask = self.find_best_match(&mut conn, &internal_bid)
-            .map_err(|e| Status::internal(e.to_string()))?
-            .ok_or_else(|| Status::not_found("No matching provider available"))?;
+        // Meter output as it streams and settle on completion; a stream that
+        // ends early is settled by the guard for whatever was delivered
+        let settlement = StreamSettlement::new(self.orderbook.clone(), hold.reservation, meter)
+            .with_capacity_claim(hold.claim);
+        let stream = futures::stream::unfold(
+            (stream, Some(settlement)),
+            |(mut stream, settlement)| async move {
+                let mut settlement = settlement?;
+                match stream.next().await {
+                    Some(Ok(mut chunk)) => {
+                        let credits_used = settlement.meter.record_output(&chunk.response);
+                        if !chunk.done && !settlement.meter.is_exhausted() {
+                            chunk.credits_used = credits_used.to_string();
+                            chunk.payment_status = matcher::PaymentStatus::Pending as i32;
+                            return Some((Ok(chunk), (stream, Some(settlement))));
+                        }
 
-        self.deduct_credits(
-            &internal_bid.user_id,
-            internal_bid.required_credits,
-            &best_ask.provider_id
-        ).await.map_err(|e| Status::internal(format!("Credit deduction failed: {}", e)))?;
+                        // Cut the stream off once the reserved credits are used up
+                        if !chunk.done {
+                            chunk.done = true;
+                            chunk.done_reason = Some("credit_limit".to_string());
+                        }
+                        if let Some(tokens) = chunk.metadata.get("eval_count").and_then(|t| t.parse().ok()) {
+                            settlement.meter.set_reported_output_tokens(tokens);
+                        }
+
+                        match settlement.finish().await {
+                            Ok(transaction) => {
+                                chunk.credits_used = transaction.amount.to_string();
+                                chunk.payment_status = matcher::PaymentStatus::Succeeded as i32;
+                            }
+                            Err(e) => {
+                                chunk.payment_status = matcher::PaymentStatus::Failed as i32;
+                                chunk.error = Some(matcher::Error {
+                                    code: matcher::ErrorCode::ErrorCreditSystemError as i32,
+                                    message: format!("Credit settlement failed: {}", e),
+                                    ..Default::default()
+                                });
+                            }
+                        }
+                        Some((Ok(chunk), (stream, None)))
+                    }
+                    Some(Err(status)) => {
+                        settlement.abort().await;
+                        Some((Err(status), (stream, None)))
+                    }
+                    None => {
+                        settlement.abort().await;
+                        None
+                    }
+                }
+            },
+        );
 
-        let stream = forward_request_stream(best_ask, internal_bid).await?;
         Ok(Response::new(Box::pin(stream)))
     }
 
@@ -266,58 +902,79 @@ impl matcher::matcher_service_server::MatcherService for MatcherService {
         &self,
         request: Request<matcher::OrderBookRequest>
     ) -> Result<Response<matcher::OrderBookStatus>, Status> {
-        let mut conn = self.redis.get_connection().map_err(|e| {
-            Status::internal(format!("Redis connection failed: {}", e))
-        })?;
+        let request = request.into_inner();
+        // Filters may use any alias the catalog knows
+        let catalog = catalog::current();
+        let model = catalog.model(&request.model).map_or(request.model.as_str(), |m| m.id.as_str());
+        let gpu_type = request.gpu_type
+            .as_deref()
+            .map(|g| catalog.gpu(g).map_or(g, |spec| spec.id.as_str()));
+        let asks = self.store
+            .list_asks(
+                Some(model).filter(|m| !m.is_empty()),
+                gpu_type.filter(|g| !g.is_empty()),
+            )
+            .await
+            .map_err(|e| Status::internal(format!("Failed to read orderbook: {}", e)))?;
 
-        let model = request.into_inner().model;
-        let pattern = match model.as_str() {
-            "" => "ask:*".to_string(),
-            m => format!("ask:*:{}", m)
-        };
-
-        let keys: Vec<String> = conn.keys(&pattern)?;
         let now = chrono::Utc::now().timestamp() as u64;
         
         let mut active_providers = std::collections::HashSet::new();
+        let mut model_providers: std::collections::HashMap<String, std::collections::HashSet<String>> =
+            std::collections::HashMap::new();
         let mut model_depths = std::collections::HashMap::new();
+        let mut gpu_types = std::collections::BTreeSet::new();
         let mut min_price = rust_decimal::Decimal::MAX;
         let mut max_price = rust_decimal::Decimal::MIN;
+        let mut min_credit_rate = rust_decimal::Decimal::MAX;
+        let mut max_credit_rate = rust_decimal::Decimal::MIN;
 
-        for key in keys {
-            if let Ok(ask_data) = conn.get::<_, String>(&key) {
-                if let Ok(ask) = serde_json::from_str::<Ask>(&ask_data) {
-                    if now - ask.last_heartbeat <= self.stale_threshold {
-                        active_providers.insert(ask.provider_id.clone());
-                        
-                        let depth = model_depths.entry(ask.model.clone())
-                            .or_insert_with(|| matcher::ModelDepth {
-                                model: ask.model.clone(),
-                                ask_count: 0,
-                                provider_count: 0,
-                            });
-                        
-                        depth.ask_count += 1;
-                        min_price = min_price.min(ask.price);
-                        max_price = max_price.max(ask.price);
-                    }
-                }
+        for ask in &asks {
+            if now - ask.last_heartbeat <= self.stale_threshold {
+                active_providers.insert(ask.provider_id.clone());
+                model_providers.entry(ask.model.clone())
+                    .or_default()
+                    .insert(ask.provider_id.clone());
+                
+                let depth = model_depths.entry(ask.model.clone())
+                    .or_insert_with(|| matcher::ModelDepth {
+                        model: ask.model.clone(),
+                        ask_count: 0,
+                        provider_count: 0,
+                        gpu_distribution: std::collections::HashMap::new(),
+                    });
+                
+                depth.ask_count += 1;
+                *depth.gpu_distribution.entry(ask.gpu_type.clone()).or_default() += 1;
+                gpu_types.insert(ask.gpu_type.clone());
+                min_price = min_price.min(ask.price);
+                max_price = max_price.max(ask.price);
+                let credit_rate = ask.credits_per_token();
+                min_credit_rate = min_credit_rate.min(credit_rate);
+                max_credit_rate = max_credit_rate.max(credit_rate);
             }
         }
 
         for depth in model_depths.values_mut() {
-            depth.provider_count = active_providers.iter()
-                .filter(|p| keys.iter().any(|k| k.contains(p)))
-                .count() as u32;
+            depth.provider_count = model_providers
+                .get(&depth.model)
+                .map_or(0, |providers| providers.len() as u32);
         }
 
+        let mut available_models: Vec<String> = model_depths.keys().cloned().collect();
+        available_models.sort();
+
         Ok(Response::new(matcher::OrderBookStatus {
-            total_asks: keys.len() as u32,
+            total_asks: asks.len() as u32,
             active_providers: active_providers.len() as u32,
             depths: model_depths.into_values().collect(),
+            available_models,
+            available_gpu_types: gpu_types.into_iter().collect(),
             last_match_timestamp: now,
             min_price: min_price.to_string(),
             max_price: max_price.to_string(),
+            min_credit_rate: min_credit_rate.to_string(),
+            max_credit_rate: max_credit_rate.to_string(),
         }))
     }
 
@@ -326,14 +983,25 @@ impl matcher::matcher_service_server::MatcherService for MatcherService {
         request: Request<matcher::CircuitStatusRequest>
     ) -> Result<Response<matcher::CircuitStatus>, Status> {
         let provider_id = request.into_inner().provider_id;
-        let providers = self.circuit_breaker.get_status(&provider_id).await;
+        let status = self.circuit_breaker.get_status(&provider_id).await;
 
+        let state = match status.state {
+            CircuitState::Closed => matcher::circuit_status::CircuitState::Closed,
+            CircuitState::Open => matcher::circuit_status::CircuitState::Open,
+            CircuitState::HalfOpen => matcher::circuit_status::CircuitState::HalfOpen,
+        };
+        // Unix seconds, or 0 when unset
+        let timestamp = |time: Option<std::time::SystemTime>| {
+            time.and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs())
+        };
         Ok(Response::new(matcher::CircuitStatus {
-            provider_id: provider_id.clone(),
-            state: providers.state as i32,
-            failure_count: providers.failures,
-            last_failure_timestamp: providers.last_failure.elapsed().as_secs(),
-            reset_timestamp: providers.reset_after.elapsed().as_secs(),
+            provider_id,
+            state: state as i32,
+            failure_count: status.failures,
+            last_failure_timestamp: timestamp(status.last_failure),
+            reset_timestamp: timestamp(status.reset_at),
+            error: None,
         }))
     }
 
@@ -341,16 +1009,9 @@ impl matcher::matcher_service_server::MatcherService for MatcherService {
         &self,
         request: Request<matcher::RateLimitRequest>
     ) -> Result<Response<matcher::RateLimitStatus>, Status> {
-        let provider_id = request.into_inner().provider_id;
-        let status = self.rate_limiter.get_status(&provider_id).await;
-
-        Ok(Response::new(matcher::RateLimitStatus {
-            provider_id,
-            remaining_tokens: status.remaining_tokens,
-            tokens_per_second: status.tokens_per_second,
-            reset_timestamp: status.reset_at.elapsed().as_secs(),
-            is_limited: status.is_limited,
-        }))
+        let _ = request;
+        // Providers are not rate limited by the matcher yet
+        Err(Status::unimplemented("Rate limiting is not enabled"))
     }
 
     async fn get_latency_metrics(
@@ -365,12 +1026,15 @@ impl matcher::matcher_service_server::MatcherService for MatcherService {
 
         Ok(Response::new(matcher::LatencyMetrics {
             provider_id: req.provider_id,
-            p50_ms: metrics.p50.as_millis() as f64,
-            p95_ms: metrics.p95.as_millis() as f64,
-            p99_ms: metrics.p99.as_millis() as f64,
+            p50_ms: metrics.p50.as_millis().to_string(),
+            p95_ms: metrics.p95.as_millis().to_string(),
+            p99_ms: metrics.p99.as_millis().to_string(),
             sample_count: metrics.samples,
             window_start_timestamp: metrics.window_start.timestamp() as u64,
             window_end_timestamp: metrics.window_end.timestamp() as u64,
+            success_count: metrics.successes,
+            failure_count: metrics.failures,
+            ..Default::default()
         }))
     }
 
@@ -379,85 +1043,416 @@ impl matcher::matcher_service_server::MatcherService for MatcherService {
         request: Request<matcher::ProviderStatusRequest>
     ) -> Result<Response<matcher::ProviderStatusResponse>, Status> {
         let status = request.into_inner();
-        let mut conn = self.redis.get_connection().map_err(|e| {
-            Status::internal(format!("Redis connection failed: {}", e))
-        })?;
+        let catalog = catalog::current();
+        let (model, gpu_type) = catalog
+            .resolve_ask(&status.model, &status.gpu_type)
+            .map_err(Status::invalid_argument)?;
+        if catalog.gpu(&gpu_type).is_none() {
+            eprintln!(
+                "Provider {} quotes unknown GPU type {}; pricing it at 1x",
+                status.provider_id, gpu_type
+            );
+        }
+        let endpoint = parse_endpoint(&status)?;
 
         let ask = Ask {
             provider_id: status.provider_id,
-            model: status.model,
-            gpu_type: status.gpu_type,
+            model,
+            gpu_type,
             price: status.price.parse().map_err(|_| {
                 Status::invalid_argument("Invalid price format")
             })?,
             max_latency: status.max_latency,
             available_tokens: status.available_tokens,
-            last_heartbeat: chrono::Utc::now().timestamp() as u64
+            last_heartbeat: chrono::Utc::now().timestamp() as u64,
+            posted_at: 0, // Assigned by add_ask
+            credit_rate: parse_credit_rate(&status.credit_rate)?,
+            capabilities: status.capabilities,
+            endpoint,
         };
 
-        self.update_ask(&mut conn, &ask).map_err(|e| {
+        let provider_id = ask.provider_id.clone();
+        self.orderbook.add_ask(ask.clone()).await.map_err(|e| {
             Status::internal(format!("Failed to update orderbook: {}", e))
         })?;
 
+        // The new quote may cross bids resting on its model's book. The
+        // heartbeat itself has already succeeded, so matching errors are
+        // only logged
+        if let Err(e) = self.orderbook.expire_resting_bids().await {
+            eprintln!("Failed to expire resting bids: {}", e);
+        }
+        match self.orderbook.match_resting_bids(&ask).await {
+            Ok(filled) if !filled.is_empty() => {
+                println!("Filled {} resting bid(s) against {}", filled.len(), provider_id);
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to match resting bids for {}: {}", provider_id, e),
+        }
+
+        let earnings = self.orderbook.provider_earnings(&provider_id).await
+            .map_err(|e| Status::internal(format!("Earnings lookup failed: {}", e)))?;
+
         Ok(Response::new(matcher::ProviderStatusResponse {
-            status: "updated".to_string()
+            status: "updated".to_string(),
+            earned_credits: earnings.total_credits.to_string(),
+            pending_payout: earnings.pending_payout.to_string(),
+            ..Default::default()
+        }))
+    }
+
+    async fn get_credit_balance(
+        &self,
+        request: Request<matcher::CreditBalanceRequest>
+    ) -> Result<Response<matcher::CreditBalanceResponse>, Status> {
+        let req = request.into_inner();
+        if req.user_id.is_empty() {
+            return Err(Status::invalid_argument("user_id is required"));
+        }
+
+        let balance = self.orderbook.get_credit_balance(&req.user_id).await
+            .map_err(|e| Status::internal(format!("Credit lookup failed: {}", e)))?;
+        let reserved = self.orderbook.get_reserved_credits(&req.user_id).await
+            .map_err(|e| Status::internal(format!("Credit lookup failed: {}", e)))?;
+        let recent = self.orderbook
+            .transaction_history(&req.user_id, &TransactionFilter::default(), None, RECENT_TRANSACTIONS)
+            .await
+            .map_err(|e| Status::internal(format!("Credit lookup failed: {}", e)))?;
+
+        // Holds move credits without writing history, so the latest
+        // balance_after only vouches for the balance when nothing is reserved
+        let latest = recent.transactions.first();
+        let balance_verified = reserved.is_zero()
+            && latest.map_or(balance.is_zero(), |t| t.balance_after == balance);
+
+        Ok(Response::new(matcher::CreditBalanceResponse {
+            balance: balance.to_string(),
+            pending_credits: if req.include_pending { reserved } else { Decimal::ZERO }.to_string(),
+            reserved_credits: reserved.to_string(),
+            last_updated: latest.map_or(0, |t| t.timestamp),
+            balance_verified,
+            recent_transactions: if req.include_history {
+                recent.transactions.iter().map(to_proto_transaction).collect()
+            } else {
+                Vec::new()
+            },
+            ..Default::default()
+        }))
+    }
+
+    async fn get_transaction_history(
+        &self,
+        request: Request<matcher::TransactionHistoryRequest>
+    ) -> Result<Response<matcher::TransactionHistoryResponse>, Status> {
+        let req = request.into_inner();
+        if req.user_id.is_empty() {
+            return Err(Status::invalid_argument("user_id is required"));
+        }
+
+        let cursor = match req.pagination_token.as_str() {
+            "" => None,
+            token => Some(token.parse::<usize>().map_err(|_| {
+                Status::invalid_argument("Invalid pagination token")
+            })?),
+        };
+        let limit = match req.limit {
+            0 => DEFAULT_HISTORY_LIMIT,
+            n => (n as usize).min(MAX_HISTORY_LIMIT),
+        };
+
+        let filter = TransactionFilter {
+            start_timestamp: Some(req.start_timestamp).filter(|t| *t > 0),
+            end_timestamp: Some(req.end_timestamp).filter(|t| *t > 0),
+            transaction_types: req.type_filter.iter()
+                .filter_map(|t| matcher::TransactionType::try_from(*t).ok())
+                .filter_map(transaction_type_name)
+                .map(String::from)
+                .collect(),
+        };
+
+        let page = self.orderbook
+            .transaction_history(&req.user_id, &filter, cursor, limit)
+            .await
+            .map_err(|e| Status::internal(format!("Transaction lookup failed: {}", e)))?;
+
+        Ok(Response::new(matcher::TransactionHistoryResponse {
+            transactions: page.transactions.iter().map(to_proto_transaction).collect(),
+            next_pagination_token: page.next_cursor.map(|c| c.to_string()).unwrap_or_default(),
+            has_more: page.next_cursor.is_some(),
+            total_count: page.total_count as u32,
+            ..Default::default()
+        }))
+    }
+
+    async fn request_provider_payout(
+        &self,
+        request: Request<matcher::ProviderPayoutRequest>
+    ) -> Result<Response<matcher::ProviderPayoutResponse>, Status> {
+        let req = request.into_inner();
+        if req.provider_id.is_empty() {
+            return Err(Status::invalid_argument("provider_id is required"));
+        }
+
+        let amount = match req.amount.as_str() {
+            "" => None,
+            amount => Some(Decimal::from_str(amount).map_err(|_| {
+                Status::invalid_argument("Invalid amount format")
+            })?),
+        };
+
+        let transaction = self.orderbook
+            .request_payout(&req.provider_id, amount)
+            .await
+            .map_err(|e| {
+                if is_below_payout_threshold(&e) {
+                    Status::failed_precondition("Pending payout is below the payout threshold")
+                } else if is_insufficient_credits(&e) {
+                    Status::failed_precondition("Payout exceeds pending earnings")
+                } else {
+                    Status::internal(format!("Payout failed: {}", e))
+                }
+            })?;
+
+        Ok(Response::new(matcher::ProviderPayoutResponse {
+            pending_payout: transaction.balance_after.to_string(),
+            transaction: Some(to_proto_transaction(&transaction)),
+            ..Default::default()
+        }))
+    }
+
+    async fn submit_batch_bid(
+        &self,
+        request: Request<matcher::BatchBidRequest>
+    ) -> Result<Response<matcher::BatchBidResponse>, Status> {
+        let req = request.into_inner();
+        if req.prompts.is_empty() {
+            return Err(Status::invalid_argument("prompts must not be empty"));
+        }
+        let (model, gpu_types) = catalog::current()
+            .resolve_bid(&req.model, &req.gpu_types, req.prompts.iter().map(String::as_str))
+            .map_err(Status::invalid_argument)?;
+
+        let batch = BatchBid {
+            model,
+            prompts: req.prompts,
+            max_price: Decimal::from_str(&req.max_price).map_err(|_| {
+                Status::invalid_argument("Invalid price format")
+            })?,
+            max_latency: req.max_latency,
+            user_id: req.user_id,
+            metadata: req.metadata,
+            gpu_types,
+            requirements: parse_requirements(&req.requirements)?,
+            allow_partial: req.allow_partial,
+        };
+
+        let fill = batch::fill_batch(&self.orderbook, &batch)
+            .await
+            .map_err(credit_status)?
+            .ok_or_else(|| Status::not_found("No providers with capacity for the batch"))?;
+
+        let credits_used: Decimal = fill.charges.iter().map(|c| c.transaction.amount).sum();
+        let credits_remaining = self.orderbook.get_credit_balance(&batch.user_id).await
+            .map_err(|e| Status::internal(format!("Credit lookup failed: {}", e)))?;
+
+        Ok(Response::new(matcher::BatchBidResponse {
+            status: if fill.unallocated.is_empty() { "matched" } else { "partial" }.to_string(),
+            allocations: fill.allocations.iter().map(|a| matcher::PromptAllocation {
+                prompt_index: a.prompt_index as u32,
+                provider_id: a.provider_id.clone(),
+                tokens: a.tokens,
+                credits: a.credits.to_string(),
+            }).collect(),
+            charges: fill.charges.iter().map(|c| matcher::ProviderCharge {
+                provider_id: c.provider_id.clone(),
+                prompt_count: c.prompt_count,
+                tokens: c.tokens,
+                credits_used: c.transaction.amount.to_string(),
+                transaction_id: c.transaction.transaction_id.clone(),
+            }).collect(),
+            unallocated_prompts: fill.unallocated.iter().map(|i| *i as u32).collect(),
+            credits_used: credits_used.to_string(),
+            credits_remaining: credits_remaining.to_string(),
+            ..Default::default()
         }))
     }
+
+    async fn get_bid_status(
+        &self,
+        request: Request<matcher::BidStatusRequest>
+    ) -> Result<Response<matcher::BidResponse>, Status> {
+        let resting = self.owned_bid(&request.into_inner()).await?;
+        Ok(Response::new(resting_bid_response(&resting)))
+    }
+
+    async fn cancel_bid(
+        &self,
+        request: Request<matcher::BidStatusRequest>
+    ) -> Result<Response<matcher::BidResponse>, Status> {
+        let resting = self.owned_bid(&request.into_inner()).await?;
+
+        // Bids that already filled or expired are reported as they are
+        let resting = self.orderbook
+            .cancel_bid(&resting.bid_id)
+            .await
+            .map_err(|e| Status::internal(format!("Cancel failed: {}", e)))?
+            .ok_or_else(|| Status::not_found("Bid not found"))?;
+        Ok(Response::new(resting_bid_response(&resting)))
+    }
 }
 
-async fn forward_request_stream(
-    ask: Ask,
-    bid: Bid,
-) -> Result<impl futures::Stream<Item = Result<matcher::StreamResponse, Status>>, Status> {
-    let client = reqwest::Client::new();
-    
-    let request_body = serde_json::json!({
-        "model": bid.model,
-        "prompt": bid.prompt
-    });
-
-    let response = client
-        .post(&ask.provider_id)
-        .json(&request_body)
-        .send()
-        .await
-        .map_err(|e| Status::internal(format!("Provider request failed: {}", e)))?;
-
-    let stream = response
-        .bytes_stream()
-        .map_err(|e| Status::internal(format!("Stream error: {}", e)))
-        .and_then(|chunk| async move {
-            let chunk_str = std::str::from_utf8(&chunk)
-                .map_err(|e| Status::internal(format!("UTF-8 decode error: {}", e)))?;
-            
-            let response: serde_json::Value = serde_json::from_str(chunk_str)
-                .map_err(|e| Status::internal(format!("JSON parse error: {}", e)))?;
-
-            Ok(matcher::StreamResponse {
-                model: response["model"].as_str().unwrap_or_default().to_string(),
-                created_at: response["created_at"].as_str().unwrap_or_default().to_string(),
-                response: response["response"].as_str().unwrap_or_default().to_string(),
-                done: response["done"].as_bool().unwrap_or(false),
-                done_reason: response.get("done_reason").and_then(|r| r.as_str()).map(String::from)
-            })
-        })
-        .boxed();
+/// Provider-reported details passed through to the client.
+fn provider_metadata(generated: &Generated) -> std::collections::HashMap<String, String> {
+    let mut metadata = std::collections::HashMap::new();
+    if let Some(tokens) = generated.output_tokens {
+        metadata.insert("eval_count".to_string(), tokens.to_string());
+    }
+    metadata
+}
 
-    Ok(stream)
+fn stream_response(generated: Generated) -> matcher::StreamResponse {
+    matcher::StreamResponse {
+        metadata: provider_metadata(&generated),
+        model: generated.model,
+        created_at: generated.created_at,
+        response: generated.text,
+        done: generated.done,
+        done_reason: generated.done_reason,
+        ..Default::default()
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let redis_url = std::env::var("REDIS_URL")
-        .unwrap_or_else(|_| "redis://localhost:6379".to_string());
+    // ORDERBOOK_STORE=memory runs the matcher without an external Redis
+    let store: Arc<dyn OrderBookStore> = match std::env::var("ORDERBOOK_STORE").as_deref() {
+        Ok("memory") => Arc::new(MemoryStore::new()),
+        _ => {
+            let redis_url = std::env::var("REDIS_URL")
+                .unwrap_or_else(|_| "redis://localhost:6379".to_string());
+            Arc::new(RedisStore::new(Client::open(redis_url)?, RedisPoolConfig::from_env()))
+        }
+    };
+
+    // Replay the ledger journal periodically and log accounts that drifted
+    // from it; LEDGER_RECONCILE_INTERVAL_SECS=0 disables the check
+    let reconcile_interval = std::env::var("LEDGER_RECONCILE_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(300);
+    if reconcile_interval > 0 {
+        let store = store.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(reconcile_interval));
+            loop {
+                ticker.tick().await;
+                match ledger::reconcile(store.as_ref()).await {
+                    Ok(report) if report.is_clean() => {
+                        if report.opening_entries > 0 {
+                            eprintln!(
+                                "Ledger opened {} balances that predate the journal",
+                                report.opening_entries
+                            );
+                        }
+                    }
+                    Ok(report) => {
+                        eprintln!(
+                            "Ledger reconciliation over {} entries found drift",
+                            report.entries_checked
+                        );
+                        for id in &report.unbalanced {
+                            eprintln!("Ledger entry {} has unbalanced postings", id);
+                        }
+                        for d in &report.discrepancies {
+                            eprintln!(
+                                "Ledger account {} stored {} but journal replays to {}",
+                                d.account, d.stored, d.replayed
+                            );
+                        }
+                    }
+                    Err(e) => eprintln!("Ledger reconciliation failed: {}", e),
+                }
+            }
+        });
+    }
 
-    let service = MatcherService::new(Client::open(redis_url)?);
+    // MODEL_CATALOG_PATH replaces the built-in model and GPU catalog; the
+    // file is reloaded when it changes, MODEL_CATALOG_RELOAD_SECS=0 disables
+    if let Ok(path) = std::env::var("MODEL_CATALOG_PATH") {
+        let path = std::path::PathBuf::from(path);
+        catalog::install(catalog::Catalog::load(&path)?);
+        let reload_interval = std::env::var("MODEL_CATALOG_RELOAD_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30);
+        if reload_interval > 0 {
+            catalog::spawn_reloader(path, std::time::Duration::from_secs(reload_interval));
+        }
+    }
+
+    let service = Arc::new(MatcherService::new(store));
+
+    // Remove asks whose provider stopped heartbeating, along with their
+    // price and latency index entries; STALE_ASK_SWEEP_SECS=0 disables
+    let sweep_interval = std::env::var("STALE_ASK_SWEEP_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60);
+    if sweep_interval > 0 {
+        let service = service.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(sweep_interval));
+            loop {
+                ticker.tick().await;
+                if let Err(e) = service.orderbook.remove_stale().await {
+                    eprintln!("Failed to remove stale asks: {}", e);
+                }
+            }
+        });
+    }
+
+    // Release credit holds left open past RESERVATION_TTL_SECS, such as
+    // those of a matcher that stopped mid-fill; RESERVATION_SWEEP_SECS=0
+    // disables
+    let reservation_sweep_interval = std::env::var("RESERVATION_SWEEP_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60);
+    if reservation_sweep_interval > 0 {
+        let service = service.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(reservation_sweep_interval));
+            loop {
+                ticker.tick().await;
+                match service.orderbook.release_expired_reservations().await {
+                    Ok(0) => {}
+                    Ok(released) => eprintln!("Released {} expired credit reservations", released),
+                    Err(e) => eprintln!("Failed to release expired reservations: {}", e),
+                }
+            }
+        });
+    }
+
+    // OPENAI_HTTP_ADDR also serves the OpenAI-compatible API from the same
+    // service
+    if let Ok(http_addr) = std::env::var("OPENAI_HTTP_ADDR") {
+        let http_addr: std::net::SocketAddr = http_addr.parse()?;
+        let auth = openai::ApiAuth::from_env(&http_addr)?;
+        let http = axum::Server::try_bind(&http_addr)?
+            .serve(openai::router(service.clone(), auth).into_make_service());
+        println!("OpenAI-compatible API listening on {}", http_addr);
+        tokio::spawn(async move {
+            if let Err(e) = http.await {
+                eprintln!("OpenAI-compatible API stopped: {}", e);
+            }
+        });
+    }
 
     let addr = "[::0]:50051".parse()?;
     println!("MatcherService listening on {}", addr);
 
     Server::builder()
-        .add_service(matcher::matcher_service_server::MatcherServiceServer::new(service))
+        .add_service(matcher::matcher_service_server::MatcherServiceServer::from_arc(service))
         .serve(addr)
         .await?;
 
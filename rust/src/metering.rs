@@ -0,0 +1,242 @@
+use crate::catalog;
+use crate::orderbook::{Ask, CapacityClaim, CreditReservation, CreditTransaction, OrderBook};
+use crate::tokenizer::TokenCounter;
+use rust_decimal::prelude::*;
+use std::sync::Arc;
+
+/// Running cost of a streamed inference. Output is priced with the matched
+/// ask's rates and the charge never exceeds the credits reserved for the bid.
+#[derive(Debug, Clone)]
+pub struct UsageMeter {
+    ask: Ask,
+    // Counter of the ask's model as of the match, kept across catalog reloads
+    counter: Arc<dyn TokenCounter>,
+    prompt_tokens: u32,
+    output_tokens: u64,
+    reported_output_tokens: Option<u64>,
+    budget: Decimal,
+}
+
+impl UsageMeter {
+    pub fn new(ask: &Ask, prompt: &str, budget: Decimal) -> Self {
+        let counter = catalog::current().token_counter(&ask.model);
+        Self {
+            ask: ask.clone(),
+            prompt_tokens: counter.count(prompt),
+            counter,
+            output_tokens: 0,
+            reported_output_tokens: None,
+            budget,
+        }
+    }
+
+    /// Adds a generated chunk and returns the running charge. Chunks are
+    /// counted separately, so a token split across two chunks may count
+    /// twice until the provider reports its own total.
+    pub fn record_output(&mut self, text: &str) -> Decimal {
+        self.output_tokens += u64::from(self.counter.count(text));
+        self.credits_used()
+    }
+
+    /// Replaces the length-based estimate with the provider's own count of
+    /// generated tokens, as reported on its final chunk.
+    pub fn set_reported_output_tokens(&mut self, tokens: u64) {
+        self.reported_output_tokens = Some(tokens);
+    }
+
+    pub fn has_output(&self) -> bool {
+        self.output_tokens > 0 || self.reported_output_tokens.is_some_and(|t| t > 0)
+    }
+
+    /// Whether usage has reached the reserved budget.
+    pub fn is_exhausted(&self) -> bool {
+        self.uncapped_cost() >= self.budget
+    }
+
+    pub fn credits_used(&self) -> Decimal {
+        self.uncapped_cost().min(self.budget)
+    }
+
+    fn uncapped_cost(&self) -> Decimal {
+        let output_tokens = self.reported_output_tokens.unwrap_or(self.output_tokens);
+        self.ask.calculate_credit_cost(self.prompt_tokens) + self.ask.token_cost(Decimal::from(output_tokens))
+    }
+}
+
+/// Owns a streaming bid's credit reservation until it is settled. A stream
+/// dropped before `finish` (client disconnect, provider error) is settled in
+/// the background for whatever output was already delivered. Provider
+/// capacity claimed for the stream is returned however it ends.
+pub struct StreamSettlement {
+    orderbook: OrderBook,
+    reservation: CreditReservation,
+    capacity: Option<CapacityClaim>,
+    pub meter: UsageMeter,
+    settled: bool,
+}
+
+impl StreamSettlement {
+    pub fn new(orderbook: OrderBook, reservation: CreditReservation, meter: UsageMeter) -> Self {
+        Self {
+            orderbook,
+            reservation,
+            capacity: None,
+            meter,
+            settled: false,
+        }
+    }
+
+    pub fn with_capacity_claim(mut self, claim: CapacityClaim) -> Self {
+        self.capacity = Some(claim);
+        self
+    }
+
+    /// Captures the metered charge and refunds the rest of the reservation.
+    pub async fn finish(mut self) -> redis::RedisResult<CreditTransaction> {
+        self.settled = true;
+        let result = self.orderbook
+            .capture_credits(&self.reservation, self.meter.credits_used())
+            .await;
+        release_capacity(&self.orderbook, self.capacity.as_ref()).await;
+        result
+    }
+
+    /// Settles a stream that ended without completing: delivered output is
+    /// charged, and a stream that produced nothing releases its hold.
+    pub async fn abort(mut self) {
+        self.settled = true;
+        settle_aborted(&self.orderbook, &self.reservation, &self.meter).await;
+        release_capacity(&self.orderbook, self.capacity.as_ref()).await;
+    }
+}
+
+impl Drop for StreamSettlement {
+    fn drop(&mut self) {
+        if self.settled {
+            return;
+        }
+
+        let orderbook = self.orderbook.clone();
+        let reservation = self.reservation.clone();
+        let meter = self.meter.clone();
+        let capacity = self.capacity.take();
+        tokio::spawn(async move {
+            settle_aborted(&orderbook, &reservation, &meter).await;
+            release_capacity(&orderbook, capacity.as_ref()).await;
+        });
+    }
+}
+
+async fn settle_aborted(orderbook: &OrderBook, reservation: &CreditReservation, meter: &UsageMeter) {
+    let result = if meter.has_output() {
+        orderbook
+            .capture_credits(reservation, meter.credits_used())
+            .await
+            .map(|_| ())
+    } else {
+        orderbook.release_credits(reservation).await.map(|_| ())
+    };
+
+    if let Err(e) = result {
+        eprintln!("Failed to settle reservation {}: {}", reservation.reservation_id, e);
+    }
+}
+
+async fn release_capacity(orderbook: &OrderBook, claim: Option<&CapacityClaim>) {
+    let Some(claim) = claim else {
+        return;
+    };
+    if let Err(e) = orderbook.release_capacity(claim).await {
+        eprintln!("Failed to return capacity to {}: {}", claim.provider_id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::test_ask;
+    use crate::store::{MemoryStore, OrderBookStore};
+    use rust_decimal_macros::dec;
+    use std::sync::Arc;
+
+    fn ask() -> Ask {
+        test_ask("p1", dec!(0.001))
+    }
+
+    #[test]
+    fn test_meter_prices_prompt_and_output() {
+        // gpt4 on a100 costs 3 credits per token
+        let mut meter = UsageMeter::new(&ask(), "12345678", dec!(100));
+        assert_eq!(meter.credits_used(), dec!(6));
+
+        assert_eq!(meter.record_output("abcd"), dec!(9));
+        assert_eq!(meter.record_output("efgh"), dec!(12));
+
+        meter.set_reported_output_tokens(5);
+        assert_eq!(meter.credits_used(), dec!(21));
+    }
+
+    #[test]
+    fn test_meter_is_capped_by_budget() {
+        let mut meter = UsageMeter::new(&ask(), "1234", dec!(10));
+        assert!(!meter.is_exhausted());
+
+        meter.record_output("abcdefghijkl");
+        assert!(meter.is_exhausted());
+        assert_eq!(meter.credits_used(), dec!(10));
+    }
+
+    #[tokio::test]
+    async fn test_finish_refunds_unused_reservation() {
+        let store = Arc::new(MemoryStore::new());
+        store.deposit_credits("u1", dec!(100), "credit_purchase").await.unwrap();
+        let orderbook = OrderBook::new(store.clone(), 60);
+
+        let reservation = orderbook.reserve_credits("u1", dec!(50), "p1").await.unwrap();
+        let mut settlement = StreamSettlement::new(
+            orderbook,
+            reservation,
+            UsageMeter::new(&ask(), "1234", dec!(50)),
+        );
+        settlement.meter.record_output("abcd");
+
+        let transaction = settlement.finish().await.unwrap();
+        assert_eq!(transaction.amount, dec!(6));
+        assert_eq!(store.get_credit_balance("u1").await.unwrap(), dec!(94));
+        assert_eq!(store.get_reserved_credits("u1").await.unwrap(), Decimal::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_abort_without_output_releases_reservation() {
+        let store = Arc::new(MemoryStore::new());
+        store.deposit_credits("u1", dec!(100), "credit_purchase").await.unwrap();
+        let orderbook = OrderBook::new(store.clone(), 60);
+
+        let reservation = orderbook.reserve_credits("u1", dec!(50), "p1").await.unwrap();
+        StreamSettlement::new(orderbook, reservation, UsageMeter::new(&ask(), "1234", dec!(50)))
+            .abort()
+            .await;
+
+        assert_eq!(store.get_credit_balance("u1").await.unwrap(), dec!(100));
+        assert!(store.credit_transaction_count("u1").await.unwrap() == 1);
+    }
+
+    #[tokio::test]
+    async fn test_settlement_returns_claimed_capacity() {
+        let store = Arc::new(MemoryStore::new());
+        store.deposit_credits("u1", dec!(100), "credit_purchase").await.unwrap();
+        store.upsert_ask(&ask()).await.unwrap();
+        let orderbook = OrderBook::new(store.clone(), 60);
+
+        let claim = orderbook.claim_capacity(&ask(), 400).await.unwrap().unwrap();
+        assert_eq!(store.get_ask("p1", "gpt4").await.unwrap().unwrap().available_tokens, 600);
+
+        let reservation = orderbook.reserve_credits("u1", dec!(50), "p1").await.unwrap();
+        StreamSettlement::new(orderbook, reservation, UsageMeter::new(&ask(), "1234", dec!(50)))
+            .with_capacity_claim(claim)
+            .finish()
+            .await
+            .unwrap();
+        assert_eq!(store.get_ask("p1", "gpt4").await.unwrap().unwrap().available_tokens, 1000);
+    }
+}
@@ -0,0 +1,648 @@
+use crate::catalog;
+use crate::gateway::{MAX_TOKENS_KEY, STOP_KEY, TEMPERATURE_KEY};
+use crate::matcher::{self, matcher_service_server::MatcherService};
+use axum::extract::rejection::JsonRejection;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::stream::{self, Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tonic::{Code, Request, Status};
+
+// Same defaults as the Lambda front end
+const DEFAULT_MAX_PRICE: &str = "0.001";
+const DEFAULT_MAX_LATENCY: u32 = 1000;
+
+/// How callers of the HTTP API are told apart.
+#[derive(Clone)]
+pub enum ApiAuth {
+    /// `Authorization: Bearer <key>` names the user through this map of API
+    /// keys to user IDs
+    Keys(HashMap<String, String>),
+    /// The user comes from `x-user-id` or the `user` body field, trusted as
+    /// set by an authenticating proxy in front of the listener
+    TrustedUser,
+}
+
+impl ApiAuth {
+    /// Reads `OPENAI_API_KEYS`, comma-separated `key=user_id` pairs. Without
+    /// keys the caller's user is trusted, which is only allowed on a
+    /// loopback address or with `OPENAI_TRUST_USER_HEADER=true`.
+    pub fn from_env(addr: &SocketAddr) -> Result<Self, String> {
+        if let Ok(keys) = std::env::var("OPENAI_API_KEYS") {
+            return parse_api_keys(&keys).map(ApiAuth::Keys);
+        }
+        let trusted = std::env::var("OPENAI_TRUST_USER_HEADER").is_ok_and(|v| v == "true" || v == "1");
+        if trusted || addr.ip().is_loopback() {
+            Ok(ApiAuth::TrustedUser)
+        } else {
+            Err(format!(
+                "OPENAI_HTTP_ADDR {} is not a loopback address: set OPENAI_API_KEYS, or \
+                 OPENAI_TRUST_USER_HEADER=true behind an authenticating proxy",
+                addr
+            ))
+        }
+    }
+
+    /// The user a request bills to.
+    fn user_id(&self, headers: &HeaderMap, user: Option<String>) -> Result<String, Status> {
+        match self {
+            ApiAuth::Keys(keys) => header(headers, "authorization")
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .and_then(|key| keys.get(key.trim()))
+                .cloned()
+                .ok_or_else(|| Status::unauthenticated("A valid API key is required")),
+            ApiAuth::TrustedUser => header(headers, "x-user-id")
+                .map(String::from)
+                .or(user.filter(|u| !u.trim().is_empty()))
+                .ok_or_else(|| Status::unauthenticated("A user is required: send `user` or an x-user-id header")),
+        }
+    }
+}
+
+fn parse_api_keys(keys: &str) -> Result<HashMap<String, String>, String> {
+    let keys: HashMap<String, String> = keys
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, user)) if !key.trim().is_empty() && !user.trim().is_empty() => {
+                Ok((key.trim().to_string(), user.trim().to_string()))
+            }
+            _ => Err("OPENAI_API_KEYS must be comma-separated key=user_id pairs".to_string()),
+        })
+        .collect::<Result<_, _>>()?;
+    if keys.is_empty() {
+        return Err("OPENAI_API_KEYS lists no keys".to_string());
+    }
+    Ok(keys)
+}
+
+struct Api<S> {
+    service: Arc<S>,
+    auth: ApiAuth,
+}
+
+/// Bid terms the OpenAI API has no fields for. Each can be sent as an extra
+/// body field or as the matching header (`x-max-price`, `x-max-latency`,
+/// and comma-separated `x-gpu-types` and `x-requirements`); body fields
+/// win.
+#[derive(Debug, Default, Deserialize)]
+struct BidTerms {
+    // OpenAI's own end-user field, which names the user only when the
+    // listener trusts callers
+    user: Option<String>,
+    // Accepted as a string or a number
+    max_price: Option<Value>,
+    max_latency: Option<u32>,
+    gpu_types: Option<Vec<String>>,
+    requirements: Option<Vec<String>>,
+    // Generation settings passed on to the provider
+    max_tokens: Option<u32>,
+    temperature: Option<f64>,
+    stop: Option<Stop>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionRequest {
+    model: String,
+    prompt: Prompt,
+    #[serde(default)]
+    stream: bool,
+    #[serde(flatten)]
+    terms: BidTerms,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Prompt {
+    Text(String),
+    Batch(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+    #[serde(flatten)]
+    terms: BidTerms,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMessage {
+    role: String,
+    #[serde(default)]
+    content: Option<Content>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentPart {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: String,
+}
+
+/// The OpenAI-compatible routes, served by `service`'s streaming bid
+/// pipeline so a request is matched, metered and charged exactly like
+/// `SubmitBidStream`, which only matches asks with a registered endpoint.
+/// Non-streaming requests are answered once the stream completes. `auth`
+/// decides which user each request is billed to.
+pub fn router<S: MatcherService>(service: Arc<S>, auth: ApiAuth) -> Router {
+    Router::new()
+        .route("/v1/models", get(list_models))
+        .route("/v1/completions", post(completions::<S>))
+        .route("/v1/chat/completions", post(chat_completions::<S>))
+        .with_state(Arc::new(Api { service, auth }))
+}
+
+async fn list_models() -> Json<Value> {
+    let catalog = catalog::current();
+    let data: Vec<Value> = catalog
+        .models()
+        .iter()
+        .map(|model| {
+            json!({
+                "id": model.id,
+                "object": "model",
+                "created": 0,
+                "owned_by": "gollem",
+                "context_window": model.context_window,
+            })
+        })
+        .collect();
+    Json(json!({ "object": "list", "data": data }))
+}
+
+async fn completions<S: MatcherService>(
+    State(api): State<Arc<Api<S>>>,
+    headers: HeaderMap,
+    body: Result<Json<CompletionRequest>, JsonRejection>,
+) -> Response {
+    let request = match body {
+        Ok(Json(request)) => request,
+        Err(rejection) => return error_response(Status::invalid_argument(rejection.body_text())),
+    };
+    let prompt = match request.prompt {
+        Prompt::Text(prompt) => prompt,
+        Prompt::Batch(mut prompts) if prompts.len() == 1 => prompts.remove(0),
+        Prompt::Batch(_) => {
+            return error_response(Status::invalid_argument("Only a single prompt is supported per request"));
+        }
+    };
+    let completion = Completion::new(Kind::Text, &request.model);
+    respond(&api, completion, prompt, request.stream, request.terms, &headers).await
+}
+
+async fn chat_completions<S: MatcherService>(
+    State(api): State<Arc<Api<S>>>,
+    headers: HeaderMap,
+    body: Result<Json<ChatRequest>, JsonRejection>,
+) -> Response {
+    let request = match body {
+        Ok(Json(request)) => request,
+        Err(rejection) => return error_response(Status::invalid_argument(rejection.body_text())),
+    };
+    let prompt = match chat_prompt(&request.messages) {
+        Ok(prompt) => prompt,
+        Err(e) => return error_response(Status::invalid_argument(e)),
+    };
+    let completion = Completion::new(Kind::Chat, &request.model);
+    respond(&api, completion, prompt, request.stream, request.terms, &headers).await
+}
+
+async fn respond<S: MatcherService>(
+    api: &Api<S>,
+    completion: Completion,
+    prompt: String,
+    streaming: bool,
+    mut terms: BidTerms,
+    headers: &HeaderMap,
+) -> Response {
+    let result = async {
+        let user_id = api.auth.user_id(headers, terms.user.take())?;
+        let bid = bid(&completion.model, prompt.clone(), user_id, terms, headers)?;
+        let request = matcher::BidRequest {
+            bid: Some(bid),
+            ..Default::default()
+        };
+        let chunks = api.service.submit_bid_stream(Request::new(request)).await?.into_inner();
+        if streaming {
+            return Ok(Sse::new(events(completion, chunks)).keep_alive(KeepAlive::default()).into_response());
+        }
+        let body = collect(&completion, &prompt, chunks).await?;
+        Ok(Json(body).into_response())
+    };
+    result.await.unwrap_or_else(error_response)
+}
+
+/// Flattens a conversation into one prompt: a `role: content` block per
+/// message, ending with an open assistant turn for the model to complete.
+fn chat_prompt(messages: &[ChatMessage]) -> Result<String, String> {
+    if messages.is_empty() {
+        return Err("messages must not be empty".to_string());
+    }
+    let mut prompt = String::new();
+    for message in messages {
+        let content = match &message.content {
+            None => String::new(),
+            Some(Content::Text(text)) => text.clone(),
+            Some(Content::Parts(parts)) => {
+                if let Some(part) = parts.iter().find(|p| p.kind != "text") {
+                    return Err(format!("Unsupported content part type: {}", part.kind));
+                }
+                parts.iter().map(|p| p.text.as_str()).collect::<Vec<_>>().join("\n")
+            }
+        };
+        prompt.push_str(&format!("{}: {}\n\n", message.role, content));
+    }
+    prompt.push_str("assistant:");
+    Ok(prompt)
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim).filter(|v| !v.is_empty())
+}
+
+fn header_list(headers: &HeaderMap, name: &str) -> Vec<String> {
+    header(headers, name)
+        .map(|list| list.split(',').map(str::trim).filter(|v| !v.is_empty()).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+fn bid(
+    model: &str,
+    prompt: String,
+    user_id: String,
+    terms: BidTerms,
+    headers: &HeaderMap,
+) -> Result<matcher::Bid, Status> {
+    let max_price = match terms.max_price {
+        Some(Value::String(price)) => price,
+        Some(Value::Number(price)) => price.to_string(),
+        Some(_) => return Err(Status::invalid_argument("max_price must be a decimal string or number")),
+        None => header(headers, "x-max-price").unwrap_or(DEFAULT_MAX_PRICE).to_string(),
+    };
+    let max_latency = match terms.max_latency {
+        Some(latency) => latency,
+        None => match header(headers, "x-max-latency") {
+            Some(latency) => latency
+                .parse()
+                .map_err(|_| Status::invalid_argument("x-max-latency must be a whole number of milliseconds"))?,
+            None => DEFAULT_MAX_LATENCY,
+        },
+    };
+
+    let mut metadata = HashMap::new();
+    if let Some(max_tokens) = terms.max_tokens {
+        metadata.insert(MAX_TOKENS_KEY.to_string(), max_tokens.to_string());
+    }
+    if let Some(temperature) = terms.temperature {
+        metadata.insert(TEMPERATURE_KEY.to_string(), temperature.to_string());
+    }
+    let stop = match terms.stop {
+        Some(Stop::One(stop)) => vec![stop],
+        Some(Stop::Many(stop)) => stop,
+        None => Vec::new(),
+    };
+    if !stop.is_empty() {
+        metadata.insert(STOP_KEY.to_string(), json!(stop).to_string());
+    }
+
+    Ok(matcher::Bid {
+        model: model.to_string(),
+        prompt,
+        max_price,
+        max_latency,
+        timestamp: chrono::Utc::now().timestamp() as u64,
+        user_id,
+        gpu_types: terms.gpu_types.unwrap_or_else(|| header_list(headers, "x-gpu-types")),
+        requirements: terms.requirements.unwrap_or_else(|| header_list(headers, "x-requirements")),
+        metadata,
+        ..Default::default()
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Text,
+    Chat,
+}
+
+/// Identifies one response and shapes its bodies for the endpoint called.
+#[derive(Debug, Clone)]
+struct Completion {
+    kind: Kind,
+    id: String,
+    created: i64,
+    model: String,
+}
+
+impl Completion {
+    fn new(kind: Kind, model: &str) -> Self {
+        let prefix = match kind {
+            Kind::Text => "cmpl",
+            Kind::Chat => "chatcmpl",
+        };
+        Self {
+            kind,
+            id: format!("{}-{}", prefix, uuid::Uuid::new_v4().simple()),
+            created: chrono::Utc::now().timestamp(),
+            model: catalog::current().model(model).map_or(model.to_string(), |m| m.id.clone()),
+        }
+    }
+
+    fn body(&self, choice: Value, streaming: bool) -> Value {
+        let object = match (self.kind, streaming) {
+            (Kind::Text, _) => "text_completion",
+            (Kind::Chat, false) => "chat.completion",
+            (Kind::Chat, true) => "chat.completion.chunk",
+        };
+        json!({
+            "id": self.id,
+            "object": object,
+            "created": self.created,
+            "model": self.model,
+            "choices": [choice],
+        })
+    }
+
+    /// A streamed piece of output; the role is only sent with the first.
+    fn chunk(&self, text: &str, finish_reason: Option<&str>, first: bool) -> Value {
+        let choice = match self.kind {
+            Kind::Text => json!({ "index": 0, "text": text, "logprobs": null, "finish_reason": finish_reason }),
+            Kind::Chat => {
+                let mut delta = json!({ "content": text });
+                if first {
+                    delta["role"] = json!("assistant");
+                }
+                json!({ "index": 0, "delta": delta, "finish_reason": finish_reason })
+            }
+        };
+        self.body(choice, true)
+    }
+
+    fn whole(&self, text: &str, finish_reason: &str) -> Value {
+        let choice = match self.kind {
+            Kind::Text => json!({ "index": 0, "text": text, "logprobs": null, "finish_reason": finish_reason }),
+            Kind::Chat => json!({
+                "index": 0,
+                "message": { "role": "assistant", "content": text },
+                "finish_reason": finish_reason,
+            }),
+        };
+        self.body(choice, false)
+    }
+}
+
+fn finish_reason(done_reason: Option<&str>) -> &'static str {
+    match done_reason {
+        Some("length") | Some("credit_limit") => "length",
+        _ => "stop",
+    }
+}
+
+/// Waits for the whole stream and answers with a single completion.
+async fn collect<St>(completion: &Completion, prompt: &str, chunks: St) -> Result<Value, Status>
+where
+    St: Stream<Item = Result<matcher::StreamResponse, Status>>,
+{
+    let mut chunks = std::pin::pin!(chunks);
+    let mut text = String::new();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        text.push_str(&chunk.response);
+        if !chunk.done {
+            continue;
+        }
+        if let Some(error) = chunk.error {
+            return Err(Status::internal(error.message));
+        }
+
+        let catalog = catalog::current();
+        let prompt_tokens = catalog.count_tokens(&completion.model, prompt);
+        let completion_tokens = chunk.metadata
+            .get("eval_count")
+            .and_then(|t| t.parse().ok())
+            .unwrap_or_else(|| catalog.count_tokens(&completion.model, &text));
+
+        let mut body = completion.whole(&text, finish_reason(chunk.done_reason.as_deref()));
+        body["usage"] = json!({
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens,
+        });
+        body["credits_used"] = json!(chunk.credits_used);
+        return Ok(body);
+    }
+    Err(Status::unavailable("Provider stream ended before completion"))
+}
+
+/// Relays the stream as server-sent events ending with `[DONE]`. A failure
+/// is sent as an error event and ends the stream without `[DONE]`.
+fn events<St>(completion: Completion, chunks: St) -> impl Stream<Item = Result<Event, Infallible>>
+where
+    St: Stream<Item = Result<matcher::StreamResponse, Status>> + Send + 'static,
+{
+    stream::unfold(Some((Box::pin(chunks), true)), move |state| {
+        let completion = completion.clone();
+        async move {
+            let (mut chunks, first) = state?;
+            let data = match chunks.next().await {
+                Some(Ok(chunk)) if !chunk.done => {
+                    let data = vec![completion.chunk(&chunk.response, None, first).to_string()];
+                    return Some((data, Some((chunks, false))));
+                }
+                Some(Ok(chunk)) => {
+                    let reason = finish_reason(chunk.done_reason.as_deref());
+                    let mut data = vec![completion.chunk(&chunk.response, Some(reason), first).to_string()];
+                    match chunk.error {
+                        Some(error) => data.push(error_body(&error.message, "server_error").to_string()),
+                        None => data.push("[DONE]".to_string()),
+                    }
+                    data
+                }
+                Some(Err(status)) => vec![error_body(status.message(), error_kind(status.code()).1).to_string()],
+                None => return None,
+            };
+            Some((data, None))
+        }
+    })
+    .flat_map(|data| stream::iter(data.into_iter().map(|d| Ok(Event::default().data(d)))))
+}
+
+fn error_kind(code: Code) -> (StatusCode, &'static str) {
+    match code {
+        Code::InvalidArgument | Code::OutOfRange => (StatusCode::BAD_REQUEST, "invalid_request_error"),
+        Code::Unauthenticated => (StatusCode::UNAUTHORIZED, "invalid_request_error"),
+        Code::FailedPrecondition => (StatusCode::PAYMENT_REQUIRED, "insufficient_quota"),
+        Code::NotFound => (StatusCode::SERVICE_UNAVAILABLE, "server_error"),
+        Code::Aborted | Code::AlreadyExists => (StatusCode::CONFLICT, "invalid_request_error"),
+        Code::ResourceExhausted => (StatusCode::TOO_MANY_REQUESTS, "rate_limit_error"),
+        Code::Unavailable => (StatusCode::BAD_GATEWAY, "server_error"),
+        Code::DeadlineExceeded => (StatusCode::GATEWAY_TIMEOUT, "server_error"),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "server_error"),
+    }
+}
+
+fn error_body(message: &str, kind: &str) -> Value {
+    json!({ "error": { "message": message, "type": kind, "code": null } })
+}
+
+fn error_response(status: Status) -> Response {
+    let (code, kind) = error_kind(status.code());
+    (code, Json(error_body(status.message(), kind))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway::GenerationOptions;
+
+    fn chunk(text: &str, done: bool) -> Result<matcher::StreamResponse, Status> {
+        Ok(matcher::StreamResponse {
+            response: text.to_string(),
+            done,
+            done_reason: done.then(|| "stop".to_string()),
+            credits_used: "3".to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_chat_prompt() {
+        let messages: Vec<ChatMessage> = serde_json::from_value(json!([
+            { "role": "system", "content": "Be brief." },
+            { "role": "user", "content": [{ "type": "text", "text": "Hi" }, { "type": "text", "text": "there" }] },
+        ]))
+        .unwrap();
+        assert_eq!(chat_prompt(&messages).unwrap(), "system: Be brief.\n\nuser: Hi\nthere\n\nassistant:");
+
+        let image: Vec<ChatMessage> = serde_json::from_value(json!([
+            { "role": "user", "content": [{ "type": "image_url", "image_url": { "url": "x" } }] },
+        ]))
+        .unwrap();
+        assert!(chat_prompt(&image).is_err());
+        assert!(chat_prompt(&[]).is_err());
+    }
+
+    #[test]
+    fn test_bid_terms_from_body_and_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-max-price", "0.5".parse().unwrap());
+        headers.insert("x-max-latency", "250".parse().unwrap());
+        headers.insert("x-gpu-types", "a100, h100".parse().unwrap());
+
+        let from_headers = bid("gpt4", "hi".to_string(), "alice".into(), BidTerms::default(), &headers).unwrap();
+        assert_eq!(
+            (from_headers.user_id.as_str(), from_headers.max_price.as_str(), from_headers.max_latency),
+            ("alice", "0.5", 250)
+        );
+        assert_eq!(from_headers.gpu_types, vec!["a100", "h100"]);
+        assert!(from_headers.metadata.is_empty());
+
+        // Body fields win over headers; generation settings ride along in
+        // the metadata
+        let terms: BidTerms = serde_json::from_value(json!({
+            "max_price": 0.25,
+            "requirements": ["context_length >= 32000"],
+            "max_tokens": 64,
+            "temperature": 0.5,
+            "stop": "\n",
+        }))
+        .unwrap();
+        let from_body = bid("gpt4", "hi".to_string(), "alice".into(), terms, &headers).unwrap();
+        assert_eq!(from_body.max_price, "0.25");
+        assert_eq!(from_body.requirements, vec!["context_length >= 32000"]);
+        let options = GenerationOptions::from_metadata(&from_body.metadata).unwrap();
+        assert_eq!(options.max_tokens, Some(64));
+        assert_eq!(options.temperature, Some(0.5));
+        assert_eq!(options.stop, vec!["\n"]);
+    }
+
+    #[test]
+    fn test_users_come_from_api_keys_or_trusted_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-user-id", "alice".parse().unwrap());
+
+        let trusted = ApiAuth::TrustedUser;
+        assert_eq!(trusted.user_id(&headers, Some("bob".into())).unwrap(), "alice");
+        assert_eq!(trusted.user_id(&HeaderMap::new(), Some("bob".into())).unwrap(), "bob");
+        assert_eq!(trusted.user_id(&HeaderMap::new(), None).unwrap_err().code(), Code::Unauthenticated);
+
+        // With keys, neither the header nor the body can pick the user
+        let keys = ApiAuth::Keys(parse_api_keys("sk-1=carol, sk-2=dave").unwrap());
+        assert_eq!(keys.user_id(&headers, Some("bob".into())).unwrap_err().code(), Code::Unauthenticated);
+        headers.insert("authorization", "Bearer sk-2".parse().unwrap());
+        assert_eq!(keys.user_id(&headers, Some("bob".into())).unwrap(), "dave");
+        headers.insert("authorization", "Bearer sk-3".parse().unwrap());
+        assert!(keys.user_id(&headers, None).is_err());
+
+        assert!(parse_api_keys("sk-1").is_err());
+        assert!(parse_api_keys(" , ").is_err());
+        assert!(ApiAuth::from_env(&"127.0.0.1:8080".parse().unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_collects_stream_into_completion() {
+        let completion = Completion::new(Kind::Chat, "GPT-4");
+        let chunks = stream::iter(vec![chunk("Hello", false), chunk(" world", true)]);
+        let body = collect(&completion, "hi", chunks).await.unwrap();
+
+        assert_eq!(body["object"], "chat.completion");
+        assert_eq!(body["model"], "gpt4");
+        assert_eq!(body["choices"][0]["message"]["content"], "Hello world");
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+        assert_eq!(body["usage"]["completion_tokens"], 3);
+        assert_eq!(body["credits_used"], "3");
+
+        let failed = stream::iter(vec![chunk("Hel", false), Err(Status::unavailable("reset"))]);
+        assert_eq!(collect(&completion, "hi", failed).await.unwrap_err().code(), Code::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn test_streams_server_sent_events() {
+        let completion = Completion::new(Kind::Text, "gpt4");
+        let chunks = stream::iter(vec![chunk("Hel", false), chunk("lo", true), chunk("ignored", false)]);
+        let sent: Vec<_> = events(completion.clone(), chunks).collect().await;
+        assert_eq!(sent.len(), 3);
+
+        // Events only expose their data through the wire format
+        let data: Vec<String> = sent
+            .into_iter()
+            .map(|e| format!("{:?}", e.unwrap()))
+            .collect();
+        assert!(data[0].contains("Hel") && data[0].contains("text_completion"));
+        assert!(data[1].contains("\\\"finish_reason\\\":\\\"stop\\\""));
+        assert!(data[2].contains("[DONE]"));
+
+        let failed = stream::iter(vec![Err(Status::failed_precondition("Insufficient credits"))]);
+        let sent: Vec<_> = events(completion, failed).collect().await;
+        assert_eq!(sent.len(), 1);
+        assert!(format!("{:?}", sent[0].as_ref().unwrap()).contains("insufficient_quota"));
+    }
+}
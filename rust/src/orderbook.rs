@@ -1,10 +1,22 @@
-use redis::{Commands, Connection, RedisError};
+use crate::capabilities::{self, Requirement};
+use crate::catalog;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::gateway::ProviderEndpoint;
+use crate::latency::LatencyRouter;
+use crate::ledger::Posting;
+use crate::selection::SelectionPolicy;
+use crate::store::OrderBookStore;
+use redis::RedisError;
 use rust_decimal::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet};
+use std::ops::Mul;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct Ask {
     pub provider_id: String,
     pub model: String,
@@ -13,201 +25,952 @@ pub struct Ask {
     pub max_latency: u32,
     pub available_tokens: u32,
     pub last_heartbeat: u64,
+    /// When the ask was first quoted at its current price and credit rate;
+    /// 0 when unknown, which ranks behind every known posting time
+    #[serde(default)]
+    pub posted_at: u64,
+    /// Credits per token quoted by the provider; asks without one are
+    /// charged the catalog's model and GPU multipliers
+    #[serde(default)]
+    pub credit_rate: Option<Decimal>,
+    /// Features the provider advertises, e.g. `context_length` or `region`
+    #[serde(default)]
+    pub capabilities: HashMap<String, String>,
+    /// Where bids filled against the ask are forwarded
+    #[serde(default)]
+    pub endpoint: Option<ProviderEndpoint>,
+}
+
+/// A live gpt4 ask on an a100 with 1000 tokens free and a 100 ms latency,
+/// for tests to adjust with struct update syntax.
+#[cfg(test)]
+pub fn test_ask(provider_id: &str, price: Decimal) -> Ask {
+    Ask {
+        provider_id: provider_id.into(),
+        model: "gpt4".into(),
+        gpu_type: "a100".into(),
+        price,
+        max_latency: 100,
+        available_tokens: 1000,
+        last_heartbeat: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        ..Default::default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bid {
+    pub model: String,
+    pub prompt: String,
+    pub max_price: Decimal,
+    pub max_latency: u32,
+    pub timestamp: u64,
+    pub user_id: String,
+    /// Credits held for the bid; without them the hold is priced from the
+    /// matched ask
+    pub required_credits: Option<Decimal>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// Acceptable GPU types; empty accepts any type registered for the model
+    #[serde(default)]
+    pub gpu_types: Vec<String>,
+    /// Capabilities a matched ask must advertise
+    #[serde(default)]
+    pub requirements: Vec<Requirement>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreditTransaction {
+    // Entries written before the ledger have neither an ID nor postings
+    #[serde(default)]
+    pub transaction_id: String,
     pub user_id: String,
     pub amount: Decimal,
     pub balance_after: Decimal,
     pub provider_id: String,
     pub timestamp: u64,
     pub transaction_type: String,
+    #[serde(default)]
+    pub postings: Vec<Posting>,
+}
+
+// Output tokens held for a bid that sets no required credits, at most what
+// the model's context window leaves after the prompt
+const OUTPUT_ALLOWANCE_TOKENS: u32 = 1024;
+
+/// Tokens a prompt occupies, counted with the catalog's tokenizer for
+/// `model`.
+pub fn prompt_tokens(model: &str, prompt: &str) -> u32 {
+    catalog::current().count_tokens(model, prompt)
+}
+
+impl Bid {
+    pub fn estimated_tokens(&self) -> u32 {
+        prompt_tokens(&self.model, &self.prompt)
+    }
+
+    /// Credits charged for the bid on `ask` when nothing meters its output:
+    /// its required credits, or the ask's price for the prompt.
+    pub fn unmetered_credits(&self, ask: &Ask) -> Decimal {
+        self.required_credits
+            .unwrap_or_else(|| ask.calculate_credit_cost(self.estimated_tokens()))
+    }
+
+    /// Credits to hold for the bid on `ask` while its output is metered: its
+    /// required credits, or the ask's price for the prompt plus an
+    /// allowance of output tokens.
+    pub fn credits_for(&self, ask: &Ask) -> Decimal {
+        self.required_credits.unwrap_or_else(|| {
+            ask.calculate_credit_cost(self.estimated_tokens())
+                + ask.calculate_credit_cost(self.output_allowance(ask))
+        })
+    }
+
+    /// Output tokens the bid's hold on `ask` pays for: what its required
+    /// credits leave after the prompt, or a fixed allowance without them,
+    /// at most what the model's context window leaves after the prompt.
+    pub fn output_allowance(&self, ask: &Ask) -> u32 {
+        let prompt_tokens = self.estimated_tokens();
+        let affordable = match self.required_credits {
+            None => OUTPUT_ALLOWANCE_TOKENS,
+            Some(credits) if ask.credits_per_token() > Decimal::ZERO => {
+                ((credits - ask.calculate_credit_cost(prompt_tokens)) / ask.credits_per_token())
+                    .floor()
+                    .clamp(Decimal::ZERO, Decimal::from(u32::MAX))
+                    .to_u32()
+                    .unwrap_or(0)
+            }
+            Some(_) => u32::MAX,
+        };
+        catalog::current()
+            .model(&self.model)
+            .map_or(affordable, |m| m.context_window.saturating_sub(prompt_tokens).min(affordable))
+    }
+
+    /// Whether the ask runs on an acceptable GPU type and advertises every
+    /// required capability.
+    pub fn accepts(&self, ask: &Ask) -> bool {
+        (self.gpu_types.is_empty() || self.gpu_types.contains(&ask.gpu_type))
+            && capabilities::meets_all(&self.requirements, &ask.capabilities)
+    }
+}
+
+/// Lifecycle of a bid that rested on the book.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum BidState {
+    Resting,
+    Filled {
+        provider_id: String,
+        transaction_id: String,
+        credits_used: Decimal,
+        credits_remaining: Decimal,
+    },
+    Expired,
+    Cancelled,
+    Failed {
+        reason: String,
+    },
+}
+
+/// A good-till-time bid waiting for an ask. Its required credits stay held
+/// by `reservation` until it is filled, cancelled or expires.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestingBid {
+    pub bid_id: String,
+    pub bid: Bid,
+    pub rested_at_ms: u64,
+    pub expires_at: u64,
+    pub reservation: CreditReservation,
+    pub state: BidState,
+}
+
+impl RestingBid {
+    fn crosses(&self, ask: &Ask, now: u64) -> bool {
+        self.expires_at > now
+            && ask.model == self.bid.model
+            && ask.price <= self.bid.max_price
+            && ask.max_latency <= self.bid.max_latency
+            && self.bid.accepts(ask)
+    }
+}
+
+/// Tokens taken from an ask's capacity by one fill.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapacityClaim {
+    pub provider_id: String,
+    pub model: String,
+    pub tokens: u32,
+}
+
+/// Credits held against a user's balance between matching a bid and
+/// settling the inference it paid for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CreditReservation {
+    pub reservation_id: String,
+    pub user_id: String,
+    pub provider_id: String,
+    pub amount: Decimal,
+    pub created_at: u64,
+    /// Unix seconds after which the reservation sweep releases the hold if
+    /// it is still open; 0 never expires
+    #[serde(default)]
+    pub expires_at: u64,
+}
+
+/// Running totals for a provider, stored as JSON under
+/// `provider:earnings:{provider_id}` where the provider agent reads them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProviderEarnings {
+    pub total_credits: Decimal,
+    pub pending_payout: Decimal,
+    pub last_payout: u64,
+    pub total_inference_count: u64,
+}
+
+impl ProviderEarnings {
+    /// Books a provider's share of one captured inference.
+    pub fn credit(&mut self, amount: Decimal) {
+        self.total_credits += amount;
+        self.pending_payout += amount;
+        self.total_inference_count += 1;
+    }
+
+    pub fn pay_out(&mut self, amount: Decimal, timestamp: u64) {
+        self.pending_payout -= amount;
+        self.last_payout = timestamp;
+    }
+}
+
+/// How captured user charges are split between provider and platform, and
+/// when providers may cash out.
+#[derive(Debug, Clone)]
+pub struct EarningsConfig {
+    pub platform_fee_rate: Decimal,
+    pub payout_threshold: Decimal,
+}
+
+impl Default for EarningsConfig {
+    fn default() -> Self {
+        Self {
+            platform_fee_rate: Decimal::new(1, 1),
+            payout_threshold: Decimal::from(100),
+        }
+    }
+}
+
+impl EarningsConfig {
+    /// Reads overrides from `PLATFORM_FEE_RATE` (a fraction of each charge)
+    /// and `PAYOUT_THRESHOLD`, falling back to the defaults.
+    pub fn from_env() -> Self {
+        fn var(name: &str) -> Option<Decimal> {
+            std::env::var(name).ok().and_then(|v| Decimal::from_str(&v).ok())
+        }
+
+        let defaults = Self::default();
+        Self {
+            platform_fee_rate: var("PLATFORM_FEE_RATE")
+                .filter(|r| *r >= Decimal::ZERO && *r <= Decimal::ONE)
+                .unwrap_or(defaults.platform_fee_rate),
+            payout_threshold: var("PAYOUT_THRESHOLD").unwrap_or(defaults.payout_threshold),
+        }
+    }
+}
+
+/// Restricts a transaction history query; empty fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct TransactionFilter {
+    pub start_timestamp: Option<u64>,
+    pub end_timestamp: Option<u64>,
+    pub transaction_types: Vec<String>,
+}
+
+impl TransactionFilter {
+    fn is_empty(&self) -> bool {
+        self.start_timestamp.is_none()
+            && self.end_timestamp.is_none()
+            && self.transaction_types.is_empty()
+    }
+
+    fn matches(&self, transaction: &CreditTransaction) -> bool {
+        !matches!(self.start_timestamp, Some(start) if transaction.timestamp < start)
+            && !matches!(self.end_timestamp, Some(end) if transaction.timestamp > end)
+            && (self.transaction_types.is_empty()
+                || self.transaction_types.contains(&transaction.transaction_type))
+    }
+}
+
+/// What a bid would cost without executing it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreditEstimate {
+    pub credits: Decimal,
+    pub balance: Decimal,
+}
+
+impl CreditEstimate {
+    pub fn is_affordable(&self) -> bool {
+        self.balance >= self.credits
+    }
+
+    /// Balance left after the charge, floored at zero.
+    pub fn remaining(&self) -> Decimal {
+        (self.balance - self.credits).max(Decimal::ZERO)
+    }
+}
+
+/// One page of a user's history, newest first. `next_cursor` is the history
+/// position to resume from and is `None` on the last page.
+#[derive(Debug, Clone)]
+pub struct TransactionPage {
+    pub transactions: Vec<CreditTransaction>,
+    pub next_cursor: Option<usize>,
+    pub total_count: usize,
+}
+
+// How many history entries are fetched per round trip while paging
+const HISTORY_CHUNK: usize = 100;
+
+pub fn insufficient_credits() -> RedisError {
+    RedisError::from((
+        redis::ErrorKind::ResponseError,
+        "Insufficient credits"
+    ))
+}
+
+pub fn is_insufficient_credits(err: &RedisError) -> bool {
+    err.kind() == redis::ErrorKind::ResponseError
+        && err.to_string() == insufficient_credits().to_string()
+}
+
+pub fn below_payout_threshold() -> RedisError {
+    RedisError::from((
+        redis::ErrorKind::ResponseError,
+        "Pending payout below threshold"
+    ))
+}
+
+pub fn is_below_payout_threshold(err: &RedisError) -> bool {
+    err.kind() == redis::ErrorKind::ResponseError
+        && err.to_string() == below_payout_threshold().to_string()
 }
 
 impl Ask {
     fn dominates(&self, other: &Ask) -> bool {
+        let (rate, other_rate) = (self.credits_per_token(), other.credits_per_token());
         let price_better = self.price <= other.price;
+        let rate_better = rate <= other_rate;
         let latency_better = self.max_latency <= other.max_latency;
         let tokens_better = self.available_tokens >= other.available_tokens;
         
-        (price_better && latency_better && tokens_better) && 
-        (self.price < other.price || self.max_latency < other.max_latency || self.available_tokens > other.available_tokens)
+        (price_better && rate_better && latency_better && tokens_better) && 
+        (self.price < other.price || rate < other_rate || self.max_latency < other.max_latency || self.available_tokens > other.available_tokens)
     }
 
-    fn calculate_credit_cost(&self, prompt_length: usize) -> Decimal {
-        // Base cost calculation using prompt length
-        let base_cost = Decimal::from(prompt_length).div(Decimal::from(4));
-        
-        // Adjust cost based on model type and GPU
-        let model_multiplier = match self.model.as_str() {
-            "gpt4" => Decimal::from(2),
-            "gpt3" => Decimal::from(1),
-            _ => Decimal::ONE,
-        };
+    /// Credits charged per token: the quoted credit rate, or the catalog's
+    /// multipliers for the ask's model and GPU.
+    pub fn credits_per_token(&self) -> Decimal {
+        self.credit_rate
+            .unwrap_or_else(|| catalog::current().multiplier(&self.model, &self.gpu_type))
+    }
 
-        let gpu_multiplier = match self.gpu_type.as_str() {
-            "a100" => Decimal::from_str("1.5").unwrap(),
-            "h100" => Decimal::from_str("2.0").unwrap(),
-            _ => Decimal::ONE,
-        };
+    /// Credits charged for a prompt of `prompt_tokens` tokens.
+    pub fn calculate_credit_cost(&self, prompt_tokens: u32) -> Decimal {
+        self.token_cost(Decimal::from(prompt_tokens))
+    }
 
+    /// Credits charged for `tokens` tokens on this ask.
+    pub fn token_cost(&self, tokens: Decimal) -> Decimal {
         // Calculate final cost with 8 decimal precision
-        base_cost
-            .mul(model_multiplier)
-            .mul(gpu_multiplier)
+        tokens
+            .mul(self.credits_per_token())
             .round_dp_with_strategy(8, RoundingStrategy::ToZero)
     }
 }
 
+/// Match priority between two asks: lower price first, then the ask quoted
+/// at its price the longest (asks without a posting time last), then lower
+/// measured p95 latency (the quoted latency for providers without recent
+/// samples), then provider ID, so equal asks always resolve the same way.
+pub fn ask_priority(a: &Ask, b: &Ask, p95: &HashMap<String, Duration>) -> Ordering {
+    let latency = |ask: &Ask| {
+        p95.get(&ask.provider_id)
+            .copied()
+            .unwrap_or_else(|| Duration::from_millis(ask.max_latency as u64))
+    };
+    let posted_at = |ask: &Ask| match ask.posted_at {
+        0 => u64::MAX,
+        posted_at => posted_at,
+    };
+
+    a.price
+        .cmp(&b.price)
+        .then(posted_at(a).cmp(&posted_at(b)))
+        .then_with(|| latency(a).cmp(&latency(b)))
+        .then_with(|| a.provider_id.cmp(&b.provider_id))
+}
+
+// Long enough for any forwarded stream to finish and settle its hold
+pub const DEFAULT_RESERVATION_TTL_SECS: u64 = 3600;
+
+#[derive(Clone)]
 pub struct OrderBook {
-    redis: Connection,
+    store: Arc<dyn OrderBookStore>,
     stale_threshold: u64,
+    reservation_ttl: u64,
+    earnings: EarningsConfig,
+    latency: Option<Arc<LatencyRouter>>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
 }
 
 impl OrderBook {
-    pub fn new(redis: Connection, stale_threshold: u64) -> Self {
-        Self { redis, stale_threshold }
+    pub fn new(store: Arc<dyn OrderBookStore>, stale_threshold: u64) -> Self {
+        Self {
+            store,
+            stale_threshold,
+            reservation_ttl: DEFAULT_RESERVATION_TTL_SECS,
+            earnings: EarningsConfig::default(),
+            latency: None,
+            circuit_breaker: None,
+        }
     }
 
-    pub fn add_ask(&mut self, ask: Ask) -> redis::RedisResult<()> {
-        let key = format!("ask:{}:{}", ask.provider_id, ask.model);
-        let serialized = serde_json::to_string(&ask).unwrap();
-        self.redis.set(&key, serialized)?;
-        
-        self.redis.zadd(
-            format!("price:{}:{}", ask.model, ask.gpu_type),
-            &ask.provider_id,
-            ask.price.to_string()
-        )?;
-        
-        self.redis.zadd(
-            format!("latency:{}:{}", ask.model, ask.gpu_type),
-            &ask.provider_id,
-            ask.max_latency
-        )
+    /// How long a credit hold may stay open before the reservation sweep
+    /// releases it. Resting bids hold for this long past their expiry.
+    pub fn with_reservation_ttl(mut self, secs: u64) -> Self {
+        self.reservation_ttl = secs;
+        self
+    }
+
+    pub fn with_earnings_config(mut self, earnings: EarningsConfig) -> Self {
+        self.earnings = earnings;
+        self
+    }
+
+    /// Breaks ties between equally priced asks on measured p95 latency.
+    pub fn with_latency_router(mut self, latency: Arc<LatencyRouter>) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Leaves providers whose circuit is open out of matching.
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    /// Posts or refreshes an ask. Heartbeats at an unchanged price and
+    /// credit rate keep the ask's posting time; requoting gives up its time
+    /// priority.
+    pub async fn add_ask(&self, mut ask: Ask) -> redis::RedisResult<()> {
+        ask.posted_at = match self.store.get_ask(&ask.provider_id, &ask.model).await? {
+            Some(existing)
+                if existing.price == ask.price
+                    && existing.credit_rate == ask.credit_rate
+                    && existing.posted_at != 0 => existing.posted_at,
+            _ => ask.last_heartbeat,
+        };
+        self.store.upsert_ask(&ask).await
     }
 
     pub async fn verify_credits(
-        &mut self,
+        &self,
         user_id: &str,
         required_credits: Decimal
     ) -> redis::RedisResult<bool> {
-        let balance = self.get_credit_balance(user_id)?;
+        let balance = self.get_credit_balance(user_id).await?;
         Ok(balance >= required_credits)
     }
 
-    fn get_credit_balance(&mut self, user_id: &str) -> redis::RedisResult<Decimal> {
-        let balance_key = format!("credit:balance:{}", user_id);
-        let balance: Option<String> = self.redis.get(&balance_key)?;
-        
-        Ok(match balance {
-            Some(b) => Decimal::from_str(&b).unwrap_or(Decimal::ZERO),
-            None => Decimal::ZERO,
+    pub async fn get_credit_balance(&self, user_id: &str) -> redis::RedisResult<Decimal> {
+        self.store.get_credit_balance(user_id).await
+    }
+
+    pub async fn get_reserved_credits(&self, user_id: &str) -> redis::RedisResult<Decimal> {
+        self.store.get_reserved_credits(user_id).await
+    }
+
+    /// Prices a bid on `ask` as a fill would, against the user's current
+    /// balance and without touching it: asks that forward to an endpoint
+    /// hold the bid's metered allowance, others charge it unmetered.
+    pub async fn estimate_credits(&self, bid: &Bid, ask: &Ask) -> redis::RedisResult<CreditEstimate> {
+        let credits = match ask.endpoint {
+            Some(_) => bid.credits_for(ask),
+            None => bid.unmetered_credits(ask),
+        };
+        Ok(CreditEstimate {
+            credits,
+            balance: self.get_credit_balance(&bid.user_id).await?,
         })
     }
 
-    pub async fn deduct_credits(
-        &mut self,
+    /// Holds `amount` of the user's credits for a match with `provider_id`.
+    /// The hold must later be captured or released.
+    pub async fn reserve_credits(
+        &self,
         user_id: &str,
         amount: Decimal,
         provider_id: &str,
-    ) -> redis::RedisResult<()> {
-        let balance_key = format!("credit:balance:{}", user_id);
-        let current_balance = self.get_credit_balance(user_id)?;
-        
-        let new_balance = (current_balance - amount)
+    ) -> redis::RedisResult<CreditReservation> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.reserve_credits_until(user_id, amount, provider_id, now, now + self.reservation_ttl).await
+    }
+
+    async fn reserve_credits_until(
+        &self,
+        user_id: &str,
+        amount: Decimal,
+        provider_id: &str,
+        created_at: u64,
+        expires_at: u64,
+    ) -> redis::RedisResult<CreditReservation> {
+        let reservation = CreditReservation {
+            reservation_id: format!("res_{}", Uuid::new_v4().simple()),
+            user_id: user_id.to_string(),
+            provider_id: provider_id.to_string(),
+            amount: amount.round_dp_with_strategy(8, RoundingStrategy::ToZero),
+            created_at,
+            expires_at,
+        };
+
+        self.store.reserve_credits(&reservation).await?;
+        Ok(reservation)
+    }
+
+    /// Releases holds left open past their expiry, such as those of a
+    /// matcher that stopped mid-fill, and returns how many were released.
+    pub async fn release_expired_reservations(&self) -> redis::RedisResult<u32> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        self.store.release_expired_reservations(now).await
+    }
+
+    /// Charges the actual cost of a completed inference, capped at the
+    /// reserved amount, and returns the remainder to the user. The charge is
+    /// credited to the matched provider less the platform fee.
+    pub async fn capture_credits(
+        &self,
+        reservation: &CreditReservation,
+        amount: Decimal,
+    ) -> redis::RedisResult<CreditTransaction> {
+        let amount = amount
+            .round_dp_with_strategy(8, RoundingStrategy::ToZero)
+            .min(reservation.amount)
+            .max(Decimal::ZERO);
+        let platform_fee = amount
+            .mul(self.earnings.platform_fee_rate)
             .round_dp_with_strategy(8, RoundingStrategy::ToZero);
 
-        if new_balance < Decimal::ZERO {
-            return Err(RedisError::from((
-                redis::ErrorKind::ResponseError,
-                "Insufficient credits"
-            )));
+        self.store.capture_reservation(reservation, amount, platform_fee).await
+    }
+
+    /// Returns all reserved credits after a failed inference.
+    pub async fn release_credits(&self, reservation: &CreditReservation) -> redis::RedisResult<Decimal> {
+        self.store.release_reservation(reservation).await
+    }
+
+    /// Pages through a user's transactions from newest to oldest. `cursor` is
+    /// a history position returned as `next_cursor` by a previous page; since
+    /// history is append-only, cursors stay valid as new transactions land.
+    pub async fn transaction_history(
+        &self,
+        user_id: &str,
+        filter: &TransactionFilter,
+        cursor: Option<usize>,
+        limit: usize,
+    ) -> redis::RedisResult<TransactionPage> {
+        let len = self.store.credit_transaction_count(user_id).await?;
+        let mut page = TransactionPage {
+            transactions: Vec::new(),
+            next_cursor: None,
+            total_count: if filter.is_empty() { len } else { 0 },
+        };
+        if len == 0 || limit == 0 {
+            return Ok(page);
         }
 
-        let transaction = CreditTransaction {
-            user_id: user_id.to_string(),
-            amount,
-            balance_after: new_balance,
-            provider_id: provider_id.to_string(),
-            timestamp: SystemTime::now()
+        let cursor = cursor.unwrap_or(len - 1).min(len - 1);
+
+        // Unfiltered pages only need to read the page itself; filtered ones
+        // walk the whole history so total_count covers every match
+        let mut position = if filter.is_empty() { cursor } else { len - 1 };
+        loop {
+            let start = position.saturating_sub(HISTORY_CHUNK - 1);
+            let chunk = self.store
+                .credit_transactions_range(user_id, start, position)
+                .await?;
+
+            for (index, transaction) in chunk.into_iter().rev() {
+                if !filter.matches(&transaction) {
+                    continue;
+                }
+                if !filter.is_empty() {
+                    page.total_count += 1;
+                }
+                if index > cursor || page.next_cursor.is_some() {
+                    continue;
+                }
+                if page.transactions.len() < limit {
+                    page.transactions.push(transaction);
+                } else {
+                    page.next_cursor = Some(index);
+                }
+            }
+
+            let done = page.next_cursor.is_some() && filter.is_empty();
+            if start == 0 || done {
+                break;
+            }
+            position = start - 1;
+        }
+
+        Ok(page)
+    }
+
+    pub async fn provider_earnings(&self, provider_id: &str) -> redis::RedisResult<ProviderEarnings> {
+        self.store.get_provider_earnings(provider_id).await
+    }
+
+    /// Pays out `amount` of a provider's pending earnings, or all of them
+    /// when `None`. Refused until the pending balance reaches the payout
+    /// threshold.
+    pub async fn request_payout(
+        &self,
+        provider_id: &str,
+        amount: Option<Decimal>,
+    ) -> redis::RedisResult<CreditTransaction> {
+        let earnings = self.provider_earnings(provider_id).await?;
+        if earnings.pending_payout < self.earnings.payout_threshold {
+            return Err(below_payout_threshold());
+        }
+
+        let amount = amount
+            .unwrap_or(earnings.pending_payout)
+            .round_dp_with_strategy(8, RoundingStrategy::ToZero);
+        if amount <= Decimal::ZERO {
+            return Err(insufficient_credits());
+        }
+
+        self.store.record_payout(provider_id, amount).await
+    }
+
+    pub async fn remove_stale(&self) -> redis::RedisResult<u32> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        self.store
+            .remove_stale(now.saturating_sub(self.stale_threshold))
+            .await
+    }
+
+    /// Picks the ask a bid is matched with: `policy` chooses among the Pareto
+    /// frontier of live asks within the bid's price and latency limits that
+    /// have capacity left for the bid.
+    pub async fn select_match(
+        &self,
+        bid: &Bid,
+        policy: &dyn SelectionPolicy,
+    ) -> redis::RedisResult<Option<(Ask, Decimal)>> {
+        let frontier = self.frontier_with_capacity(bid, |_| true).await?;
+        Ok(policy.select(&frontier).cloned())
+    }
+
+    /// Like `select_match`, but also takes the bid's tokens from the chosen
+    /// ask's capacity. When a concurrent fill gets to that capacity first,
+    /// the policy chooses again among the remaining asks.
+    pub async fn claim_match(
+        &self,
+        bid: &Bid,
+        policy: &dyn SelectionPolicy,
+    ) -> redis::RedisResult<Option<(Ask, Decimal, CapacityClaim)>> {
+        self.claim_match_where(bid, policy, |_| true).await
+    }
+
+    /// Like `claim_match`, among only the asks `keep` accepts. The frontier
+    /// is built from those asks, so a passed-over ask does not hide the
+    /// asks it dominates.
+    pub async fn claim_match_where(
+        &self,
+        bid: &Bid,
+        policy: &dyn SelectionPolicy,
+        keep: impl Fn(&Ask) -> bool,
+    ) -> redis::RedisResult<Option<(Ask, Decimal, CapacityClaim)>> {
+        // Asks that lost a claim no longer dominate anything, so the
+        // frontier is rebuilt without them rather than merely shrunk, letting
+        // the asks they dominated back in
+        let mut lost: HashSet<String> = HashSet::new();
+        loop {
+            let frontier = self
+                .frontier_with_capacity(bid, |ask| keep(ask) && !lost.contains(&ask.provider_id))
+                .await?;
+            let Some((ask, cost)) = policy.select(&frontier).cloned() else {
+                return Ok(None);
+            };
+            if let Some(claim) = self.claim_capacity(&ask, bid.estimated_tokens()).await? {
+                return Ok(Some((ask, cost, claim)));
+            }
+            lost.insert(ask.provider_id);
+        }
+    }
+
+    async fn frontier_with_capacity(
+        &self,
+        bid: &Bid,
+        keep: impl Fn(&Ask) -> bool,
+    ) -> redis::RedisResult<Vec<(Ask, Decimal)>> {
+        let mut asks = self.crossing_asks(bid).await?;
+        asks.retain(|ask| keep(ask));
+
+        // Dominating asks never have less capacity than the asks they
+        // dominate, so filtering the frontier loses no candidates
+        let tokens = bid.estimated_tokens();
+        let mut frontier = self.pareto_frontier(asks, tokens).await;
+        frontier.retain(|(ask, _)| ask.available_tokens >= tokens);
+        Ok(frontier)
+    }
+
+    /// Takes `tokens` from an ask's available capacity, or returns `None`
+    /// if it has fewer left.
+    pub async fn claim_capacity(
+        &self,
+        ask: &Ask,
+        tokens: u32,
+    ) -> redis::RedisResult<Option<CapacityClaim>> {
+        let claimed = self.store
+            .adjust_capacity(&ask.provider_id, &ask.model, -(tokens as i64))
+            .await?;
+        Ok(claimed.then(|| CapacityClaim {
+            provider_id: ask.provider_id.clone(),
+            model: ask.model.clone(),
+            tokens,
+        }))
+    }
+
+    /// Returns claimed capacity to its ask once the inference completes or
+    /// fails. Nothing is returned to an ask that has since been removed.
+    pub async fn release_capacity(&self, claim: &CapacityClaim) -> redis::RedisResult<()> {
+        self.store
+            .adjust_capacity(&claim.provider_id, &claim.model, claim.tokens as i64)
+            .await
+            .map(|_| ())
+    }
+
+    /// Closes a claim for a fill the client runs on the provider directly.
+    /// Its tokens are not returned; the provider's next heartbeat reports
+    /// its capacity afresh.
+    pub async fn settle_capacity(&self, claim: &CapacityClaim) -> redis::RedisResult<()> {
+        self.store
+            .settle_capacity(&claim.provider_id, &claim.model, claim.tokens)
+            .await
+    }
+
+    /// Rests a bid that found no match until `expires_at`, holding its
+    /// required credits in the meantime. Bids without required credits
+    /// cannot rest, as there is no ask to price them from.
+    pub async fn rest_bid(&self, bid: Bid, expires_at: u64) -> redis::RedisResult<RestingBid> {
+        let Some(required_credits) = bid.required_credits else {
+            return Err(RedisError::from((
+                redis::ErrorKind::ClientError,
+                "Resting bids must set required credits",
+            )));
+        };
+        // The provider is unknown until the bid fills, and the hold lasts
+        // as long past the bid's expiry as any other
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let reservation = self
+            .reserve_credits_until(&bid.user_id, required_credits, "", now, expires_at + self.reservation_ttl)
+            .await?;
+        let resting = RestingBid {
+            bid_id: format!("bid_{}", Uuid::new_v4().simple()),
+            bid,
+            rested_at_ms: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
-                .as_secs(),
-            transaction_type: "inference_usage".to_string(),
+                .as_millis() as u64,
+            expires_at,
+            reservation,
+            state: BidState::Resting,
         };
 
-        // Atomic transaction
-        redis::pipe()
-            .atomic()
-            .set(&balance_key, new_balance.to_string())
-            .rpush(
-                format!("credit:transactions:{}", user_id),
-                serde_json::to_string(&transaction).unwrap()
-            )
-            .query(&mut self.redis)?;
+        if let Err(e) = self.store.rest_bid(&resting).await {
+            self.release_credits(&resting.reservation).await?;
+            return Err(e);
+        }
+        Ok(resting)
+    }
 
-        Ok(())
+    pub async fn bid_status(&self, bid_id: &str) -> redis::RedisResult<Option<RestingBid>> {
+        self.store.get_bid(bid_id).await
     }
 
-    pub fn remove_stale(&mut self) -> redis::RedisResult<u32> {
+    /// Fills the resting bids `ask` crosses in price-time priority: highest
+    /// limit price first, earliest rested first among equal prices. Returns
+    /// the bids that were filled. Asks with a registered endpoint fill no
+    /// resting bids, since fills here are charged without being forwarded.
+    pub async fn match_resting_bids(&self, ask: &Ask) -> redis::RedisResult<Vec<RestingBid>> {
+        if ask.endpoint.is_some() {
+            return Ok(Vec::new());
+        }
+
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs();
-            
-        let mut removed = 0;
 
-        let keys: Vec<String> = self.redis.keys("ask:*")?;
-        for key in keys {
-            let ask: Ask = match self.redis.get(&key) {
-                Ok(data) => serde_json::from_str(&data).unwrap(),
-                Err(_) => continue,
-            };
+        let mut crossing: Vec<RestingBid> = self.store.resting_bids(&ask.model).await?
+            .into_iter()
+            .filter(|b| b.crosses(ask, now))
+            .collect();
+        crossing.sort_by(|a, b| {
+            b.bid.max_price
+                .cmp(&a.bid.max_price)
+                .then(a.rested_at_ms.cmp(&b.rested_at_ms))
+                .then_with(|| a.bid_id.cmp(&b.bid_id))
+        });
 
-            if now - ask.last_heartbeat > self.stale_threshold {
-                self.redis.del(&key)?;
-                
-                self.redis.zrem(
-                    format!("price:{}:{}", ask.model, ask.gpu_type),
-                    &ask.provider_id
-                )?;
-                
-                self.redis.zrem(
-                    format!("latency:{}:{}", ask.model, ask.gpu_type),
-                    &ask.provider_id
-                )?;
-
-                removed += 1;
+        // One bid failing to fill does not hold up the rest
+        let mut filled = Vec::new();
+        for bid in crossing {
+            let bid_id = bid.bid_id.clone();
+            match self.fill_crossing_bid(bid, ask).await {
+                Ok(Some(bid)) => filled.push(bid),
+                Ok(None) => {}
+                Err(e) => eprintln!("Failed to fill resting bid {}: {}", bid_id, e),
             }
         }
 
-        Ok(removed)
+        Ok(filled)
+    }
+
+    /// Fills one resting bid against `ask`, returning it if it filled. Bids
+    /// too large for the capacity left, or already taken off the book by
+    /// another matcher, are passed over.
+    async fn fill_crossing_bid(
+        &self,
+        mut bid: RestingBid,
+        ask: &Ask,
+    ) -> redis::RedisResult<Option<RestingBid>> {
+        let Some(claim) = self.claim_capacity(ask, bid.bid.estimated_tokens()).await? else {
+            return Ok(None);
+        };
+
+        if !self.store.take_resting_bid(&bid).await? {
+            self.release_capacity(&claim).await?;
+            return Ok(None);
+        }
+
+        let fill = self.fill_resting_bid(&bid, ask).await;
+        if fill.is_err() {
+            self.release_capacity(&claim).await?;
+        } else {
+            self.settle_capacity(&claim).await?;
+        }
+        bid.state = match fill {
+            Ok(transaction) => BidState::Filled {
+                provider_id: ask.provider_id.clone(),
+                transaction_id: transaction.transaction_id,
+                credits_used: transaction.amount,
+                credits_remaining: transaction.balance_after,
+            },
+            Err(e) => BidState::Failed { reason: e.to_string() },
+        };
+        self.store.save_bid(&bid).await?;
+
+        Ok(matches!(bid.state, BidState::Filled { .. }).then_some(bid))
     }
 
+    /// Charges a resting bid's hold to the matched provider. The hold is
+    /// moved onto the provider in one step, so it cannot be spent elsewhere
+    /// in between; a charge that fails returns it to the user.
+    async fn fill_resting_bid(
+        &self,
+        bid: &RestingBid,
+        ask: &Ask,
+    ) -> redis::RedisResult<CreditTransaction> {
+        let reservation = self.store
+            .retarget_reservation(&bid.reservation, &ask.provider_id)
+            .await?;
+        match self.capture_credits(&reservation, reservation.amount).await {
+            Ok(transaction) => Ok(transaction),
+            Err(e) => {
+                if let Err(release_err) = self.release_credits(&reservation).await {
+                    eprintln!("Failed to release reservation {}: {}", reservation.reservation_id, release_err);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Takes a bid off the book and returns its held credits. Bids that
+    /// already left the book are returned as they are.
+    pub async fn cancel_bid(&self, bid_id: &str) -> redis::RedisResult<Option<RestingBid>> {
+        let Some(bid) = self.store.get_bid(bid_id).await? else {
+            return Ok(None);
+        };
+        if bid.state != BidState::Resting {
+            return Ok(Some(bid));
+        }
+
+        match self.close_resting_bid(bid, BidState::Cancelled).await? {
+            Some(bid) => Ok(Some(bid)),
+            None => self.store.get_bid(bid_id).await,
+        }
+    }
+
+    /// Closes every resting bid past its expiry and returns how many it closed.
+    pub async fn expire_resting_bids(&self) -> redis::RedisResult<u32> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let mut expired = 0;
+        for bid in self.store.expired_bids(now).await? {
+            if self.close_resting_bid(bid, BidState::Expired).await?.is_some() {
+                expired += 1;
+            }
+        }
+        Ok(expired)
+    }
+
+    async fn close_resting_bid(
+        &self,
+        mut bid: RestingBid,
+        state: BidState,
+    ) -> redis::RedisResult<Option<RestingBid>> {
+        if !self.store.take_resting_bid(&bid).await? {
+            return Ok(None);
+        }
+
+        self.release_credits(&bid.reservation).await?;
+        bid.state = state;
+        self.store.save_bid(&bid).await?;
+        Ok(Some(bid))
+    }
+
+    #[cfg(test)]
     pub async fn find_matches_with_credits(
-        &mut self,
+        &self,
         bid: &Bid,
-        prompt_length: usize,
+        prompt_tokens: u32,
     ) -> redis::RedisResult<Vec<(Ask, Decimal)>> {
-        let asks: Vec<Ask> = self.redis.keys("ask:*")?
-            .iter()
-            .filter_map(|key| {
-                let ask: Ask = self.redis.get(key).ok()?;
-                if ask.model == bid.model && 
-                   ask.price <= bid.max_price &&
-                   ask.max_latency <= bid.max_latency {
-                    Some(ask)
-                } else {
-                    None
-                }
-            })
-            .collect();
+        let asks = self.crossing_asks(bid).await?;
+        Ok(self.pareto_frontier(asks, prompt_tokens).await)
+    }
 
-        let mut frontier = Vec::new();
+    async fn pareto_frontier(&self, asks: Vec<Ask>, prompt_tokens: u32) -> Vec<(Ask, Decimal)> {
+        let mut frontier: Vec<(Ask, Decimal)> = Vec::new();
         for ask in asks {
-            let credit_cost = ask.calculate_credit_cost(prompt_length);
+            let credit_cost = ask.calculate_credit_cost(prompt_tokens);
             
             if !frontier.iter().any(|(f, _)| f.dominates(&ask)) {
                 frontier.retain(|(f, _)| !ask.dominates(f));
@@ -215,64 +978,602 @@ impl OrderBook {
             }
         }
 
-        // Sort by credit cost as primary key
-        frontier.sort_by(|a, b| a.1.cmp(&b.1));
-        
-        Ok(frontier)
+        // Credit cost first, then price-time priority, so policies that keep
+        // the first of equal candidates pick the same ask every time
+        let p95 = self.p95_snapshot().await;
+        frontier.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| ask_priority(&a.0, &b.0, &p95)));
+        frontier
+    }
+
+    /// Every live ask within the bid's price and latency limits, dominated
+    /// ones included, cheapest per token first and ties broken by
+    /// `ask_priority`.
+    pub async fn ranked_asks(&self, bid: &Bid) -> redis::RedisResult<Vec<Ask>> {
+        let mut asks = self.crossing_asks(bid).await?;
+        let p95 = self.p95_snapshot().await;
+        asks.sort_by(|a, b| {
+            a.credits_per_token()
+                .cmp(&b.credits_per_token())
+                .then_with(|| ask_priority(a, b, &p95))
+        });
+        Ok(asks)
+    }
+
+    /// GPU types that currently have asks for `model`.
+    pub async fn gpu_types(&self, model: &str) -> redis::RedisResult<Vec<String>> {
+        self.store.gpu_types_for_model(model).await
+    }
+
+    /// Live asks for the bid's model within its price and latency limits,
+    /// read from the price index of each GPU type the bid accepts.
+    async fn crossing_asks(&self, bid: &Bid) -> redis::RedisResult<Vec<Ask>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let gpu_types = if bid.gpu_types.is_empty() {
+            self.gpu_types(&bid.model).await?
+        } else {
+            bid.gpu_types.clone()
+        };
+
+        let mut asks = Vec::new();
+        for gpu_type in &gpu_types {
+            let provider_ids = self.store
+                .providers_by_price(&bid.model, gpu_type, bid.max_price)
+                .await?;
+            // An ask read after its provider moved to another GPU type is
+            // left to that type's index
+            asks.extend(
+                self.store.asks_for_providers(&bid.model, &provider_ids).await?
+                    .into_iter()
+                    .filter(|ask| ask.gpu_type == *gpu_type),
+            );
+        }
+
+        asks.retain(|ask| {
+            ask.price <= bid.max_price &&
+            ask.max_latency <= bid.max_latency &&
+            ask.last_heartbeat + self.stale_threshold >= now &&
+            capabilities::meets_all(&bid.requirements, &ask.capabilities)
+        });
+
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            let mut closed = Vec::with_capacity(asks.len());
+            for ask in asks {
+                if circuit_breaker.can_execute(&ask.provider_id).await {
+                    closed.push(ask);
+                }
+            }
+            asks = closed;
+        }
+        Ok(asks)
+    }
+
+    async fn p95_snapshot(&self) -> HashMap<String, Duration> {
+        match &self.latency {
+            Some(router) => router.p95_snapshot().await,
+            None => HashMap::new(),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use redis::Client;
+    use crate::store::MemoryStore;
     use rust_decimal_macros::dec;
 
     fn setup() -> OrderBook {
-        let client = Client::open("redis://127.0.0.1/").unwrap();
-        let conn = client.get_connection().unwrap();
-        OrderBook::new(conn, 60)
+        OrderBook::new(Arc::new(MemoryStore::new()), 60)
     }
 
     #[test]
     fn test_credit_calculation() {
-        let ask = Ask {
-            provider_id: "p1".into(),
-            model: "gpt4".into(),
-            gpu_type: "a100".into(),
-            price: dec!(0.001),
-            max_latency: 100,
-            available_tokens: 1000,
-            last_heartbeat: 123,
-        };
+        let ask = test_ask("p1", dec!(0.001));
 
-        let credit_cost = ask.calculate_credit_cost(100);
-        assert_eq!(credit_cost, dec!(75.00000000)); // (100/4) * 2 * 1.5
+        let credit_cost = ask.calculate_credit_cost(25);
+        assert_eq!(credit_cost, dec!(75.00000000)); // 25 * 2 * 1.5
     }
 
     #[test]
     fn test_pareto_dominance() {
-        let ask1 = Ask {
-            provider_id: "p1".into(),
-            model: "gpt4".into(),
-            gpu_type: "a100".into(),
-            price: dec!(0.001),
-            max_latency: 100,
-            available_tokens: 1000,
-            last_heartbeat: 123,
-        };
+        let ask1 = test_ask("p1", dec!(0.001));
 
         let ask2 = Ask {
-            provider_id: "p2".into(),
-            model: "gpt4".into(),
-            gpu_type: "a100".into(),
-            price: dec!(0.002),
             max_latency: 200,
             available_tokens: 500,
-            last_heartbeat: 123,
+            ..test_ask("p2", dec!(0.002))
         };
 
         assert!(ask1.dominates(&ask2));
         assert!(!ask2.dominates(&ask1));
+
+        // A lower quoted credit rate keeps an otherwise worse ask on the frontier
+        let ask3 = Ask {
+            provider_id: "p3".into(),
+            credit_rate: Some(dec!(0.5)),
+            ..ask2.clone()
+        };
+        assert!(!ask1.dominates(&ask3));
+        assert_eq!(ask3.calculate_credit_cost(10), dec!(5));
+    }
+
+    #[tokio::test]
+    async fn test_reserve_and_capture_credits() {
+        let orderbook = setup();
+        orderbook.store.deposit_credits("u1", dec!(100), "credit_purchase").await.unwrap();
+
+        assert!(orderbook.verify_credits("u1", dec!(40)).await.unwrap());
+        let reservation = orderbook.reserve_credits("u1", dec!(40), "p1").await.unwrap();
+        assert_eq!(reservation.expires_at, reservation.created_at + DEFAULT_RESERVATION_TTL_SECS);
+        assert_eq!(orderbook.get_credit_balance("u1").await.unwrap(), dec!(60));
+        assert_eq!(orderbook.get_reserved_credits("u1").await.unwrap(), dec!(40));
+
+        let err = orderbook.reserve_credits("u1", dec!(61), "p1").await.unwrap_err();
+        assert!(is_insufficient_credits(&err));
+
+        // Capture is capped at the reserved amount
+        let transaction = orderbook.capture_credits(&reservation, dec!(55)).await.unwrap();
+        assert_eq!(transaction.amount, dec!(40));
+        assert_eq!(orderbook.get_credit_balance("u1").await.unwrap(), dec!(60));
+        assert_eq!(orderbook.get_reserved_credits("u1").await.unwrap(), Decimal::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_captured_charges_fund_provider_payouts() {
+        let orderbook = setup().with_earnings_config(EarningsConfig {
+            platform_fee_rate: dec!(0.1),
+            payout_threshold: dec!(50),
+        });
+        orderbook.store.deposit_credits("u1", dec!(200), "credit_purchase").await.unwrap();
+
+        let reservation = orderbook.reserve_credits("u1", dec!(40), "p1").await.unwrap();
+        orderbook.capture_credits(&reservation, dec!(40)).await.unwrap();
+
+        let earnings = orderbook.provider_earnings("p1").await.unwrap();
+        assert_eq!(earnings.total_credits, dec!(36));
+        assert_eq!(earnings.pending_payout, dec!(36));
+
+        let err = orderbook.request_payout("p1", None).await.unwrap_err();
+        assert!(is_below_payout_threshold(&err));
+
+        let reservation = orderbook.reserve_credits("u1", dec!(30), "p1").await.unwrap();
+        orderbook.capture_credits(&reservation, dec!(30)).await.unwrap();
+
+        let payout = orderbook.request_payout("p1", None).await.unwrap();
+        assert_eq!(payout.amount, dec!(63));
+        assert_eq!(payout.balance_after, Decimal::ZERO);
+
+        let earnings = orderbook.provider_earnings("p1").await.unwrap();
+        assert_eq!(earnings.total_credits, dec!(63));
+        assert_eq!(earnings.total_inference_count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_estimate_credits_leaves_balance_untouched() {
+        let orderbook = setup();
+        orderbook.store.deposit_credits("u1", dec!(100), "credit_purchase").await.unwrap();
+        let ask = test_ask("p1", dec!(0.001));
+
+        // 25 prompt tokens
+        let mut bid = Bid {
+            prompt: "x".repeat(100),
+            required_credits: None,
+            ..resting_bid_for("u1", dec!(0.002))
+        };
+        let estimate = orderbook.estimate_credits(&bid, &ask).await.unwrap();
+        assert_eq!(estimate.credits, dec!(75));
+        assert!(estimate.is_affordable());
+        assert_eq!(estimate.remaining(), dec!(25));
+        assert_eq!(orderbook.get_credit_balance("u1").await.unwrap(), dec!(100));
+        assert_eq!(orderbook.get_reserved_credits("u1").await.unwrap(), Decimal::ZERO);
+
+        // Forwarding asks hold the output allowance too
+        let forwarding = Ask {
+            endpoint: Some(ProviderEndpoint {
+                url: "http://p1:11434/api/generate".into(),
+                auth_token: None,
+                protocol: Default::default(),
+                model: None,
+            }),
+            ..ask.clone()
+        };
+        assert_eq!(orderbook.estimate_credits(&bid, &forwarding).await.unwrap().credits, bid.credits_for(&forwarding));
+
+        bid.required_credits = Some(dec!(150));
+        let estimate = orderbook.estimate_credits(&bid, &ask).await.unwrap();
+        assert_eq!(estimate.credits, dec!(150));
+        assert!(!estimate.is_affordable());
+        assert_eq!(estimate.remaining(), Decimal::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_bids_without_required_credits_are_priced_from_the_ask() {
+        let orderbook = setup();
+        let ask = Ask {
+            credit_rate: Some(dec!(0.5)),
+            ..test_ask("p1", dec!(0.001))
+        };
+        let mut bid = resting_bid_for("u1", dec!(0.002));
+        assert_eq!(bid.credits_for(&ask), dec!(10));
+
+        // One prompt token plus the output allowance, at the ask's rate
+        bid.required_credits = None;
+        assert_eq!(bid.credits_for(&ask), dec!(512.5));
+        // Unmetered matches are charged for the prompt alone
+        assert_eq!(bid.unmetered_credits(&ask), dec!(0.5));
+
+        // Without an ask there is nothing to price a resting bid from
+        orderbook.store.deposit_credits("u1", dec!(100), "credit_purchase").await.unwrap();
+        assert!(orderbook.rest_bid(bid, u64::MAX).await.is_err());
+        assert_eq!(orderbook.get_reserved_credits("u1").await.unwrap(), Decimal::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_frontier_ties_break_deterministically() {
+        let router = Arc::new(LatencyRouter::new());
+        let orderbook = setup().with_latency_router(router.clone());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let ask = |provider_id: &str, last_heartbeat: u64| Ask {
+            last_heartbeat,
+            ..test_ask(provider_id, dec!(0.001))
+        };
+
+        // "late" quoted last; "d" and "c" quoted together and split on p95
+        orderbook.add_ask(ask("late", now)).await.unwrap();
+        orderbook.add_ask(ask("late", now)).await.unwrap();
+        for provider_id in ["d", "c", "b"] {
+            orderbook.add_ask(ask(provider_id, now - 10)).await.unwrap();
+        }
+        orderbook.add_ask(ask("late", now + 5)).await.unwrap();
+        router.record_latency("c", Duration::from_millis(80)).await;
+        router.record_latency("d", Duration::from_millis(20)).await;
+        // Written by the provider agent without a posting time
+        orderbook.store.upsert_ask(&ask("agent", now - 20)).await.unwrap();
+
+        let bid = Bid {
+            model: "gpt4".into(),
+            prompt: "test".into(),
+            max_price: dec!(0.002),
+            max_latency: 1000,
+            timestamp: 0,
+            user_id: "u1".into(),
+            required_credits: Some(dec!(10)),
+            metadata: HashMap::new(),
+            gpu_types: Vec::new(),
+            requirements: Vec::new(),
+        };
+        let order: Vec<String> = orderbook
+            .find_matches_with_credits(&bid, 1)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(ask, _)| ask.provider_id)
+            .collect();
+        assert_eq!(order, vec!["d", "c", "b", "late", "agent"]);
+
+        // Heartbeats at the same price keep the original posting time
+        let late = orderbook.store.get_ask("late", "gpt4").await.unwrap().unwrap();
+        assert_eq!(late.posted_at, now);
+    }
+
+    #[tokio::test]
+    async fn test_matches_across_registered_gpu_types() {
+        let orderbook = setup();
+        for (provider_id, gpu_type) in [("a", "a100"), ("h", "h100"), ("l", "l4")] {
+            orderbook.add_ask(Ask {
+                gpu_type: gpu_type.into(),
+                credit_rate: Some(dec!(1)),
+                ..test_ask(provider_id, dec!(0.001))
+            }).await.unwrap();
+        }
+
+        let mut bid = resting_bid_for("u1", dec!(0.002));
+        let providers = |asks: Vec<(Ask, Decimal)>| {
+            let mut ids: Vec<String> = asks.into_iter().map(|(a, _)| a.provider_id).collect();
+            ids.sort();
+            ids
+        };
+
+        // Asks quoting the same rate: none dominates another, so each GPU type shows up
+        assert_eq!(providers(orderbook.find_matches_with_credits(&bid, 1).await.unwrap()), vec!["a", "h", "l"]);
+
+        bid.gpu_types = vec!["h100".into(), "l4".into(), "tpu".into()];
+        assert_eq!(providers(orderbook.find_matches_with_credits(&bid, 1).await.unwrap()), vec!["h", "l"]);
+    }
+
+    #[tokio::test]
+    async fn test_requirements_filter_before_frontier() {
+        let orderbook = setup();
+        for (provider_id, price, context_length) in [("cheap", dec!(0.001), "8192"), ("long", dec!(0.0015), "128000")] {
+            orderbook.add_ask(Ask {
+                capabilities: HashMap::from([("context_length".to_string(), context_length.to_string())]),
+                ..test_ask(provider_id, price)
+            }).await.unwrap();
+        }
+
+        let mut bid = resting_bid_for("u1", dec!(0.002));
+        let matched = |asks: Vec<(Ask, Decimal)>| -> Vec<String> {
+            asks.into_iter().map(|(a, _)| a.provider_id).collect()
+        };
+        // Without requirements the cheaper ask dominates
+        assert_eq!(matched(orderbook.find_matches_with_credits(&bid, 1).await.unwrap()), vec!["cheap"]);
+
+        bid.requirements = vec!["context_length >= 32000".parse().unwrap()];
+        assert_eq!(matched(orderbook.find_matches_with_credits(&bid, 1).await.unwrap()), vec!["long"]);
+
+        bid.requirements = vec!["function_calling".parse().unwrap()];
+        assert!(orderbook.find_matches_with_credits(&bid, 1).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_claims_pass_over_failed_providers() {
+        let breaker = Arc::new(CircuitBreaker::new(1, Duration::from_secs(60), Duration::ZERO));
+        let orderbook = setup().with_circuit_breaker(breaker.clone());
+        for (provider_id, price) in [("cheap", dec!(0.001)), ("dear", dec!(0.0015))] {
+            orderbook.add_ask(test_ask(provider_id, price)).await.unwrap();
+        }
+
+        let bid = resting_bid_for("u1", dec!(0.002));
+        let claimed = |claim: Option<(Ask, Decimal, CapacityClaim)>| claim.unwrap().0.provider_id;
+        let policy = crate::selection::Cheapest;
+        assert_eq!(claimed(orderbook.claim_match(&bid, &policy).await.unwrap()), "cheap");
+
+        // "dear" is dominated by "cheap" but is next once "cheap" is passed over
+        let next = orderbook.claim_match_where(&bid, &policy, |ask| ask.provider_id != "cheap").await.unwrap();
+        assert_eq!(claimed(next), "dear");
+
+        // An open circuit leaves the provider out of every match
+        breaker.record_failure("cheap").await;
+        assert_eq!(claimed(orderbook.claim_match(&bid, &policy).await.unwrap()), "dear");
+    }
+
+    /// Picks the cheapest ask, but first drains the capacity of the named
+    /// provider's ask, as a concurrent bid claiming it between the read and
+    /// the claim would.
+    struct RacedCheapest(Arc<MemoryStore>, &'static str);
+
+    impl SelectionPolicy for RacedCheapest {
+        fn select<'a>(&self, frontier: &'a [(Ask, Decimal)]) -> Option<&'a (Ask, Decimal)> {
+            let first = frontier.first()?;
+            let (ask, _) = first;
+            if ask.provider_id == self.1 {
+                let drain = -(ask.available_tokens as i64);
+                futures::executor::block_on(self.0.adjust_capacity(&ask.provider_id, &ask.model, drain)).unwrap();
+            }
+            Some(first)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lost_claim_falls_back_to_dominated_asks() {
+        let store = Arc::new(MemoryStore::new());
+        let orderbook = OrderBook::new(store.clone(), 60);
+        for (provider_id, price) in [("cheap", dec!(0.001)), ("dear", dec!(0.0015))] {
+            orderbook.add_ask(test_ask(provider_id, price)).await.unwrap();
+        }
+
+        // "dear" was dominated by "cheap", so it is only found by rebuilding
+        // the frontier once the claim on "cheap" is lost
+        let bid = resting_bid_for("u1", dec!(0.002));
+        let (ask, _, _) = orderbook.claim_match(&bid, &RacedCheapest(store, "cheap")).await.unwrap().unwrap();
+        assert_eq!(ask.provider_id, "dear");
     }
-}
\ No newline at end of file
+
+    fn resting_bid_for(user_id: &str, max_price: Decimal) -> Bid {
+        Bid {
+            model: "gpt4".into(),
+            prompt: "test".into(),
+            max_price,
+            max_latency: 1000,
+            timestamp: 0,
+            user_id: user_id.into(),
+            required_credits: Some(dec!(10)),
+            metadata: HashMap::new(),
+            gpu_types: Vec::new(),
+            requirements: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resting_bids_fill_in_price_time_priority() {
+        let orderbook = setup();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        for user in ["u1", "u2", "u3"] {
+            orderbook.store.deposit_credits(user, dec!(100), "credit_purchase").await.unwrap();
+        }
+
+        let low = orderbook.rest_bid(resting_bid_for("u1", dec!(0.001)), now + 60).await.unwrap();
+        let early = orderbook.rest_bid(resting_bid_for("u2", dec!(0.002)), now + 60).await.unwrap();
+        // Time priority has millisecond resolution
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        let late = orderbook.rest_bid(resting_bid_for("u3", dec!(0.002)), now + 60).await.unwrap();
+        assert_eq!(orderbook.get_credit_balance("u1").await.unwrap(), dec!(90));
+
+        let ask = test_ask("p1", dec!(0.0015));
+        orderbook.add_ask(ask.clone()).await.unwrap();
+        let filled: Vec<String> = orderbook
+            .match_resting_bids(&ask)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|b| b.bid_id)
+            .collect();
+        assert_eq!(filled, vec![early.bid_id.clone(), late.bid_id.clone()]);
+
+        // The low bid does not cross and keeps resting
+        let status = orderbook.bid_status(&low.bid_id).await.unwrap().unwrap();
+        assert_eq!(status.state, BidState::Resting);
+        match orderbook.bid_status(&early.bid_id).await.unwrap().unwrap().state {
+            BidState::Filled { provider_id, credits_used, .. } => {
+                assert_eq!(provider_id, "p1");
+                assert_eq!(credits_used, dec!(10));
+            }
+            other => panic!("unexpected state {:?}", other),
+        }
+        assert_eq!(orderbook.provider_earnings("p1").await.unwrap().total_inference_count, 2);
+        // Each fill took the bid's tokens from the ask
+        assert_eq!(orderbook.store.get_ask("p1", "gpt4").await.unwrap().unwrap().available_tokens, 998);
+
+        let cancelled = orderbook.cancel_bid(&low.bid_id).await.unwrap().unwrap();
+        assert_eq!(cancelled.state, BidState::Cancelled);
+        assert_eq!(orderbook.get_credit_balance("u1").await.unwrap(), dec!(100));
+        assert!(orderbook.match_resting_bids(&Ask { price: dec!(0.0001), ..ask.clone() }).await.unwrap().is_empty());
+
+        // Bids filled here would be charged without reaching the provider
+        let forwarding = Ask {
+            endpoint: Some(ProviderEndpoint {
+                url: "http://p1:11434/api/generate".into(),
+                auth_token: None,
+                protocol: Default::default(),
+                model: None,
+            }),
+            ..ask
+        };
+        orderbook.store.deposit_credits("u1", dec!(10), "credit_purchase").await.unwrap();
+        let waiting = orderbook.rest_bid(resting_bid_for("u1", dec!(0.002)), now + 60).await.unwrap();
+        assert!(orderbook.match_resting_bids(&forwarding).await.unwrap().is_empty());
+        let status = orderbook.bid_status(&waiting.bid_id).await.unwrap().unwrap();
+        assert_eq!(status.state, BidState::Resting);
+    }
+
+    #[tokio::test]
+    async fn test_resting_fills_move_the_hold_and_continue_past_failures() {
+        let orderbook = setup();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        for user in ["u1", "u2"] {
+            orderbook.store.deposit_credits(user, dec!(10), "credit_purchase").await.unwrap();
+        }
+        let lost = orderbook.rest_bid(resting_bid_for("u1", dec!(0.003)), now + 60).await.unwrap();
+        let kept = orderbook.rest_bid(resting_bid_for("u2", dec!(0.002)), now + 60).await.unwrap();
+        // The first bid's hold is gone, so its fill fails
+        orderbook.release_credits(&lost.reservation).await.unwrap();
+
+        let ask = test_ask("p1", dec!(0.001));
+        orderbook.add_ask(ask.clone()).await.unwrap();
+        let filled = orderbook.match_resting_bids(&ask).await.unwrap();
+        assert_eq!(filled.len(), 1);
+        assert_eq!(filled[0].bid_id, kept.bid_id);
+
+        let failed = orderbook.bid_status(&lost.bid_id).await.unwrap().unwrap();
+        assert!(matches!(failed.state, BidState::Failed { .. }));
+        assert_eq!(orderbook.get_credit_balance("u1").await.unwrap(), dec!(10));
+
+        // The held credits were charged to p1 without passing through the balance
+        assert_eq!(orderbook.get_credit_balance("u2").await.unwrap(), Decimal::ZERO);
+        assert_eq!(orderbook.get_reserved_credits("u2").await.unwrap(), Decimal::ZERO);
+        assert_eq!(orderbook.provider_earnings("p1").await.unwrap().total_inference_count, 1);
+        // The failed fill's capacity went back to the ask
+        assert_eq!(orderbook.store.get_ask("p1", "gpt4").await.unwrap().unwrap().available_tokens, 999);
+    }
+
+    #[tokio::test]
+    async fn test_expired_bids_release_their_hold() {
+        let orderbook = setup();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        orderbook.store.deposit_credits("u1", dec!(100), "credit_purchase").await.unwrap();
+
+        let bid = orderbook.rest_bid(resting_bid_for("u1", dec!(0.002)), now).await.unwrap();
+        assert_eq!(orderbook.expire_resting_bids().await.unwrap(), 1);
+        assert_eq!(orderbook.bid_status(&bid.bid_id).await.unwrap().unwrap().state, BidState::Expired);
+        assert_eq!(orderbook.get_credit_balance("u1").await.unwrap(), dec!(100));
+        assert_eq!(orderbook.expire_resting_bids().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_release_credits() {
+        let orderbook = setup();
+        orderbook.store.deposit_credits("u1", dec!(10), "credit_purchase").await.unwrap();
+
+        let reservation = orderbook.reserve_credits("u1", dec!(10), "p1").await.unwrap();
+        assert!(!orderbook.verify_credits("u1", dec!(1)).await.unwrap());
+        assert_eq!(orderbook.release_credits(&reservation).await.unwrap(), dec!(10));
+        assert!(orderbook.release_credits(&reservation).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_history_pagination() {
+        let orderbook = setup();
+        for _ in 0..5 {
+            orderbook.store.deposit_credits("u1", dec!(10), "credit_purchase").await.unwrap();
+            let reservation = orderbook.reserve_credits("u1", dec!(1), "p1").await.unwrap();
+            orderbook.capture_credits(&reservation, dec!(1)).await.unwrap();
+        }
+
+        let all = TransactionFilter::default();
+        let first = orderbook.transaction_history("u1", &all, None, 4).await.unwrap();
+        assert_eq!(first.total_count, 10);
+        assert_eq!(first.transactions.len(), 4);
+        assert_eq!(first.transactions[0].transaction_type, "inference_usage");
+        assert_eq!(first.next_cursor, Some(5));
+
+        let rest = orderbook.transaction_history("u1", &all, first.next_cursor, 10).await.unwrap();
+        assert_eq!(rest.transactions.len(), 6);
+        assert_eq!(rest.next_cursor, None);
+
+        let usage = TransactionFilter {
+            transaction_types: vec!["inference_usage".into()],
+            ..Default::default()
+        };
+        let page = orderbook.transaction_history("u1", &usage, None, 3).await.unwrap();
+        assert_eq!(page.total_count, 5);
+        assert_eq!(page.transactions.len(), 3);
+        assert!(page.transactions.iter().all(|t| t.amount == dec!(1)));
+        assert_eq!(page.next_cursor, Some(3));
+
+        let page = orderbook.transaction_history("u1", &usage, page.next_cursor, 3).await.unwrap();
+        assert_eq!(page.transactions.len(), 2);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[tokio::test]
+    async fn test_find_matches_with_credits() {
+        let orderbook = setup();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        for (provider_id, price, max_latency) in [
+            ("p1", dec!(0.001), 100),
+            ("p2", dec!(0.002), 200),
+            ("p3", dec!(0.0005), 300),
+            ("p4", dec!(0.01), 50),
+        ] {
+            orderbook.add_ask(Ask {
+                max_latency,
+                ..test_ask(provider_id, price)
+            }).await.unwrap();
+        }
+
+        let bid = Bid {
+            model: "gpt4".into(),
+            prompt: "test".into(),
+            max_price: dec!(0.005),
+            max_latency: 1000,
+            timestamp: now,
+            user_id: "u1".into(),
+            required_credits: Some(dec!(1)),
+            metadata: HashMap::new(),
+            gpu_types: Vec::new(),
+            requirements: Vec::new(),
+        };
+
+        let mut matched: Vec<String> = orderbook
+            .find_matches_with_credits(&bid, 25)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(ask, _)| ask.provider_id)
+            .collect();
+        matched.sort();
+
+        // p2 is dominated by p1; p4 is over the bid's max price
+        assert_eq!(matched, vec!["p1".to_string(), "p3".to_string()]);
+
+        let (fastest, _) = orderbook
+            .select_match(&bid, &crate::selection::LowestLatency)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fastest.provider_id, "p1");
+    }
+}
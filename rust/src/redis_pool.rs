@@ -0,0 +1,189 @@
+use redis::aio::MultiplexedConnection;
+use redis::{Client, FromRedisValue, RedisError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout};
+
+#[derive(Debug, Clone)]
+pub struct RedisPoolConfig {
+    pub pool_size: usize,
+    pub connect_timeout: Duration,
+    pub response_timeout: Duration,
+    pub reconnect_attempts: u32,
+    pub reconnect_backoff: Duration,
+}
+
+impl Default for RedisPoolConfig {
+    fn default() -> Self {
+        Self {
+            pool_size: 8,
+            connect_timeout: Duration::from_secs(2),
+            response_timeout: Duration::from_secs(1),
+            reconnect_attempts: 3,
+            reconnect_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RedisPoolConfig {
+    /// Reads overrides from `REDIS_POOL_SIZE`, `REDIS_CONNECT_TIMEOUT_MS`,
+    /// `REDIS_RESPONSE_TIMEOUT_MS`, `REDIS_RECONNECT_ATTEMPTS` and
+    /// `REDIS_RECONNECT_BACKOFF_MS`, falling back to the defaults.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name).ok().and_then(|v| v.parse().ok())
+        }
+
+        let defaults = Self::default();
+        Self {
+            pool_size: var::<usize>("REDIS_POOL_SIZE")
+                .unwrap_or(defaults.pool_size)
+                .max(1),
+            connect_timeout: var("REDIS_CONNECT_TIMEOUT_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.connect_timeout),
+            response_timeout: var("REDIS_RESPONSE_TIMEOUT_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.response_timeout),
+            reconnect_attempts: var("REDIS_RECONNECT_ATTEMPTS")
+                .unwrap_or(defaults.reconnect_attempts),
+            reconnect_backoff: var("REDIS_RECONNECT_BACKOFF_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.reconnect_backoff),
+        }
+    }
+}
+
+fn timeout_error(message: &'static str) -> RedisError {
+    RedisError::from((redis::ErrorKind::IoError, message))
+}
+
+/// Fixed-size set of multiplexed async connections handed out round-robin.
+/// Slots connect lazily and are dropped on I/O errors or timeouts so the next
+/// checkout reconnects.
+pub struct RedisPool {
+    client: Client,
+    config: RedisPoolConfig,
+    slots: Vec<Mutex<Option<MultiplexedConnection>>>,
+    next: AtomicUsize,
+}
+
+impl RedisPool {
+    pub fn new(client: Client, config: RedisPoolConfig) -> Self {
+        let slots = (0..config.pool_size.max(1)).map(|_| Mutex::new(None)).collect();
+        Self {
+            client,
+            config,
+            slots,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub async fn query<T: FromRedisValue>(&self, cmd: &redis::Cmd) -> redis::RedisResult<T> {
+        let (slot, mut conn) = self.checkout().await?;
+        let result = timeout(self.config.response_timeout, cmd.query_async(&mut conn)).await;
+        self.settle(slot, result).await
+    }
+
+    pub async fn query_pipeline<T: FromRedisValue>(
+        &self,
+        pipe: &redis::Pipeline,
+    ) -> redis::RedisResult<T> {
+        let (slot, mut conn) = self.checkout().await?;
+        let result = timeout(self.config.response_timeout, pipe.query_async(&mut conn)).await;
+        self.settle(slot, result).await
+    }
+
+    pub async fn invoke<T: FromRedisValue>(
+        &self,
+        invocation: &redis::ScriptInvocation<'_>,
+    ) -> redis::RedisResult<T> {
+        let (slot, mut conn) = self.checkout().await?;
+        let result = timeout(self.config.response_timeout, invocation.invoke_async(&mut conn)).await;
+        self.settle(slot, result).await
+    }
+
+    async fn settle<T>(
+        &self,
+        slot: usize,
+        result: Result<redis::RedisResult<T>, tokio::time::error::Elapsed>,
+    ) -> redis::RedisResult<T> {
+        match result {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(e)) => {
+                if e.is_io_error() || e.is_connection_dropped() {
+                    self.invalidate(slot).await;
+                }
+                Err(e)
+            }
+            Err(_) => {
+                self.invalidate(slot).await;
+                Err(timeout_error("Redis response timed out"))
+            }
+        }
+    }
+
+    async fn checkout(&self) -> redis::RedisResult<(usize, MultiplexedConnection)> {
+        let slot = self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        let mut guard = self.slots[slot].lock().await;
+
+        if let Some(conn) = guard.as_ref() {
+            return Ok((slot, conn.clone()));
+        }
+
+        let conn = self.connect().await?;
+        *guard = Some(conn.clone());
+        Ok((slot, conn))
+    }
+
+    async fn invalidate(&self, slot: usize) {
+        *self.slots[slot].lock().await = None;
+    }
+
+    async fn connect(&self) -> redis::RedisResult<MultiplexedConnection> {
+        let mut attempt = 0;
+        loop {
+            let result = match timeout(
+                self.config.connect_timeout,
+                self.client.get_multiplexed_tokio_connection(),
+            )
+            .await
+            {
+                Ok(result) => result,
+                Err(_) => Err(timeout_error("Redis connect timed out")),
+            };
+
+            match result {
+                Ok(conn) => return Ok(conn),
+                Err(_) if attempt < self.config.reconnect_attempts => {
+                    attempt += 1;
+                    sleep(self.config.reconnect_backoff * attempt).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unreachable_server_fails_after_retries() {
+        // Nothing listens on port 1, so every attempt is refused immediately
+        let client = Client::open("redis://127.0.0.1:1/").unwrap();
+        let pool = RedisPool::new(client, RedisPoolConfig {
+            pool_size: 2,
+            connect_timeout: Duration::from_millis(200),
+            response_timeout: Duration::from_millis(200),
+            reconnect_attempts: 2,
+            reconnect_backoff: Duration::from_millis(1),
+        });
+
+        let result: redis::RedisResult<Option<String>> = pool.query(&redis::Cmd::get("key")).await;
+        assert!(result.is_err());
+        assert!(pool.slots[0].lock().await.is_none());
+    }
+}
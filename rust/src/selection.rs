@@ -0,0 +1,195 @@
+use crate::orderbook::Ask;
+use rust_decimal::prelude::*;
+use std::collections::HashMap;
+
+// Bid.metadata entries that choose how a frontier ask is picked
+const POLICY_KEY: &str = "selection_policy";
+const PRICE_WEIGHT_KEY: &str = "weight_price";
+const LATENCY_WEIGHT_KEY: &str = "weight_latency";
+const CAPACITY_WEIGHT_KEY: &str = "weight_capacity";
+
+/// Chooses one ask from a Pareto frontier of `(ask, credit_cost)` pairs.
+/// Frontier asks are all efficient, so a policy only expresses which
+/// trade-off between cost, latency and capacity the bidder prefers.
+pub trait SelectionPolicy: Send + Sync {
+    fn select<'a>(&self, frontier: &'a [(Ask, Decimal)]) -> Option<&'a (Ask, Decimal)>;
+}
+
+/// Lowest credit cost, then lowest quoted price.
+pub struct Cheapest;
+
+impl SelectionPolicy for Cheapest {
+    fn select<'a>(&self, frontier: &'a [(Ask, Decimal)]) -> Option<&'a (Ask, Decimal)> {
+        frontier
+            .iter()
+            .min_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.price.cmp(&b.0.price)))
+    }
+}
+
+/// Lowest quoted latency, then lowest credit cost.
+pub struct LowestLatency;
+
+impl SelectionPolicy for LowestLatency {
+    fn select<'a>(&self, frontier: &'a [(Ask, Decimal)]) -> Option<&'a (Ask, Decimal)> {
+        frontier
+            .iter()
+            .min_by(|a, b| a.0.max_latency.cmp(&b.0.max_latency).then_with(|| a.1.cmp(&b.1)))
+    }
+}
+
+/// Scores each ask on credit cost, latency and spare capacity, each
+/// normalised to `0..=1` across the frontier, and picks the lowest weighted
+/// score. Ties go to the earlier frontier entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeightedUtility {
+    pub price: f64,
+    pub latency: f64,
+    pub capacity: f64,
+}
+
+impl Default for WeightedUtility {
+    fn default() -> Self {
+        Self {
+            price: 0.5,
+            latency: 0.3,
+            capacity: 0.2,
+        }
+    }
+}
+
+impl SelectionPolicy for WeightedUtility {
+    fn select<'a>(&self, frontier: &'a [(Ask, Decimal)]) -> Option<&'a (Ask, Decimal)> {
+        let costs: Vec<f64> = frontier.iter().map(|(_, c)| c.to_f64().unwrap_or(f64::MAX)).collect();
+        let latencies: Vec<f64> = frontier.iter().map(|(a, _)| a.max_latency as f64).collect();
+        let capacities: Vec<f64> = frontier.iter().map(|(a, _)| a.available_tokens as f64).collect();
+
+        let scores = (0..frontier.len()).map(|i| {
+            self.price * normalize(&costs, i)
+                + self.latency * normalize(&latencies, i)
+                + self.capacity * (1.0 - normalize(&capacities, i))
+        });
+
+        scores
+            .enumerate()
+            .fold(None, |best: Option<(usize, f64)>, (i, score)| match best {
+                Some((_, best_score)) if best_score <= score => best,
+                _ => Some((i, score)),
+            })
+            .map(|(i, _)| &frontier[i])
+    }
+}
+
+fn normalize(values: &[f64], index: usize) -> f64 {
+    let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if max > min {
+        (values[index] - min) / (max - min)
+    } else {
+        0.0
+    }
+}
+
+/// Builds the policy a bid asked for through its metadata. Without a
+/// `selection_policy` entry, bids carrying weights get a weighted policy and
+/// all others the cheapest ask.
+pub fn policy_for_bid(metadata: &HashMap<String, String>) -> Result<Box<dyn SelectionPolicy>, String> {
+    let has_weights = [PRICE_WEIGHT_KEY, LATENCY_WEIGHT_KEY, CAPACITY_WEIGHT_KEY]
+        .iter()
+        .any(|k| metadata.contains_key(*k));
+
+    match metadata.get(POLICY_KEY).map(String::as_str) {
+        None | Some("") if has_weights => Ok(Box::new(weights_for_bid(metadata)?)),
+        None | Some("") | Some("cheapest") => Ok(Box::new(Cheapest)),
+        Some("lowest_latency") => Ok(Box::new(LowestLatency)),
+        Some("weighted") => Ok(Box::new(weights_for_bid(metadata)?)),
+        Some(other) => Err(format!("Unknown selection policy: {}", other)),
+    }
+}
+
+fn weights_for_bid(metadata: &HashMap<String, String>) -> Result<WeightedUtility, String> {
+    let weight = |key: &str, default: f64| -> Result<f64, String> {
+        match metadata.get(key) {
+            None => Ok(default),
+            Some(v) => v
+                .parse::<f64>()
+                .ok()
+                .filter(|w| w.is_finite() && *w >= 0.0)
+                .ok_or_else(|| format!("Invalid {}: {}", key, v)),
+        }
+    };
+
+    let defaults = WeightedUtility::default();
+    let weights = WeightedUtility {
+        price: weight(PRICE_WEIGHT_KEY, defaults.price)?,
+        latency: weight(LATENCY_WEIGHT_KEY, defaults.latency)?,
+        capacity: weight(CAPACITY_WEIGHT_KEY, defaults.capacity)?,
+    };
+
+    if weights.price + weights.latency + weights.capacity == 0.0 {
+        return Err("Selection weights must not all be zero".to_string());
+    }
+    Ok(weights)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::test_ask;
+    use rust_decimal_macros::dec;
+
+    fn candidate(provider_id: &str, cost: Decimal, max_latency: u32, available_tokens: u32) -> (Ask, Decimal) {
+        let ask = Ask {
+            max_latency,
+            available_tokens,
+            ..test_ask(provider_id, dec!(0.001))
+        };
+        (ask, cost)
+    }
+
+    fn frontier() -> Vec<(Ask, Decimal)> {
+        vec![
+            candidate("cheap", dec!(1), 900, 1000),
+            candidate("fast", dec!(3), 50, 1000),
+            candidate("big", dec!(2), 500, 100_000),
+        ]
+    }
+
+    fn selected(policy: &dyn SelectionPolicy) -> String {
+        policy.select(&frontier()).unwrap().0.provider_id.clone()
+    }
+
+    fn metadata(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_builtin_policies() {
+        assert_eq!(selected(&Cheapest), "cheap");
+        assert_eq!(selected(&LowestLatency), "fast");
+        assert_eq!(selected(&WeightedUtility { price: 0.0, latency: 0.0, capacity: 1.0 }), "big");
+        assert!(Cheapest.select(&[]).is_none());
+    }
+
+    #[test]
+    fn test_policy_from_bid_metadata() {
+        assert_eq!(selected(policy_for_bid(&HashMap::new()).unwrap().as_ref()), "cheap");
+        assert_eq!(
+            selected(policy_for_bid(&metadata(&[("selection_policy", "lowest_latency")])).unwrap().as_ref()),
+            "fast"
+        );
+
+        // Weights alone imply the weighted policy
+        let latency_first = metadata(&[("weight_price", "0.1"), ("weight_latency", "1"), ("weight_capacity", "0")]);
+        assert_eq!(selected(policy_for_bid(&latency_first).unwrap().as_ref()), "fast");
+
+        assert!(policy_for_bid(&metadata(&[("selection_policy", "random")])).is_err());
+        assert!(policy_for_bid(&metadata(&[("weight_price", "-1")])).is_err());
+        assert!(policy_for_bid(&metadata(&[
+            ("selection_policy", "weighted"),
+            ("weight_price", "0"),
+            ("weight_latency", "0"),
+            ("weight_capacity", "0"),
+        ]))
+        .is_err());
+    }
+}
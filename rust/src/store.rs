@@ -0,0 +1,2161 @@
+use crate::ledger::{
+    deposit_postings, hold_postings, new_transaction_id, payout_postings, settle_postings, Account,
+    LedgerCheckpoint, Posting,
+};
+use crate::orderbook::{
+    insufficient_credits, Ask, CreditReservation, CreditTransaction, ProviderEarnings, RestingBid,
+};
+use crate::redis_pool::{RedisPool, RedisPoolConfig};
+use redis::{Client, Cmd, RedisError, Script};
+use rust_decimal::prelude::*;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Storage backend behind the order book: asks with their price/latency
+/// indexes, and the credit ledger. Every credit write also appends its
+/// transaction, postings included, to the ledger journal in the same step.
+#[tonic::async_trait]
+pub trait OrderBookStore: Send + Sync {
+    async fn upsert_ask(&self, ask: &Ask) -> redis::RedisResult<()>;
+
+    async fn get_ask(&self, provider_id: &str, model: &str) -> redis::RedisResult<Option<Ask>>;
+
+    /// Provider IDs on the `price:{model}:{gpu_type}` index quoting at or
+    /// below `max_price`, cheapest first.
+    async fn providers_by_price(
+        &self,
+        model: &str,
+        gpu_type: &str,
+        max_price: Decimal,
+    ) -> redis::RedisResult<Vec<String>>;
+
+    /// The asks `provider_ids` have posted for `model`; providers without
+    /// one are skipped.
+    async fn asks_for_providers(
+        &self,
+        model: &str,
+        provider_ids: &[String],
+    ) -> redis::RedisResult<Vec<Ask>>;
+
+    /// GPU types with at least one ask for `model`, sorted.
+    async fn gpu_types_for_model(&self, model: &str) -> redis::RedisResult<Vec<String>>;
+
+    /// Stored asks read through the model/GPU indexes; `None` leaves that
+    /// dimension unrestricted.
+    async fn list_asks(
+        &self,
+        model: Option<&str>,
+        gpu_type: Option<&str>,
+    ) -> redis::RedisResult<Vec<Ask>>;
+
+    async fn remove_ask(&self, ask: &Ask) -> redis::RedisResult<()>;
+
+    /// Adds `delta` to a stored ask's `available_tokens` unless that would
+    /// take it below zero. Returns false in that case, or when the ask is
+    /// gone. Tokens taken stay claimed until given back, even if the ask is
+    /// gone by then, and heartbeats store the provider's own figure less the
+    /// tokens still claimed.
+    async fn adjust_capacity(&self, provider_id: &str, model: &str, delta: i64) -> redis::RedisResult<bool>;
+
+    /// Stops counting `tokens` of an ask's capacity as claimed without
+    /// giving them back, leaving them to the provider's next heartbeat.
+    async fn settle_capacity(&self, provider_id: &str, model: &str, tokens: u32) -> redis::RedisResult<()>;
+
+    /// Removes every ask whose last heartbeat is older than `cutoff`, found
+    /// through the heartbeat index, and returns how many were dropped.
+    async fn remove_stale(&self, cutoff: u64) -> redis::RedisResult<u32>;
+
+    /// Spendable balance, excluding credits held by open reservations.
+    async fn get_credit_balance(&self, user_id: &str) -> redis::RedisResult<Decimal>;
+
+    async fn get_reserved_credits(&self, user_id: &str) -> redis::RedisResult<Decimal>;
+
+    /// Adds `amount` to the user's balance and records it as a
+    /// `transaction_type` transaction. Purchases are credited by the payment
+    /// webhook, so the matcher itself only deposits in tests.
+    #[allow(dead_code)]
+    async fn deposit_credits(
+        &self,
+        user_id: &str,
+        amount: Decimal,
+        transaction_type: &str,
+    ) -> redis::RedisResult<CreditTransaction>;
+
+    /// Moves `reservation.amount` from the user's balance into their reserved
+    /// credits, failing if the balance cannot cover it. Returns the balance
+    /// left after the hold.
+    async fn reserve_credits(&self, reservation: &CreditReservation) -> redis::RedisResult<Decimal>;
+
+    /// Closes a reservation, charging `amount` (at most the reserved amount)
+    /// and returning the rest to the user's balance. In the same step the
+    /// charge is credited to the reservation's provider, except for
+    /// `platform_fee`, which goes to the platform account.
+    async fn capture_reservation(
+        &self,
+        reservation: &CreditReservation,
+        amount: Decimal,
+        platform_fee: Decimal,
+    ) -> redis::RedisResult<CreditTransaction>;
+
+    /// Closes a reservation without charging and returns the restored balance.
+    async fn release_reservation(&self, reservation: &CreditReservation) -> redis::RedisResult<Decimal>;
+
+    /// Releases every open reservation whose `expires_at` is at or before
+    /// `now`, found through the reservation expiry index, and returns how
+    /// many were released.
+    async fn release_expired_reservations(&self, now: u64) -> redis::RedisResult<u32>;
+
+    /// Points an open reservation at `provider_id` in one step, keeping its
+    /// hold, and returns the updated reservation.
+    async fn retarget_reservation(
+        &self,
+        reservation: &CreditReservation,
+        provider_id: &str,
+    ) -> redis::RedisResult<CreditReservation>;
+
+    async fn credit_transaction_count(&self, user_id: &str) -> redis::RedisResult<usize>;
+
+    /// Transactions at history positions `start..=stop` (0 is the oldest),
+    /// paired with their position.
+    async fn credit_transactions_range(
+        &self,
+        user_id: &str,
+        start: usize,
+        stop: usize,
+    ) -> redis::RedisResult<Vec<(usize, CreditTransaction)>>;
+
+    async fn get_provider_earnings(&self, provider_id: &str) -> redis::RedisResult<ProviderEarnings>;
+
+    async fn get_platform_fees(&self) -> redis::RedisResult<Decimal>;
+
+    /// Moves `amount` out of the provider's pending payout, failing if it is
+    /// not covered, and records a `provider_payout` transaction.
+    async fn record_payout(
+        &self,
+        provider_id: &str,
+        amount: Decimal,
+    ) -> redis::RedisResult<CreditTransaction>;
+
+    /// Number of entries in the ledger journal.
+    async fn ledger_len(&self) -> redis::RedisResult<usize>;
+
+    /// Journal entries at positions `start..=stop`, oldest first.
+    async fn ledger_range(&self, start: usize, stop: usize) -> redis::RedisResult<Vec<CreditTransaction>>;
+
+    /// Appends an entry that moves no stored balance, such as an opening
+    /// balance, to the journal.
+    async fn append_ledger_entry(&self, entry: &CreditTransaction) -> redis::RedisResult<()>;
+
+    async fn ledger_checkpoint(&self) -> redis::RedisResult<Option<LedgerCheckpoint>>;
+
+    async fn save_ledger_checkpoint(&self, checkpoint: &LedgerCheckpoint) -> redis::RedisResult<()>;
+
+    /// Every account with a stored balance, whether or not the journal has
+    /// touched it. Only the first reconciliation lists them, so this may
+    /// scan the keyspace.
+    async fn ledger_accounts(&self) -> redis::RedisResult<Vec<Account>>;
+
+    /// Claims the user's idempotency `key` for `ttl` seconds unless it is
+    /// already claimed, in which case the earlier request's state is returned.
+    /// The claim only needs to outlive the request; completing it keeps the
+    /// response for longer.
+    async fn claim_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        ttl: u64,
+    ) -> redis::RedisResult<IdempotencyState>;
+
+    /// Records the encoded response for a claimed key, restarting its TTL.
+    async fn complete_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        response: &[u8],
+        ttl: u64,
+    ) -> redis::RedisResult<()>;
+
+    /// Drops a claim whose request failed so a retry can run again.
+    async fn release_idempotency_key(&self, user_id: &str, key: &str) -> redis::RedisResult<()>;
+
+    /// Stores a bid and puts it on its model's book of resting bids.
+    async fn rest_bid(&self, bid: &RestingBid) -> redis::RedisResult<()>;
+
+    async fn get_bid(&self, bid_id: &str) -> redis::RedisResult<Option<RestingBid>>;
+
+    /// Bids resting on `model`'s book, earliest rested first.
+    async fn resting_bids(&self, model: &str) -> redis::RedisResult<Vec<RestingBid>>;
+
+    /// Resting bids, across all models, that expire at or before `now`.
+    async fn expired_bids(&self, now: u64) -> redis::RedisResult<Vec<RestingBid>>;
+
+    /// Takes a bid off the book. Only one caller gets `true` for a given
+    /// bid; the others find it already gone.
+    async fn take_resting_bid(&self, bid: &RestingBid) -> redis::RedisResult<bool>;
+
+    /// Records the final state of a bid taken off the book. The record is
+    /// kept for `BID_RECORD_TTL_SECS`.
+    async fn save_bid(&self, bid: &RestingBid) -> redis::RedisResult<()>;
+}
+
+/// What a repeated idempotency key refers to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdempotencyState {
+    /// The key was unused and now belongs to the caller
+    Claimed,
+    /// The first request with this key has not finished yet
+    InProgress,
+    /// The first request finished with this encoded response
+    Completed(Vec<u8>),
+}
+
+// Secondary indexes over ask keys, maintained alongside every ask write so
+// lookups never have to scan the keyspace.
+const HEARTBEAT_INDEX: &str = "asks:heartbeat";
+
+fn ask_key(provider_id: &str, model: &str) -> String {
+    format!("ask:{}:{}", provider_id, model)
+}
+
+// Tokens taken from an ask by fills still in flight
+fn claimed_key(provider_id: &str, model: &str) -> String {
+    format!("capacity:claimed:{}:{}", provider_id, model)
+}
+
+// Registry of GPU types each model has been quoted on. Entries whose price
+// index has emptied are pruned when the registry is read
+fn gpu_registry(model: &str) -> String {
+    format!("gpus:{}", model)
+}
+
+fn model_index(model: &str) -> String {
+    format!("asks:model:{}", model)
+}
+
+fn gpu_index(gpu_type: &str) -> String {
+    format!("asks:gpu:{}", gpu_type)
+}
+
+fn parse_decimal(value: Option<String>) -> Decimal {
+    value
+        .and_then(|v| Decimal::from_str(&v).ok())
+        .unwrap_or(Decimal::ZERO)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn ledger_transaction(
+    user_id: &str,
+    provider_id: &str,
+    amount: Decimal,
+    balance_after: Decimal,
+    transaction_type: &str,
+    postings: Vec<Posting>,
+) -> CreditTransaction {
+    CreditTransaction {
+        transaction_id: new_transaction_id(),
+        user_id: user_id.to_string(),
+        amount,
+        balance_after,
+        provider_id: provider_id.to_string(),
+        timestamp: now_secs(),
+        transaction_type: transaction_type.to_string(),
+        postings,
+    }
+}
+
+fn deposit_transaction(
+    user_id: &str,
+    amount: Decimal,
+    balance_after: Decimal,
+    transaction_type: &str,
+) -> CreditTransaction {
+    ledger_transaction(
+        user_id,
+        "",
+        amount,
+        balance_after,
+        transaction_type,
+        deposit_postings(user_id, amount),
+    )
+}
+
+// Holds and releases only appear in the journal, not in user history
+fn hold_transaction(reservation: &CreditReservation, balance_after: Decimal) -> CreditTransaction {
+    ledger_transaction(
+        &reservation.user_id,
+        &reservation.provider_id,
+        reservation.amount,
+        balance_after,
+        "credit_hold",
+        hold_postings(reservation),
+    )
+}
+
+fn release_transaction(reservation: &CreditReservation, balance_after: Decimal) -> CreditTransaction {
+    ledger_transaction(
+        &reservation.user_id,
+        &reservation.provider_id,
+        reservation.amount,
+        balance_after,
+        "credit_release",
+        settle_postings(reservation, Decimal::ZERO, Decimal::ZERO),
+    )
+}
+
+fn usage_transaction(
+    reservation: &CreditReservation,
+    amount: Decimal,
+    platform_fee: Decimal,
+    balance_after: Decimal,
+) -> CreditTransaction {
+    ledger_transaction(
+        &reservation.user_id,
+        &reservation.provider_id,
+        amount,
+        balance_after,
+        "inference_usage",
+        settle_postings(reservation, amount, platform_fee),
+    )
+}
+
+fn payout_transaction(
+    provider_id: &str,
+    amount: Decimal,
+    earnings: &ProviderEarnings,
+) -> CreditTransaction {
+    CreditTransaction {
+        timestamp: earnings.last_payout,
+        ..ledger_transaction(
+            provider_id,
+            provider_id,
+            amount,
+            earnings.pending_payout,
+            "provider_payout",
+            payout_postings(provider_id, amount),
+        )
+    }
+}
+
+fn reservation_error(message: &'static str) -> RedisError {
+    RedisError::from((redis::ErrorKind::ResponseError, message))
+}
+
+// Credit scripts compare-and-set the raw balance strings read by the caller.
+// Decimal arithmetic stays in Rust (Lua numbers are doubles), and a script
+// that sees a concurrent change returns 0 so the caller re-reads and retries.
+const CAS_ATTEMPTS: usize = 8;
+
+const DEPOSIT_SCRIPT: &str = r"
+if (redis.call('GET', KEYS[1]) or '') ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2])
+redis.call('RPUSH', KEYS[2], ARGV[3])
+redis.call('RPUSH', KEYS[3], ARGV[3])
+return 1
+";
+
+const RESERVE_SCRIPT: &str = r"
+if (redis.call('GET', KEYS[1]) or '') ~= ARGV[1]
+    or (redis.call('GET', KEYS[2]) or '') ~= ARGV[3] then
+    return 0
+end
+if redis.call('EXISTS', KEYS[3]) == 1 then
+    return -1
+end
+redis.call('SET', KEYS[1], ARGV[2])
+redis.call('SET', KEYS[2], ARGV[4])
+redis.call('SET', KEYS[3], ARGV[5])
+redis.call('RPUSH', KEYS[4], ARGV[6])
+if tonumber(ARGV[7]) > 0 then
+    redis.call('ZADD', KEYS[5], ARGV[7], ARGV[8])
+end
+return 1
+";
+
+const SETTLE_SCRIPT: &str = r"
+if (redis.call('GET', KEYS[1]) or '') ~= ARGV[1]
+    or (redis.call('GET', KEYS[2]) or '') ~= ARGV[3]
+    or (redis.call('GET', KEYS[5]) or '') ~= ARGV[7]
+    or (redis.call('GET', KEYS[6]) or '') ~= ARGV[9] then
+    return 0
+end
+if redis.call('GET', KEYS[3]) ~= ARGV[5] then
+    return -1
+end
+redis.call('SET', KEYS[1], ARGV[2])
+redis.call('SET', KEYS[2], ARGV[4])
+redis.call('DEL', KEYS[3])
+redis.call('ZREM', KEYS[8], ARGV[12])
+if ARGV[6] ~= '' then
+    redis.call('RPUSH', KEYS[4], ARGV[6])
+    redis.call('SET', KEYS[5], ARGV[8])
+    redis.call('SET', KEYS[6], ARGV[10])
+end
+redis.call('RPUSH', KEYS[7], ARGV[11])
+return 1
+";
+
+// Rewrites an ask only if it is unchanged since it was read, moving its
+// claimed tokens by ARGV[3]
+const CAPACITY_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2])
+if redis.call('INCRBY', KEYS[2], ARGV[3]) <= 0 then
+    redis.call('DEL', KEYS[2])
+end
+return 1
+";
+
+const UNCLAIM_SCRIPT: &str = r"
+if redis.call('DECRBY', KEYS[1], ARGV[1]) <= 0 then
+    redis.call('DEL', KEYS[1])
+end
+return 1
+";
+
+// Writes a heartbeat only if the claimed tokens it was reduced by are unchanged
+const HEARTBEAT_SCRIPT: &str = r"
+if (redis.call('GET', KEYS[2]) or '') ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2])
+return 1
+";
+
+/// The provider's advertised capacity less the tokens still claimed.
+fn unclaimed_capacity(advertised: u32, claimed: Option<&str>) -> u32 {
+    let claimed = claimed.and_then(|c| c.parse::<u64>().ok()).unwrap_or(0);
+    u32::try_from((advertised as u64).saturating_sub(claimed)).unwrap_or(0)
+}
+
+fn adjusted_capacity(available: u32, delta: i64) -> Option<u32> {
+    u32::try_from(available as i64 + delta).ok()
+}
+
+struct CreditKeys {
+    balance: String,
+    reserved: String,
+    transactions: String,
+}
+
+impl CreditKeys {
+    fn new(user_id: &str) -> Self {
+        Self {
+            balance: format!("credit:balance:{}", user_id),
+            reserved: format!("credit:reserved:{}", user_id),
+            transactions: format!("credit:transactions:{}", user_id),
+        }
+    }
+}
+
+fn reservation_key(reservation_id: &str) -> String {
+    format!("credit:reservation:{}", reservation_id)
+}
+
+// IDs of open reservations scored by expiry time, for the reservation sweep
+const RESERVATION_EXPIRY_INDEX: &str = "credit:reservations:expiry";
+
+// Holds sit in the user's escrow whichever provider they are for, so
+// retargeting only rewrites the stored reservation
+const RETARGET_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2])
+return 1
+";
+
+// An in-flight claim is stored as an empty value, expiring after the short
+// claim TTL unless completed; completed responses are never empty
+const CLAIM_SCRIPT: &str = r"
+local existing = redis.call('GET', KEYS[1])
+if existing then
+    return existing
+end
+redis.call('SET', KEYS[1], '', 'EX', ARGV[1])
+return false
+";
+
+fn idempotency_key(user_id: &str, key: &str) -> String {
+    format!("idempotency:{}:{}", user_id, key)
+}
+
+// Resting bids are ordered by the time they rested on their model's book;
+// a single expiry index covers all models
+const BID_EXPIRY_INDEX: &str = "bids:expiry";
+
+// How long filled, cancelled and expired bids can still be looked up
+pub const BID_RECORD_TTL_SECS: usize = 86400;
+
+fn bid_key(bid_id: &str) -> String {
+    format!("bid:{}", bid_id)
+}
+
+fn resting_index(model: &str) -> String {
+    format!("bids:resting:{}", model)
+}
+
+// Every credit transaction in write order, holds and releases included
+const JOURNAL_KEY: &str = "ledger:journal";
+
+const LEDGER_CHECKPOINT_KEY: &str = "ledger:checkpoint";
+
+// Provider earnings are read by the provider agent; platform fees accumulate
+// in a single account
+const PLATFORM_FEES_KEY: &str = "platform:fees";
+
+fn earnings_key(provider_id: &str) -> String {
+    format!("provider:earnings:{}", provider_id)
+}
+
+fn provider_transactions_key(provider_id: &str) -> String {
+    format!("provider:transactions:{}", provider_id)
+}
+
+fn parse_earnings(value: Option<String>) -> ProviderEarnings {
+    value
+        .and_then(|v| serde_json::from_str(&v).ok())
+        .unwrap_or_default()
+}
+
+pub struct RedisStore {
+    pool: RedisPool,
+    deposit_script: Script,
+    reserve_script: Script,
+    settle_script: Script,
+    claim_script: Script,
+    capacity_script: Script,
+    unclaim_script: Script,
+    heartbeat_script: Script,
+    retarget_script: Script,
+}
+
+impl RedisStore {
+    pub fn new(client: Client, config: RedisPoolConfig) -> Self {
+        Self {
+            pool: RedisPool::new(client, config),
+            deposit_script: Script::new(DEPOSIT_SCRIPT),
+            reserve_script: Script::new(RESERVE_SCRIPT),
+            settle_script: Script::new(SETTLE_SCRIPT),
+            claim_script: Script::new(CLAIM_SCRIPT),
+            capacity_script: Script::new(CAPACITY_SCRIPT),
+            unclaim_script: Script::new(UNCLAIM_SCRIPT),
+            heartbeat_script: Script::new(HEARTBEAT_SCRIPT),
+            retarget_script: Script::new(RETARGET_SCRIPT),
+        }
+    }
+}
+
+impl RedisStore {
+    /// Fetches the asks behind `keys`, also returning the keys whose ask has
+    /// already expired or been deleted.
+    async fn load_asks(&self, keys: Vec<String>) -> redis::RedisResult<(Vec<Ask>, Vec<String>)> {
+        if keys.is_empty() {
+            return Ok((Vec::new(), Vec::new()));
+        }
+
+        let data: Vec<Option<String>> = self.pool
+            .query(redis::cmd("MGET").arg(&keys))
+            .await?;
+
+        let mut asks = Vec::with_capacity(keys.len());
+        let mut missing = Vec::new();
+        for (key, data) in keys.into_iter().zip(data) {
+            match data.and_then(|d| serde_json::from_str::<Ask>(&d).ok()) {
+                Some(ask) => asks.push(ask),
+                None => missing.push(key),
+            }
+        }
+
+        Ok((asks, missing))
+    }
+
+    async fn load_bids(&self, ids: Vec<String>) -> redis::RedisResult<Vec<RestingBid>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let keys: Vec<String> = ids.iter().map(|id| bid_key(id)).collect();
+        let data: Vec<Option<String>> = self.pool
+            .query(redis::cmd("MGET").arg(&keys))
+            .await?;
+        Ok(data
+            .into_iter()
+            .flatten()
+            .filter_map(|d| serde_json::from_str(&d).ok())
+            .collect())
+    }
+
+    /// Raw balance and reserved strings, as compared by the credit scripts.
+    async fn read_credit_state(&self, keys: &CreditKeys) -> redis::RedisResult<(String, String)> {
+        let (balance, reserved): (Option<String>, Option<String>) = self.pool
+            .query(redis::cmd("MGET").arg(&keys.balance).arg(&keys.reserved))
+            .await?;
+        Ok((balance.unwrap_or_default(), reserved.unwrap_or_default()))
+    }
+
+    /// Closes `reservation`, charging `charge` of it and crediting the
+    /// charge to the provider less the platform fee. Returns the balance
+    /// after the unused part was returned.
+    async fn settle(
+        &self,
+        reservation: &CreditReservation,
+        charge: Option<(Decimal, Decimal)>,
+    ) -> redis::RedisResult<(Decimal, Option<CreditTransaction>)> {
+        let keys = CreditKeys::new(&reservation.user_id);
+        let earnings_key = earnings_key(&reservation.provider_id);
+        let stored = serde_json::to_string(reservation).unwrap();
+
+        for _ in 0..CAS_ATTEMPTS {
+            let (raw_balance, raw_reserved) = self.read_credit_state(&keys).await?;
+            let (raw_earnings, raw_fees): (Option<String>, Option<String>) = self.pool
+                .query(redis::cmd("MGET").arg(&earnings_key).arg(PLATFORM_FEES_KEY))
+                .await?;
+
+            let (amount, platform_fee) = charge.unwrap_or_default();
+            let new_balance = parse_decimal(Some(raw_balance.clone())) + reservation.amount - amount;
+            let new_reserved = (parse_decimal(Some(raw_reserved.clone())) - reservation.amount)
+                .max(Decimal::ZERO);
+
+            let mut earnings = parse_earnings(raw_earnings.clone());
+            earnings.credit(amount - platform_fee);
+            let new_fees = parse_decimal(raw_fees.clone()) + platform_fee;
+
+            let entry = match charge {
+                Some(_) => usage_transaction(reservation, amount, platform_fee, new_balance),
+                None => release_transaction(reservation, new_balance),
+            };
+            let entry_json = serde_json::to_string(&entry).unwrap();
+
+            let mut invocation = self.settle_script.prepare_invoke();
+            invocation
+                .key(&keys.balance)
+                .key(&keys.reserved)
+                .key(reservation_key(&reservation.reservation_id))
+                .key(&keys.transactions)
+                .key(&earnings_key)
+                .key(PLATFORM_FEES_KEY)
+                .key(JOURNAL_KEY)
+                .key(RESERVATION_EXPIRY_INDEX)
+                .arg(raw_balance)
+                .arg(new_balance.to_string())
+                .arg(raw_reserved)
+                .arg(new_reserved.to_string())
+                .arg(&stored)
+                .arg(if charge.is_some() { entry_json.as_str() } else { "" })
+                .arg(raw_earnings.unwrap_or_default())
+                .arg(serde_json::to_string(&earnings).unwrap())
+                .arg(raw_fees.unwrap_or_default())
+                .arg(new_fees.to_string())
+                .arg(&entry_json)
+                .arg(&reservation.reservation_id);
+
+            match self.pool.invoke::<i64>(&invocation).await? {
+                1 => return Ok((new_balance, charge.map(|_| entry))),
+                -1 => return Err(reservation_error("Reservation not found")),
+                _ => continue,
+            }
+        }
+
+        Err(reservation_error("Credit balance contention"))
+    }
+
+    /// Drops index entries that point at asks which no longer exist, e.g.
+    /// ones written with a TTL by the provider agent. The GPU type of an
+    /// expired ask is unknown, so its price and latency entries are removed
+    /// from every GPU type registered for the model; that lets the registry
+    /// prune GPU types whose last ask expired.
+    async fn prune_index_entries(&self, keys: &[String]) -> redis::RedisResult<()> {
+        let mut by_model: HashMap<&str, Vec<&str>> = HashMap::new();
+        for key in keys {
+            let ask = key.strip_prefix("ask:").unwrap_or(key);
+            if let Some((provider_id, model)) = ask.rsplit_once(':') {
+                by_model.entry(model).or_default().push(provider_id);
+            }
+        }
+
+        let mut registry = redis::pipe();
+        for model in by_model.keys() {
+            registry.smembers(gpu_registry(model));
+        }
+        let gpu_types: Vec<Vec<String>> = self.pool.query_pipeline(&registry).await?;
+
+        let mut pipe = redis::pipe();
+        pipe.zrem(HEARTBEAT_INDEX, keys).ignore();
+
+        for ((model, provider_ids), gpu_types) in by_model.iter().zip(gpu_types) {
+            let model_keys: Vec<String> = provider_ids.iter().map(|p| ask_key(p, model)).collect();
+            pipe.srem(model_index(model), &model_keys).ignore();
+            for gpu_type in &gpu_types {
+                pipe.srem(gpu_index(gpu_type), &model_keys)
+                    .ignore()
+                    .zrem(format!("price:{}:{}", model, gpu_type), provider_ids)
+                    .ignore()
+                    .zrem(format!("latency:{}:{}", model, gpu_type), provider_ids)
+                    .ignore();
+            }
+        }
+
+        self.pool.query_pipeline(&pipe).await
+    }
+}
+
+#[tonic::async_trait]
+impl OrderBookStore for RedisStore {
+    async fn upsert_ask(&self, ask: &Ask) -> redis::RedisResult<()> {
+        let key = ask_key(&ask.provider_id, &ask.model);
+        let claimed_key = claimed_key(&ask.provider_id, &ask.model);
+        let previous = self.get_ask(&ask.provider_id, &ask.model).await?;
+
+        let mut written = false;
+        for _ in 0..CAS_ATTEMPTS {
+            let claimed: Option<String> = self.pool.query(&Cmd::get(&claimed_key)).await?;
+            let stored = Ask {
+                available_tokens: unclaimed_capacity(ask.available_tokens, claimed.as_deref()),
+                ..ask.clone()
+            };
+
+            let mut invocation = self.heartbeat_script.prepare_invoke();
+            invocation
+                .key(&key)
+                .key(&claimed_key)
+                .arg(claimed.unwrap_or_default())
+                .arg(serde_json::to_string(&stored).unwrap());
+            if self.pool.invoke::<i64>(&invocation).await? == 1 {
+                written = true;
+                break;
+            }
+        }
+        if !written {
+            return Err(reservation_error("Ask capacity contention"));
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        // A provider that moved the model to another GPU type must not linger
+        // in the old GPU's indexes
+        if let Some(previous) = previous.filter(|p| p.gpu_type != ask.gpu_type) {
+            pipe.srem(gpu_index(&previous.gpu_type), &key)
+                .ignore()
+                .zrem(
+                    format!("price:{}:{}", previous.model, previous.gpu_type),
+                    &previous.provider_id,
+                )
+                .ignore()
+                .zrem(
+                    format!("latency:{}:{}", previous.model, previous.gpu_type),
+                    &previous.provider_id,
+                )
+                .ignore();
+        }
+
+        pipe.zadd(
+            format!("price:{}:{}", ask.model, ask.gpu_type),
+            &ask.provider_id,
+            ask.price.to_string(),
+        )
+        .ignore()
+        .zadd(
+            format!("latency:{}:{}", ask.model, ask.gpu_type),
+            &ask.provider_id,
+            ask.max_latency,
+        )
+        .ignore()
+        .sadd(model_index(&ask.model), &key)
+        .ignore()
+        .sadd(gpu_index(&ask.gpu_type), &key)
+        .ignore()
+        .sadd(gpu_registry(&ask.model), &ask.gpu_type)
+        .ignore()
+        .zadd(HEARTBEAT_INDEX, &key, ask.last_heartbeat)
+        .ignore();
+
+        self.pool.query_pipeline(&pipe).await
+    }
+
+    async fn get_ask(&self, provider_id: &str, model: &str) -> redis::RedisResult<Option<Ask>> {
+        let data: Option<String> = self.pool
+            .query(&Cmd::get(ask_key(provider_id, model)))
+            .await?;
+        Ok(data.and_then(|d| serde_json::from_str(&d).ok()))
+    }
+
+    async fn providers_by_price(
+        &self,
+        model: &str,
+        gpu_type: &str,
+        max_price: Decimal,
+    ) -> redis::RedisResult<Vec<String>> {
+        self.pool.query(&Cmd::zrangebyscore(
+            format!("price:{}:{}", model, gpu_type),
+            "-inf",
+            max_price.to_string(),
+        )).await
+    }
+
+    async fn asks_for_providers(
+        &self,
+        model: &str,
+        provider_ids: &[String],
+    ) -> redis::RedisResult<Vec<Ask>> {
+        let keys = provider_ids.iter().map(|p| ask_key(p, model)).collect();
+        let (asks, _) = self.load_asks(keys).await?;
+        Ok(asks)
+    }
+
+    async fn gpu_types_for_model(&self, model: &str) -> redis::RedisResult<Vec<String>> {
+        let registered: Vec<String> = self.pool
+            .query(&Cmd::smembers(gpu_registry(model)))
+            .await?;
+        if registered.is_empty() {
+            return Ok(registered);
+        }
+
+        let mut pipe = redis::pipe();
+        for gpu_type in &registered {
+            pipe.zcard(format!("price:{}:{}", model, gpu_type));
+        }
+        let counts: Vec<usize> = self.pool.query_pipeline(&pipe).await?;
+
+        let (mut live, empty): (Vec<_>, Vec<_>) = registered
+            .into_iter()
+            .zip(counts)
+            .partition(|(_, count)| *count > 0);
+        if !empty.is_empty() {
+            let empty: Vec<String> = empty.into_iter().map(|(g, _)| g).collect();
+            self.pool.query::<()>(&Cmd::srem(gpu_registry(model), empty)).await?;
+        }
+
+        live.sort();
+        Ok(live.into_iter().map(|(g, _)| g).collect())
+    }
+
+    async fn list_asks(
+        &self,
+        model: Option<&str>,
+        gpu_type: Option<&str>,
+    ) -> redis::RedisResult<Vec<Ask>> {
+        let keys: Vec<String> = match (model, gpu_type) {
+            (None, None) => self.pool.query(&Cmd::zrange(HEARTBEAT_INDEX, 0, -1)).await?,
+            (Some(m), None) => self.pool.query(&Cmd::smembers(model_index(m))).await?,
+            (None, Some(g)) => self.pool.query(&Cmd::smembers(gpu_index(g))).await?,
+            (Some(m), Some(g)) => {
+                self.pool
+                    .query(&Cmd::sinter(&[model_index(m), gpu_index(g)]))
+                    .await?
+            }
+        };
+
+        let (asks, missing) = self.load_asks(keys).await?;
+        if !missing.is_empty() {
+            self.prune_index_entries(&missing).await?;
+        }
+
+        Ok(asks)
+    }
+
+    async fn remove_ask(&self, ask: &Ask) -> redis::RedisResult<()> {
+        let key = ask_key(&ask.provider_id, &ask.model);
+
+        self.pool.query_pipeline(
+            redis::pipe()
+                .atomic()
+                .del(&key)
+                .ignore()
+                .zrem(
+                    format!("price:{}:{}", ask.model, ask.gpu_type),
+                    &ask.provider_id,
+                )
+                .ignore()
+                .zrem(
+                    format!("latency:{}:{}", ask.model, ask.gpu_type),
+                    &ask.provider_id,
+                )
+                .ignore()
+                .srem(model_index(&ask.model), &key)
+                .ignore()
+                .srem(gpu_index(&ask.gpu_type), &key)
+                .ignore()
+                .zrem(HEARTBEAT_INDEX, &key)
+                .ignore(),
+        ).await
+    }
+
+    async fn adjust_capacity(&self, provider_id: &str, model: &str, delta: i64) -> redis::RedisResult<bool> {
+        let key = ask_key(provider_id, model);
+
+        for _ in 0..CAS_ATTEMPTS {
+            let raw: Option<String> = self.pool.query(&Cmd::get(&key)).await?;
+            let Some((raw, mut ask)) = raw.and_then(|r| {
+                let ask = serde_json::from_str::<Ask>(&r).ok()?;
+                Some((r, ask))
+            }) else {
+                // Tokens given back to a removed ask are no longer claimed
+                if let Ok(tokens) = u32::try_from(delta) {
+                    self.settle_capacity(provider_id, model, tokens).await?;
+                }
+                return Ok(false);
+            };
+            let Some(available) = adjusted_capacity(ask.available_tokens, delta) else {
+                return Ok(false);
+            };
+            ask.available_tokens = available;
+
+            let mut invocation = self.capacity_script.prepare_invoke();
+            invocation
+                .key(&key)
+                .key(claimed_key(provider_id, model))
+                .arg(raw)
+                .arg(serde_json::to_string(&ask).unwrap())
+                .arg(-delta);
+
+            if self.pool.invoke::<i64>(&invocation).await? == 1 {
+                return Ok(true);
+            }
+        }
+
+        Err(reservation_error("Ask capacity contention"))
+    }
+
+    async fn settle_capacity(&self, provider_id: &str, model: &str, tokens: u32) -> redis::RedisResult<()> {
+        let mut invocation = self.unclaim_script.prepare_invoke();
+        invocation.key(claimed_key(provider_id, model)).arg(tokens);
+        self.pool.invoke::<i64>(&invocation).await.map(|_| ())
+    }
+
+    async fn remove_stale(&self, cutoff: u64) -> redis::RedisResult<u32> {
+        let keys: Vec<String> = self.pool
+            .query(&Cmd::zrangebyscore(HEARTBEAT_INDEX, "-inf", format!("({}", cutoff)))
+            .await?;
+
+        let (asks, missing) = self.load_asks(keys).await?;
+        if !missing.is_empty() {
+            self.prune_index_entries(&missing).await?;
+        }
+
+        let mut removed = missing.len() as u32;
+        for ask in asks {
+            // Skip asks that heartbeated between the index read and the load
+            if ask.last_heartbeat < cutoff {
+                self.remove_ask(&ask).await?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    async fn get_credit_balance(&self, user_id: &str) -> redis::RedisResult<Decimal> {
+        let balance: Option<String> = self.pool
+            .query(&Cmd::get(CreditKeys::new(user_id).balance))
+            .await?;
+        Ok(parse_decimal(balance))
+    }
+
+    async fn get_reserved_credits(&self, user_id: &str) -> redis::RedisResult<Decimal> {
+        let reserved: Option<String> = self.pool
+            .query(&Cmd::get(CreditKeys::new(user_id).reserved))
+            .await?;
+        Ok(parse_decimal(reserved))
+    }
+
+    async fn deposit_credits(
+        &self,
+        user_id: &str,
+        amount: Decimal,
+        transaction_type: &str,
+    ) -> redis::RedisResult<CreditTransaction> {
+        let keys = CreditKeys::new(user_id);
+
+        for _ in 0..CAS_ATTEMPTS {
+            let (raw_balance, _) = self.read_credit_state(&keys).await?;
+            let new_balance = parse_decimal(Some(raw_balance.clone())) + amount;
+            if new_balance < Decimal::ZERO {
+                return Err(insufficient_credits());
+            }
+
+            let transaction = deposit_transaction(user_id, amount, new_balance, transaction_type);
+
+            let mut invocation = self.deposit_script.prepare_invoke();
+            invocation
+                .key(&keys.balance)
+                .key(&keys.transactions)
+                .key(JOURNAL_KEY)
+                .arg(raw_balance)
+                .arg(new_balance.to_string())
+                .arg(serde_json::to_string(&transaction).unwrap());
+
+            if self.pool.invoke::<i64>(&invocation).await? == 1 {
+                return Ok(transaction);
+            }
+        }
+
+        Err(reservation_error("Credit balance contention"))
+    }
+
+    async fn reserve_credits(&self, reservation: &CreditReservation) -> redis::RedisResult<Decimal> {
+        let keys = CreditKeys::new(&reservation.user_id);
+        let stored = serde_json::to_string(reservation).unwrap();
+
+        for _ in 0..CAS_ATTEMPTS {
+            let (raw_balance, raw_reserved) = self.read_credit_state(&keys).await?;
+            let new_balance = parse_decimal(Some(raw_balance.clone())) - reservation.amount;
+            if new_balance < Decimal::ZERO {
+                return Err(insufficient_credits());
+            }
+            let new_reserved = parse_decimal(Some(raw_reserved.clone())) + reservation.amount;
+            let hold = hold_transaction(reservation, new_balance);
+
+            let mut invocation = self.reserve_script.prepare_invoke();
+            invocation
+                .key(&keys.balance)
+                .key(&keys.reserved)
+                .key(reservation_key(&reservation.reservation_id))
+                .key(JOURNAL_KEY)
+                .key(RESERVATION_EXPIRY_INDEX)
+                .arg(raw_balance)
+                .arg(new_balance.to_string())
+                .arg(raw_reserved)
+                .arg(new_reserved.to_string())
+                .arg(&stored)
+                .arg(serde_json::to_string(&hold).unwrap())
+                .arg(reservation.expires_at)
+                .arg(&reservation.reservation_id);
+
+            match self.pool.invoke::<i64>(&invocation).await? {
+                1 => return Ok(new_balance),
+                -1 => return Err(reservation_error("Reservation already exists")),
+                _ => continue,
+            }
+        }
+
+        Err(reservation_error("Credit balance contention"))
+    }
+
+    async fn capture_reservation(
+        &self,
+        reservation: &CreditReservation,
+        amount: Decimal,
+        platform_fee: Decimal,
+    ) -> redis::RedisResult<CreditTransaction> {
+        if amount > reservation.amount {
+            return Err(reservation_error("Capture exceeds reserved credits"));
+        }
+        if platform_fee > amount {
+            return Err(reservation_error("Platform fee exceeds charge"));
+        }
+
+        let (_, transaction) = self.settle(reservation, Some((amount, platform_fee))).await?;
+        Ok(transaction.unwrap())
+    }
+
+    async fn release_reservation(&self, reservation: &CreditReservation) -> redis::RedisResult<Decimal> {
+        let (balance, _) = self.settle(reservation, None).await?;
+        Ok(balance)
+    }
+
+    async fn release_expired_reservations(&self, now: u64) -> redis::RedisResult<u32> {
+        let ids: Vec<String> = self.pool
+            .query(&Cmd::zrangebyscore(RESERVATION_EXPIRY_INDEX, "-inf", now))
+            .await?;
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let keys: Vec<String> = ids.iter().map(|id| reservation_key(id)).collect();
+        let data: Vec<Option<String>> = self.pool
+            .query(redis::cmd("MGET").arg(&keys))
+            .await?;
+
+        let mut released = 0;
+        for (id, data) in ids.iter().zip(data) {
+            let Some(reservation) = data.and_then(|d| serde_json::from_str::<CreditReservation>(&d).ok()) else {
+                // Settled between the index read and the load
+                self.pool.query::<()>(&Cmd::zrem(RESERVATION_EXPIRY_INDEX, id)).await?;
+                continue;
+            };
+            match self.settle(&reservation, None).await {
+                Ok(_) => released += 1,
+                // Settled or retargeted since it was loaded; a retargeted
+                // hold is picked up again by the next sweep
+                Err(e) if e.to_string() == reservation_error("Reservation not found").to_string() => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(released)
+    }
+
+    async fn retarget_reservation(
+        &self,
+        reservation: &CreditReservation,
+        provider_id: &str,
+    ) -> redis::RedisResult<CreditReservation> {
+        let retargeted = CreditReservation {
+            provider_id: provider_id.to_string(),
+            ..reservation.clone()
+        };
+
+        let mut invocation = self.retarget_script.prepare_invoke();
+        invocation
+            .key(reservation_key(&reservation.reservation_id))
+            .arg(serde_json::to_string(reservation).unwrap())
+            .arg(serde_json::to_string(&retargeted).unwrap());
+        match self.pool.invoke::<i64>(&invocation).await? {
+            1 => Ok(retargeted),
+            _ => Err(reservation_error("Reservation not found")),
+        }
+    }
+
+    async fn credit_transaction_count(&self, user_id: &str) -> redis::RedisResult<usize> {
+        self.pool
+            .query(&Cmd::llen(CreditKeys::new(user_id).transactions))
+            .await
+    }
+
+    async fn credit_transactions_range(
+        &self,
+        user_id: &str,
+        start: usize,
+        stop: usize,
+    ) -> redis::RedisResult<Vec<(usize, CreditTransaction)>> {
+        let entries: Vec<String> = self.pool
+            .query(&Cmd::lrange(
+                CreditKeys::new(user_id).transactions,
+                start as isize,
+                stop as isize,
+            ))
+            .await?;
+        Ok(entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, e)| Some((start + i, serde_json::from_str(e).ok()?)))
+            .collect())
+    }
+
+    async fn get_provider_earnings(&self, provider_id: &str) -> redis::RedisResult<ProviderEarnings> {
+        let earnings: Option<String> = self.pool
+            .query(&Cmd::get(earnings_key(provider_id)))
+            .await?;
+        Ok(parse_earnings(earnings))
+    }
+
+    async fn record_payout(
+        &self,
+        provider_id: &str,
+        amount: Decimal,
+    ) -> redis::RedisResult<CreditTransaction> {
+        let key = earnings_key(provider_id);
+
+        for _ in 0..CAS_ATTEMPTS {
+            let raw: Option<String> = self.pool.query(&Cmd::get(&key)).await?;
+            let mut earnings = parse_earnings(raw.clone());
+            if earnings.pending_payout < amount {
+                return Err(insufficient_credits());
+            }
+            earnings.pay_out(amount, now_secs());
+            let transaction = payout_transaction(provider_id, amount, &earnings);
+
+            // Same compare-and-set as a deposit, on the earnings record
+            let mut invocation = self.deposit_script.prepare_invoke();
+            invocation
+                .key(&key)
+                .key(provider_transactions_key(provider_id))
+                .key(JOURNAL_KEY)
+                .arg(raw.unwrap_or_default())
+                .arg(serde_json::to_string(&earnings).unwrap())
+                .arg(serde_json::to_string(&transaction).unwrap());
+
+            if self.pool.invoke::<i64>(&invocation).await? == 1 {
+                return Ok(transaction);
+            }
+        }
+
+        Err(reservation_error("Credit balance contention"))
+    }
+
+    async fn get_platform_fees(&self) -> redis::RedisResult<Decimal> {
+        let fees: Option<String> = self.pool.query(&Cmd::get(PLATFORM_FEES_KEY)).await?;
+        Ok(parse_decimal(fees))
+    }
+
+    async fn ledger_len(&self) -> redis::RedisResult<usize> {
+        self.pool.query(&Cmd::llen(JOURNAL_KEY)).await
+    }
+
+    async fn ledger_range(&self, start: usize, stop: usize) -> redis::RedisResult<Vec<CreditTransaction>> {
+        let entries: Vec<String> = self.pool
+            .query(&Cmd::lrange(JOURNAL_KEY, start as isize, stop as isize))
+            .await?;
+        Ok(entries
+            .iter()
+            .filter_map(|e| serde_json::from_str(e).ok())
+            .collect())
+    }
+
+    async fn append_ledger_entry(&self, entry: &CreditTransaction) -> redis::RedisResult<()> {
+        self.pool
+            .query(&Cmd::rpush(JOURNAL_KEY, serde_json::to_string(entry).unwrap()))
+            .await
+    }
+
+    async fn ledger_checkpoint(&self) -> redis::RedisResult<Option<LedgerCheckpoint>> {
+        let data: Option<String> = self.pool
+            .query(&Cmd::get(LEDGER_CHECKPOINT_KEY))
+            .await?;
+        Ok(data.and_then(|d| serde_json::from_str(&d).ok()))
+    }
+
+    async fn save_ledger_checkpoint(&self, checkpoint: &LedgerCheckpoint) -> redis::RedisResult<()> {
+        self.pool
+            .query(&Cmd::set(LEDGER_CHECKPOINT_KEY, serde_json::to_string(checkpoint).unwrap()))
+            .await
+    }
+
+    async fn ledger_accounts(&self) -> redis::RedisResult<Vec<Account>> {
+        let mut accounts = vec![Account::PlatformFees];
+        let patterns: [(&str, fn(String) -> Account); 3] = [
+            ("credit:balance:", Account::User),
+            ("credit:reserved:", Account::Escrow),
+            ("provider:earnings:", Account::Provider),
+        ];
+        for (prefix, account) in patterns {
+            let mut cursor = 0u64;
+            loop {
+                let (next, keys): (u64, Vec<String>) = self.pool
+                    .query(
+                        redis::cmd("SCAN")
+                            .arg(cursor)
+                            .arg("MATCH")
+                            .arg(format!("{}*", prefix))
+                            .arg("COUNT")
+                            .arg(1000),
+                    )
+                    .await?;
+                accounts.extend(keys.iter().filter_map(|k| k.strip_prefix(prefix)).map(|id| account(id.to_string())));
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+        }
+        accounts.sort();
+        accounts.dedup();
+        Ok(accounts)
+    }
+
+    async fn claim_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        ttl: u64,
+    ) -> redis::RedisResult<IdempotencyState> {
+        let mut invocation = self.claim_script.prepare_invoke();
+        invocation.key(idempotency_key(user_id, key)).arg(ttl);
+
+        let existing: Option<Vec<u8>> = self.pool.invoke(&invocation).await?;
+        Ok(match existing {
+            None => IdempotencyState::Claimed,
+            Some(response) if response.is_empty() => IdempotencyState::InProgress,
+            Some(response) => IdempotencyState::Completed(response),
+        })
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        response: &[u8],
+        ttl: u64,
+    ) -> redis::RedisResult<()> {
+        self.pool
+            .query(&Cmd::set_ex(idempotency_key(user_id, key), response, ttl as usize))
+            .await
+    }
+
+    async fn release_idempotency_key(&self, user_id: &str, key: &str) -> redis::RedisResult<()> {
+        self.pool.query(&Cmd::del(idempotency_key(user_id, key))).await
+    }
+
+    async fn rest_bid(&self, bid: &RestingBid) -> redis::RedisResult<()> {
+        self.pool.query_pipeline(
+            redis::pipe()
+                .atomic()
+                .set(bid_key(&bid.bid_id), serde_json::to_string(bid).unwrap())
+                .ignore()
+                .zadd(resting_index(&bid.bid.model), &bid.bid_id, bid.rested_at_ms)
+                .ignore()
+                .zadd(BID_EXPIRY_INDEX, &bid.bid_id, bid.expires_at)
+                .ignore(),
+        ).await
+    }
+
+    async fn get_bid(&self, bid_id: &str) -> redis::RedisResult<Option<RestingBid>> {
+        let data: Option<String> = self.pool.query(&Cmd::get(bid_key(bid_id))).await?;
+        Ok(data.and_then(|d| serde_json::from_str(&d).ok()))
+    }
+
+    async fn resting_bids(&self, model: &str) -> redis::RedisResult<Vec<RestingBid>> {
+        let ids: Vec<String> = self.pool
+            .query(&Cmd::zrange(resting_index(model), 0, -1))
+            .await?;
+        self.load_bids(ids).await
+    }
+
+    async fn expired_bids(&self, now: u64) -> redis::RedisResult<Vec<RestingBid>> {
+        let ids: Vec<String> = self.pool
+            .query(&Cmd::zrangebyscore(BID_EXPIRY_INDEX, "-inf", now))
+            .await?;
+        self.load_bids(ids).await
+    }
+
+    async fn take_resting_bid(&self, bid: &RestingBid) -> redis::RedisResult<bool> {
+        // ZREM reports the removal to exactly one of any concurrent takers
+        let (removed, _): (i64, i64) = self.pool.query_pipeline(
+            redis::pipe()
+                .atomic()
+                .zrem(resting_index(&bid.bid.model), &bid.bid_id)
+                .zrem(BID_EXPIRY_INDEX, &bid.bid_id),
+        ).await?;
+        Ok(removed == 1)
+    }
+
+    async fn save_bid(&self, bid: &RestingBid) -> redis::RedisResult<()> {
+        self.pool
+            .query(&Cmd::set_ex(
+                bid_key(&bid.bid_id),
+                serde_json::to_string(bid).unwrap(),
+                BID_RECORD_TTL_SECS,
+            ))
+            .await
+    }
+}
+
+#[derive(Default)]
+struct MemoryState {
+    asks: HashMap<String, Ask>,
+    by_model: HashMap<String, BTreeSet<String>>,
+    by_gpu: HashMap<String, BTreeSet<String>>,
+    heartbeats: BTreeSet<(u64, String)>,
+    // Tokens claimed from each ask key by fills still in flight
+    claimed: HashMap<String, u64>,
+    balances: HashMap<String, Decimal>,
+    reserved: HashMap<String, Decimal>,
+    reservations: HashMap<String, CreditReservation>,
+    transactions: HashMap<String, Vec<CreditTransaction>>,
+    earnings: HashMap<String, ProviderEarnings>,
+    platform_fees: Decimal,
+    provider_transactions: HashMap<String, Vec<CreditTransaction>>,
+    journal: Vec<CreditTransaction>,
+    ledger_checkpoint: Option<LedgerCheckpoint>,
+    // Idempotency records with their expiry time
+    idempotency: HashMap<String, (Vec<u8>, u64)>,
+    bids: HashMap<String, RestingBid>,
+    // IDs of the bids still on the book
+    resting: HashSet<String>,
+}
+
+impl MemoryState {
+    fn unindex(&mut self, key: &str) -> Option<Ask> {
+        let ask = self.asks.remove(key)?;
+        if let Some(keys) = self.by_model.get_mut(&ask.model) {
+            keys.remove(key);
+        }
+        if let Some(keys) = self.by_gpu.get_mut(&ask.gpu_type) {
+            keys.remove(key);
+        }
+        self.heartbeats.remove(&(ask.last_heartbeat, key.to_string()));
+        Some(ask)
+    }
+
+    fn unclaim(&mut self, key: &str, tokens: u32) {
+        if let Some(claimed) = self.claimed.get_mut(key) {
+            *claimed = claimed.saturating_sub(tokens as u64);
+            if *claimed == 0 {
+                self.claimed.remove(key);
+            }
+        }
+    }
+
+    fn settle(
+        &mut self,
+        reservation: &CreditReservation,
+        charge: Decimal,
+    ) -> redis::RedisResult<Decimal> {
+        match self.reservations.get(&reservation.reservation_id) {
+            Some(stored) if stored == reservation => {}
+            _ => return Err(reservation_error("Reservation not found")),
+        }
+        self.reservations.remove(&reservation.reservation_id);
+
+        let reserved = self.reserved.entry(reservation.user_id.clone()).or_default();
+        *reserved = (*reserved - reservation.amount).max(Decimal::ZERO);
+
+        let balance = self.balances.entry(reservation.user_id.clone()).or_default();
+        *balance += reservation.amount - charge;
+        Ok(*balance)
+    }
+
+    fn index_keys(&self, model: Option<&str>, gpu_type: Option<&str>) -> Vec<String> {
+        let empty = BTreeSet::new();
+        match (model, gpu_type) {
+            (None, None) => self.heartbeats.iter().map(|(_, k)| k.clone()).collect(),
+            (Some(m), None) => self.by_model.get(m).unwrap_or(&empty).iter().cloned().collect(),
+            (None, Some(g)) => self.by_gpu.get(g).unwrap_or(&empty).iter().cloned().collect(),
+            (Some(m), Some(g)) => self
+                .by_model
+                .get(m)
+                .unwrap_or(&empty)
+                .intersection(self.by_gpu.get(g).unwrap_or(&empty))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Process-local store with the same semantics as `RedisStore`. Used for
+/// tests and for running the matcher without an external Redis.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[tonic::async_trait]
+impl OrderBookStore for MemoryStore {
+    async fn upsert_ask(&self, ask: &Ask) -> redis::RedisResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let key = ask_key(&ask.provider_id, &ask.model);
+
+        let claimed = state.claimed.get(&key).map(u64::to_string);
+        let stored = Ask {
+            available_tokens: unclaimed_capacity(ask.available_tokens, claimed.as_deref()),
+            ..ask.clone()
+        };
+
+        state.unindex(&key);
+        state.by_model.entry(ask.model.clone()).or_default().insert(key.clone());
+        state.by_gpu.entry(ask.gpu_type.clone()).or_default().insert(key.clone());
+        state.heartbeats.insert((ask.last_heartbeat, key.clone()));
+        state.asks.insert(key, stored);
+        Ok(())
+    }
+
+    async fn get_ask(&self, provider_id: &str, model: &str) -> redis::RedisResult<Option<Ask>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.asks.get(&ask_key(provider_id, model)).cloned())
+    }
+
+    async fn providers_by_price(
+        &self,
+        model: &str,
+        gpu_type: &str,
+        max_price: Decimal,
+    ) -> redis::RedisResult<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        let mut matches: Vec<&Ask> = state
+            .index_keys(Some(model), Some(gpu_type))
+            .iter()
+            .filter_map(|k| state.asks.get(k))
+            .filter(|a| a.price <= max_price)
+            .collect();
+
+        // Same ordering as a sorted set: by score, then lexicographically by member
+        matches.sort_by(|a, b| {
+            a.price
+                .cmp(&b.price)
+                .then_with(|| a.provider_id.cmp(&b.provider_id))
+        });
+
+        Ok(matches.into_iter().map(|a| a.provider_id.clone()).collect())
+    }
+
+    async fn asks_for_providers(
+        &self,
+        model: &str,
+        provider_ids: &[String],
+    ) -> redis::RedisResult<Vec<Ask>> {
+        let state = self.state.lock().unwrap();
+        Ok(provider_ids
+            .iter()
+            .filter_map(|p| state.asks.get(&ask_key(p, model)).cloned())
+            .collect())
+    }
+
+    async fn gpu_types_for_model(&self, model: &str) -> redis::RedisResult<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        let gpu_types: BTreeSet<String> = state
+            .index_keys(Some(model), None)
+            .iter()
+            .filter_map(|k| state.asks.get(k))
+            .map(|a| a.gpu_type.clone())
+            .collect();
+        Ok(gpu_types.into_iter().collect())
+    }
+
+    async fn list_asks(
+        &self,
+        model: Option<&str>,
+        gpu_type: Option<&str>,
+    ) -> redis::RedisResult<Vec<Ask>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .index_keys(model, gpu_type)
+            .iter()
+            .filter_map(|k| state.asks.get(k).cloned())
+            .collect())
+    }
+
+    async fn remove_ask(&self, ask: &Ask) -> redis::RedisResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state.unindex(&ask_key(&ask.provider_id, &ask.model));
+        Ok(())
+    }
+
+    async fn adjust_capacity(&self, provider_id: &str, model: &str, delta: i64) -> redis::RedisResult<bool> {
+        let mut state = self.state.lock().unwrap();
+        let key = ask_key(provider_id, model);
+        let Some(ask) = state.asks.get_mut(&key) else {
+            // Tokens given back to a removed ask are no longer claimed
+            if let Ok(tokens) = u32::try_from(delta) {
+                state.unclaim(&key, tokens);
+            }
+            return Ok(false);
+        };
+        let Some(available) = adjusted_capacity(ask.available_tokens, delta) else {
+            return Ok(false);
+        };
+        ask.available_tokens = available;
+
+        match u32::try_from(delta) {
+            Ok(tokens) => state.unclaim(&key, tokens),
+            Err(_) => *state.claimed.entry(key).or_default() += delta.unsigned_abs(),
+        }
+        Ok(true)
+    }
+
+    async fn settle_capacity(&self, provider_id: &str, model: &str, tokens: u32) -> redis::RedisResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state.unclaim(&ask_key(provider_id, model), tokens);
+        Ok(())
+    }
+
+    async fn remove_stale(&self, cutoff: u64) -> redis::RedisResult<u32> {
+        let mut state = self.state.lock().unwrap();
+        let stale: Vec<String> = state
+            .heartbeats
+            .range(..(cutoff, String::new()))
+            .map(|(_, k)| k.clone())
+            .collect();
+
+        for key in &stale {
+            state.unindex(key);
+        }
+        Ok(stale.len() as u32)
+    }
+
+    async fn get_credit_balance(&self, user_id: &str) -> redis::RedisResult<Decimal> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .balances
+            .get(user_id)
+            .copied()
+            .unwrap_or(Decimal::ZERO))
+    }
+
+    async fn get_reserved_credits(&self, user_id: &str) -> redis::RedisResult<Decimal> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .reserved
+            .get(user_id)
+            .copied()
+            .unwrap_or(Decimal::ZERO))
+    }
+
+    async fn deposit_credits(
+        &self,
+        user_id: &str,
+        amount: Decimal,
+        transaction_type: &str,
+    ) -> redis::RedisResult<CreditTransaction> {
+        let mut state = self.state.lock().unwrap();
+        let balance = state.balances.entry(user_id.to_string()).or_default();
+        if *balance + amount < Decimal::ZERO {
+            return Err(insufficient_credits());
+        }
+        *balance += amount;
+
+        let transaction = deposit_transaction(user_id, amount, *balance, transaction_type);
+        state
+            .transactions
+            .entry(user_id.to_string())
+            .or_default()
+            .push(transaction.clone());
+        state.journal.push(transaction.clone());
+        Ok(transaction)
+    }
+
+    async fn reserve_credits(&self, reservation: &CreditReservation) -> redis::RedisResult<Decimal> {
+        let mut state = self.state.lock().unwrap();
+        if state.reservations.contains_key(&reservation.reservation_id) {
+            return Err(reservation_error("Reservation already exists"));
+        }
+
+        let balance = state.balances.entry(reservation.user_id.clone()).or_default();
+        if *balance < reservation.amount {
+            return Err(insufficient_credits());
+        }
+        *balance -= reservation.amount;
+        let balance_after = *balance;
+
+        *state.reserved.entry(reservation.user_id.clone()).or_default() += reservation.amount;
+        state
+            .reservations
+            .insert(reservation.reservation_id.clone(), reservation.clone());
+        state.journal.push(hold_transaction(reservation, balance_after));
+        Ok(balance_after)
+    }
+
+    async fn capture_reservation(
+        &self,
+        reservation: &CreditReservation,
+        amount: Decimal,
+        platform_fee: Decimal,
+    ) -> redis::RedisResult<CreditTransaction> {
+        if amount > reservation.amount {
+            return Err(reservation_error("Capture exceeds reserved credits"));
+        }
+        if platform_fee > amount {
+            return Err(reservation_error("Platform fee exceeds charge"));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let balance_after = state.settle(reservation, amount)?;
+        let transaction = usage_transaction(reservation, amount, platform_fee, balance_after);
+        state
+            .transactions
+            .entry(reservation.user_id.clone())
+            .or_default()
+            .push(transaction.clone());
+        state.journal.push(transaction.clone());
+        state
+            .earnings
+            .entry(reservation.provider_id.clone())
+            .or_default()
+            .credit(amount - platform_fee);
+        state.platform_fees += platform_fee;
+        Ok(transaction)
+    }
+
+    async fn release_reservation(&self, reservation: &CreditReservation) -> redis::RedisResult<Decimal> {
+        let mut state = self.state.lock().unwrap();
+        let balance_after = state.settle(reservation, Decimal::ZERO)?;
+        state.journal.push(release_transaction(reservation, balance_after));
+        Ok(balance_after)
+    }
+
+    async fn release_expired_reservations(&self, now: u64) -> redis::RedisResult<u32> {
+        let mut state = self.state.lock().unwrap();
+        let expired: Vec<CreditReservation> = state
+            .reservations
+            .values()
+            .filter(|r| r.expires_at > 0 && r.expires_at <= now)
+            .cloned()
+            .collect();
+        for reservation in &expired {
+            let balance_after = state.settle(reservation, Decimal::ZERO)?;
+            state.journal.push(release_transaction(reservation, balance_after));
+        }
+        Ok(expired.len() as u32)
+    }
+
+    async fn retarget_reservation(
+        &self,
+        reservation: &CreditReservation,
+        provider_id: &str,
+    ) -> redis::RedisResult<CreditReservation> {
+        let mut state = self.state.lock().unwrap();
+        match state.reservations.get_mut(&reservation.reservation_id) {
+            Some(stored) if stored == reservation => {
+                stored.provider_id = provider_id.to_string();
+                Ok(stored.clone())
+            }
+            _ => Err(reservation_error("Reservation not found")),
+        }
+    }
+
+    async fn credit_transaction_count(&self, user_id: &str) -> redis::RedisResult<usize> {
+        let state = self.state.lock().unwrap();
+        Ok(state.transactions.get(user_id).map_or(0, |t| t.len()))
+    }
+
+    async fn credit_transactions_range(
+        &self,
+        user_id: &str,
+        start: usize,
+        stop: usize,
+    ) -> redis::RedisResult<Vec<(usize, CreditTransaction)>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .transactions
+            .get(user_id)
+            .map(|t| {
+                t.iter()
+                    .enumerate()
+                    .skip(start)
+                    .take((stop + 1).saturating_sub(start))
+                    .map(|(i, tx)| (i, tx.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn get_provider_earnings(&self, provider_id: &str) -> redis::RedisResult<ProviderEarnings> {
+        let state = self.state.lock().unwrap();
+        Ok(state.earnings.get(provider_id).cloned().unwrap_or_default())
+    }
+
+    async fn record_payout(
+        &self,
+        provider_id: &str,
+        amount: Decimal,
+    ) -> redis::RedisResult<CreditTransaction> {
+        let mut state = self.state.lock().unwrap();
+        let earnings = state.earnings.entry(provider_id.to_string()).or_default();
+        if earnings.pending_payout < amount {
+            return Err(insufficient_credits());
+        }
+        earnings.pay_out(amount, now_secs());
+
+        let transaction = payout_transaction(provider_id, amount, earnings);
+        state
+            .provider_transactions
+            .entry(provider_id.to_string())
+            .or_default()
+            .push(transaction.clone());
+        state.journal.push(transaction.clone());
+        Ok(transaction)
+    }
+
+    async fn get_platform_fees(&self) -> redis::RedisResult<Decimal> {
+        Ok(self.state.lock().unwrap().platform_fees)
+    }
+
+    async fn ledger_len(&self) -> redis::RedisResult<usize> {
+        Ok(self.state.lock().unwrap().journal.len())
+    }
+
+    async fn ledger_range(&self, start: usize, stop: usize) -> redis::RedisResult<Vec<CreditTransaction>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .journal
+            .iter()
+            .skip(start)
+            .take((stop + 1).saturating_sub(start))
+            .cloned()
+            .collect())
+    }
+
+    async fn append_ledger_entry(&self, entry: &CreditTransaction) -> redis::RedisResult<()> {
+        self.state.lock().unwrap().journal.push(entry.clone());
+        Ok(())
+    }
+
+    async fn ledger_checkpoint(&self) -> redis::RedisResult<Option<LedgerCheckpoint>> {
+        Ok(self.state.lock().unwrap().ledger_checkpoint.clone())
+    }
+
+    async fn save_ledger_checkpoint(&self, checkpoint: &LedgerCheckpoint) -> redis::RedisResult<()> {
+        self.state.lock().unwrap().ledger_checkpoint = Some(checkpoint.clone());
+        Ok(())
+    }
+
+    async fn ledger_accounts(&self) -> redis::RedisResult<Vec<Account>> {
+        let state = self.state.lock().unwrap();
+        let mut accounts: Vec<Account> = std::iter::once(Account::PlatformFees)
+            .chain(state.balances.keys().cloned().map(Account::User))
+            .chain(state.reserved.keys().cloned().map(Account::Escrow))
+            .chain(state.earnings.keys().cloned().map(Account::Provider))
+            .collect();
+        accounts.sort();
+        accounts.dedup();
+        Ok(accounts)
+    }
+
+    async fn claim_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        ttl: u64,
+    ) -> redis::RedisResult<IdempotencyState> {
+        let mut state = self.state.lock().unwrap();
+        let now = now_secs();
+        let key = idempotency_key(user_id, key);
+
+        match state.idempotency.get(&key) {
+            Some((response, expires_at)) if *expires_at > now => Ok(if response.is_empty() {
+                IdempotencyState::InProgress
+            } else {
+                IdempotencyState::Completed(response.clone())
+            }),
+            _ => {
+                state.idempotency.insert(key, (Vec::new(), now + ttl));
+                Ok(IdempotencyState::Claimed)
+            }
+        }
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        response: &[u8],
+        ttl: u64,
+    ) -> redis::RedisResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .idempotency
+            .insert(idempotency_key(user_id, key), (response.to_vec(), now_secs() + ttl));
+        Ok(())
+    }
+
+    async fn release_idempotency_key(&self, user_id: &str, key: &str) -> redis::RedisResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state.idempotency.remove(&idempotency_key(user_id, key));
+        Ok(())
+    }
+
+    async fn rest_bid(&self, bid: &RestingBid) -> redis::RedisResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state.resting.insert(bid.bid_id.clone());
+        state.bids.insert(bid.bid_id.clone(), bid.clone());
+        Ok(())
+    }
+
+    async fn get_bid(&self, bid_id: &str) -> redis::RedisResult<Option<RestingBid>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.bids.get(bid_id).cloned())
+    }
+
+    async fn resting_bids(&self, model: &str) -> redis::RedisResult<Vec<RestingBid>> {
+        let state = self.state.lock().unwrap();
+        let mut bids: Vec<RestingBid> = state
+            .resting
+            .iter()
+            .filter_map(|id| state.bids.get(id))
+            .filter(|b| b.bid.model == model)
+            .cloned()
+            .collect();
+        bids.sort_by(|a, b| (a.rested_at_ms, &a.bid_id).cmp(&(b.rested_at_ms, &b.bid_id)));
+        Ok(bids)
+    }
+
+    async fn expired_bids(&self, now: u64) -> redis::RedisResult<Vec<RestingBid>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .resting
+            .iter()
+            .filter_map(|id| state.bids.get(id))
+            .filter(|b| b.expires_at <= now)
+            .cloned()
+            .collect())
+    }
+
+    async fn take_resting_bid(&self, bid: &RestingBid) -> redis::RedisResult<bool> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state.resting.remove(&bid.bid_id))
+    }
+
+    async fn save_bid(&self, bid: &RestingBid) -> redis::RedisResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state.bids.insert(bid.bid_id.clone(), bid.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::test_ask;
+    use rust_decimal_macros::dec;
+
+    fn ask(provider_id: &str, gpu_type: &str, price: Decimal, last_heartbeat: u64) -> Ask {
+        Ask {
+            gpu_type: gpu_type.into(),
+            last_heartbeat,
+            ..test_ask(provider_id, price)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_price_index() {
+        let store = MemoryStore::new();
+        store.upsert_ask(&ask("p2", "a100", dec!(0.002), 100)).await.unwrap();
+        store.upsert_ask(&ask("p1", "a100", dec!(0.001), 100)).await.unwrap();
+        store.upsert_ask(&ask("p3", "a100", dec!(0.005), 100)).await.unwrap();
+        store.upsert_ask(&ask("p4", "h100", dec!(0.001), 100)).await.unwrap();
+
+        let providers = store
+            .providers_by_price("gpt4", "a100", dec!(0.002))
+            .await
+            .unwrap();
+        assert_eq!(providers, vec!["p1".to_string(), "p2".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_memory_adjust_capacity() {
+        let store = MemoryStore::new();
+        store.upsert_ask(&ask("p1", "a100", dec!(0.001), 100)).await.unwrap();
+
+        assert!(store.adjust_capacity("p1", "gpt4", -600).await.unwrap());
+        assert!(!store.adjust_capacity("p1", "gpt4", -600).await.unwrap());
+        assert!(store.adjust_capacity("p1", "gpt4", 200).await.unwrap());
+        assert_eq!(store.get_ask("p1", "gpt4").await.unwrap().unwrap().available_tokens, 600);
+        assert!(!store.adjust_capacity("p2", "gpt4", 1).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_memory_heartbeat_keeps_claims() {
+        let store = MemoryStore::new();
+        store.upsert_ask(&ask("p1", "a100", dec!(0.001), 100)).await.unwrap();
+        assert!(store.adjust_capacity("p1", "gpt4", -600).await.unwrap());
+
+        // The provider still advertises its full capacity mid-fill
+        store.upsert_ask(&ask("p1", "a100", dec!(0.001), 110)).await.unwrap();
+        assert_eq!(store.get_ask("p1", "gpt4").await.unwrap().unwrap().available_tokens, 400);
+
+        assert!(store.adjust_capacity("p1", "gpt4", 600).await.unwrap());
+        assert_eq!(store.get_ask("p1", "gpt4").await.unwrap().unwrap().available_tokens, 1000);
+        store.upsert_ask(&ask("p1", "a100", dec!(0.001), 120)).await.unwrap();
+        assert_eq!(store.get_ask("p1", "gpt4").await.unwrap().unwrap().available_tokens, 1000);
+
+        // A settled claim is left for the next heartbeat to report
+        assert!(store.adjust_capacity("p1", "gpt4", -300).await.unwrap());
+        store.settle_capacity("p1", "gpt4", 300).await.unwrap();
+        assert_eq!(store.get_ask("p1", "gpt4").await.unwrap().unwrap().available_tokens, 700);
+        store.upsert_ask(&ask("p1", "a100", dec!(0.001), 130)).await.unwrap();
+        assert_eq!(store.get_ask("p1", "gpt4").await.unwrap().unwrap().available_tokens, 1000);
+    }
+
+    #[tokio::test]
+    async fn test_memory_remove_stale() {
+        let store = MemoryStore::new();
+        store.upsert_ask(&ask("p1", "a100", dec!(0.001), 50)).await.unwrap();
+        store.upsert_ask(&ask("p2", "a100", dec!(0.001), 150)).await.unwrap();
+
+        assert_eq!(store.remove_stale(100).await.unwrap(), 1);
+        assert!(store.get_ask("p1", "gpt4").await.unwrap().is_none());
+        assert!(store.get_ask("p2", "gpt4").await.unwrap().is_some());
+        assert_eq!(
+            store.providers_by_price("gpt4", "a100", dec!(1)).await.unwrap(),
+            vec!["p2".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_model_and_gpu_indexes() {
+        let store = MemoryStore::new();
+        store.upsert_ask(&ask("p1", "a100", dec!(0.001), 100)).await.unwrap();
+        store.upsert_ask(&ask("p2", "h100", dec!(0.001), 100)).await.unwrap();
+        let mut other_model = ask("p3", "a100", dec!(0.001), 100);
+        other_model.model = "claude-v2".into();
+        store.upsert_ask(&other_model).await.unwrap();
+
+        assert_eq!(store.list_asks(None, None).await.unwrap().len(), 3);
+        assert_eq!(store.list_asks(Some("gpt4"), None).await.unwrap().len(), 2);
+        assert_eq!(store.list_asks(None, Some("a100")).await.unwrap().len(), 2);
+
+        let both = store.list_asks(Some("gpt4"), Some("a100")).await.unwrap();
+        assert_eq!(both.len(), 1);
+        assert_eq!(both[0].provider_id, "p1");
+
+        assert_eq!(store.gpu_types_for_model("gpt4").await.unwrap(), vec!["a100", "h100"]);
+        assert_eq!(store.gpu_types_for_model("claude-v2").await.unwrap(), vec!["a100"]);
+        store.remove_ask(&ask("p2", "h100", dec!(0.001), 100)).await.unwrap();
+        assert_eq!(store.gpu_types_for_model("gpt4").await.unwrap(), vec!["a100"]);
+    }
+
+    #[tokio::test]
+    async fn test_memory_reindex_on_gpu_change() {
+        let store = MemoryStore::new();
+        store.upsert_ask(&ask("p1", "a100", dec!(0.001), 100)).await.unwrap();
+        store.upsert_ask(&ask("p1", "h100", dec!(0.001), 200)).await.unwrap();
+
+        assert!(store.list_asks(None, Some("a100")).await.unwrap().is_empty());
+        assert_eq!(store.list_asks(None, Some("h100")).await.unwrap().len(), 1);
+        assert!(store
+            .providers_by_price("gpt4", "a100", dec!(1))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Only the latest heartbeat is indexed
+        assert_eq!(store.remove_stale(150).await.unwrap(), 0);
+        assert_eq!(store.remove_stale(201).await.unwrap(), 1);
+    }
+
+    fn reservation(reservation_id: &str, amount: Decimal) -> CreditReservation {
+        CreditReservation {
+            reservation_id: reservation_id.into(),
+            user_id: "u1".into(),
+            provider_id: "p1".into(),
+            amount,
+            created_at: 0,
+            expires_at: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_reserve_capture_release() {
+        let store = MemoryStore::new();
+        store.deposit_credits("u1", dec!(100), "credit_purchase").await.unwrap();
+
+        let first = reservation("r1", dec!(60));
+        assert_eq!(store.reserve_credits(&first).await.unwrap(), dec!(40));
+        assert!(store.reserve_credits(&reservation("r2", dec!(50))).await.is_err());
+        assert_eq!(store.get_reserved_credits("u1").await.unwrap(), dec!(60));
+
+        let transaction = store.capture_reservation(&first, dec!(25), dec!(5)).await.unwrap();
+        assert_eq!(transaction.amount, dec!(25));
+        assert_eq!(transaction.balance_after, dec!(75));
+        assert_eq!(store.get_reserved_credits("u1").await.unwrap(), Decimal::ZERO);
+
+        // A reservation can only be settled once
+        assert!(store.release_reservation(&first).await.is_err());
+
+        let second = reservation("r2", dec!(50));
+        store.reserve_credits(&second).await.unwrap();
+        assert_eq!(store.release_reservation(&second).await.unwrap(), dec!(75));
+        assert_eq!(store.credit_transaction_count("u1").await.unwrap(), 2);
+
+        // Only the captured charge reaches the provider, net of the fee
+        let earnings = store.get_provider_earnings("p1").await.unwrap();
+        assert_eq!(earnings.total_credits, dec!(20));
+        assert_eq!(earnings.total_inference_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_memory_releases_expired_reservations() {
+        let store = MemoryStore::new();
+        store.deposit_credits("u1", dec!(100), "credit_purchase").await.unwrap();
+        let expiring = CreditReservation { expires_at: 100, ..reservation("r1", dec!(30)) };
+        let later = CreditReservation { expires_at: 200, ..reservation("r2", dec!(20)) };
+        let open = reservation("r3", dec!(10));
+        for r in [&expiring, &later, &open] {
+            store.reserve_credits(r).await.unwrap();
+        }
+
+        assert_eq!(store.release_expired_reservations(99).await.unwrap(), 0);
+        assert_eq!(store.release_expired_reservations(100).await.unwrap(), 1);
+        assert_eq!(store.get_reserved_credits("u1").await.unwrap(), dec!(30));
+        assert_eq!(store.get_credit_balance("u1").await.unwrap(), dec!(70));
+
+        // Reservations without an expiry are never swept
+        assert_eq!(store.release_expired_reservations(u64::MAX).await.unwrap(), 1);
+        assert_eq!(store.get_reserved_credits("u1").await.unwrap(), dec!(10));
+        assert!(store.release_reservation(&expiring).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_memory_record_payout() {
+        let store = MemoryStore::new();
+        store.deposit_credits("u1", dec!(100), "credit_purchase").await.unwrap();
+        let first = reservation("r1", dec!(60));
+        store.reserve_credits(&first).await.unwrap();
+        store.capture_reservation(&first, dec!(50), dec!(5)).await.unwrap();
+
+        assert!(store.record_payout("p1", dec!(46)).await.is_err());
+
+        let payout = store.record_payout("p1", dec!(30)).await.unwrap();
+        assert_eq!(payout.transaction_type, "provider_payout");
+        assert_eq!(payout.balance_after, dec!(15));
+
+        let earnings = store.get_provider_earnings("p1").await.unwrap();
+        assert_eq!(earnings.total_credits, dec!(45));
+        assert_eq!(earnings.pending_payout, dec!(15));
+        assert!(earnings.last_payout > 0);
+    }
+
+    #[tokio::test]
+    async fn test_memory_idempotency_keys() {
+        let store = MemoryStore::new();
+        assert_eq!(store.claim_idempotency_key("u1", "k1", 60).await.unwrap(), IdempotencyState::Claimed);
+        assert_eq!(store.claim_idempotency_key("u1", "k1", 60).await.unwrap(), IdempotencyState::InProgress);
+
+        // Keys are scoped per user
+        assert_eq!(store.claim_idempotency_key("u2", "k1", 60).await.unwrap(), IdempotencyState::Claimed);
+
+        store.complete_idempotency_key("u1", "k1", b"response", 60).await.unwrap();
+        assert_eq!(
+            store.claim_idempotency_key("u1", "k1", 60).await.unwrap(),
+            IdempotencyState::Completed(b"response".to_vec())
+        );
+
+        store.release_idempotency_key("u2", "k1").await.unwrap();
+        assert_eq!(store.claim_idempotency_key("u2", "k1", 60).await.unwrap(), IdempotencyState::Claimed);
+
+        // Expired claims can be taken again
+        assert_eq!(store.claim_idempotency_key("u3", "k1", 0).await.unwrap(), IdempotencyState::Claimed);
+        assert_eq!(store.claim_idempotency_key("u3", "k1", 60).await.unwrap(), IdempotencyState::Claimed);
+
+        // Completing a short-lived claim keeps the response for the full TTL
+        assert_eq!(store.claim_idempotency_key("u4", "k1", 0).await.unwrap(), IdempotencyState::Claimed);
+        store.complete_idempotency_key("u4", "k1", b"response", 60).await.unwrap();
+        assert_eq!(
+            store.claim_idempotency_key("u4", "k1", 60).await.unwrap(),
+            IdempotencyState::Completed(b"response".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_reconcile_reports_drift() {
+        let store = MemoryStore::new();
+        store.deposit_credits("u1", dec!(100), "credit_purchase").await.unwrap();
+        store.reserve_credits(&reservation("r1", dec!(60))).await.unwrap();
+        assert!(crate::ledger::reconcile(&store).await.unwrap().is_clean());
+
+        // A balance written behind the ledger's back
+        store.state.lock().unwrap().balances.insert("u1".into(), dec!(90));
+
+        let report = crate::ledger::reconcile(&store).await.unwrap();
+        assert_eq!(report.discrepancies.len(), 1);
+        assert_eq!(report.discrepancies[0].account, crate::ledger::Account::User("u1".into()));
+        assert_eq!(report.discrepancies[0].stored, dec!(90));
+        assert_eq!(report.discrepancies[0].replayed, dec!(40));
+    }
+
+    #[tokio::test]
+    async fn test_memory_reconcile_opens_legacy_balances() {
+        let store = MemoryStore::new();
+        // A balance from before the journal existed
+        store.state.lock().unwrap().balances.insert("u0".into(), dec!(30));
+        store.deposit_credits("u1", dec!(100), "credit_purchase").await.unwrap();
+
+        let report = crate::ledger::reconcile(&store).await.unwrap();
+        assert!(report.is_clean(), "{:?}", report);
+        assert_eq!((report.entries_checked, report.opening_entries), (1, 1));
+
+        // Later runs replay only the entries written since the checkpoint,
+        // the opening entry among them
+        store.deposit_credits("u0", dec!(5), "credit_purchase").await.unwrap();
+        let report = crate::ledger::reconcile(&store).await.unwrap();
+        assert!(report.is_clean(), "{:?}", report);
+        assert_eq!((report.entries_checked, report.opening_entries), (2, 0));
+    }
+}
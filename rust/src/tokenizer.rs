@@ -0,0 +1,263 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+use std::path::Path;
+
+/// Counts the tokens a model sees in a piece of text.
+pub trait TokenCounter: fmt::Debug + Send + Sync {
+    fn count(&self, text: &str) -> u32;
+}
+
+/// Estimates four bytes per token, for models without a vocabulary.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ByteLengthCounter;
+
+impl TokenCounter for ByteLengthCounter {
+    fn count(&self, text: &str) -> u32 {
+        text.len().div_ceil(4) as u32
+    }
+}
+
+/// Byte-pair encoding over a ranked vocabulary. Text is split into words,
+/// numbers, punctuation and whitespace runs, and each piece's bytes are
+/// merged lowest rank first until no adjacent pair is in the vocabulary.
+pub struct BpeTokenizer {
+    ranks: HashMap<Vec<u8>, u32>,
+}
+
+impl fmt::Debug for BpeTokenizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BpeTokenizer").field("vocab_size", &self.ranks.len()).finish()
+    }
+}
+
+impl BpeTokenizer {
+    #[cfg(test)]
+    pub fn from_ranks(ranks: impl IntoIterator<Item = (Vec<u8>, u32)>) -> Self {
+        Self {
+            ranks: ranks.into_iter().collect(),
+        }
+    }
+
+    /// Reads a vocabulary with one `<base64 token> <rank>` pair per line, as
+    /// in tiktoken's `.tiktoken` files.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+
+        let mut ranks = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = || format!("{}:{}: expected `<base64 token> <rank>`", path.display(), number + 1);
+            let (token, rank) = line.trim().split_once(' ').ok_or_else(invalid)?;
+            let token = decode_base64(token).ok_or_else(invalid)?;
+            let rank = rank.trim().parse().map_err(|_| invalid())?;
+            ranks.insert(token, rank);
+        }
+        Ok(Self { ranks })
+    }
+
+    fn count_piece(&self, piece: &[u8]) -> u32 {
+        if self.ranks.contains_key(piece) {
+            return 1;
+        }
+
+        // Parts are linked by their start offsets, and candidate merges wait
+        // in a heap keyed by rank then offset. A merge only changes the pairs
+        // either side of it, so entries for pairs it ended are skipped when
+        // popped instead of rescanning the piece.
+        let n = piece.len();
+        let mut next: Vec<usize> = (1..=n).collect();
+        let mut prev: Vec<Option<usize>> = (0..n).map(|i| i.checked_sub(1)).collect();
+        let mut merged = vec![false; n];
+        let pair = |start: usize, next: &[usize]| -> Option<Reverse<(u32, usize, usize)>> {
+            let end = *next.get(next[start])?;
+            self.ranks.get(&piece[start..end]).map(|rank| Reverse((*rank, start, end)))
+        };
+
+        let mut candidates: BinaryHeap<_> = (0..n).filter_map(|i| pair(i, &next)).collect();
+        let mut parts = n as u32;
+        while let Some(Reverse((_, start, end))) = candidates.pop() {
+            if merged[start] || next.get(next[start]) != Some(&end) {
+                continue;
+            }
+            let right = next[start];
+            merged[right] = true;
+            next[start] = end;
+            if end < n {
+                prev[end] = Some(start);
+            }
+            parts -= 1;
+
+            candidates.extend(pair(start, &next));
+            if let Some(before) = prev[start] {
+                candidates.extend(pair(before, &next));
+            }
+        }
+        parts
+    }
+}
+
+impl TokenCounter for BpeTokenizer {
+    fn count(&self, text: &str) -> u32 {
+        split_pieces(text)
+            .into_iter()
+            .map(|piece| self.count_piece(piece.as_bytes()))
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CharClass {
+    Letter,
+    Digit,
+    Space,
+    Other,
+}
+
+fn char_class(c: char) -> CharClass {
+    if c.is_alphabetic() {
+        CharClass::Letter
+    } else if c.is_numeric() {
+        CharClass::Digit
+    } else if c.is_whitespace() {
+        CharClass::Space
+    } else {
+        CharClass::Other
+    }
+}
+
+// Whether a space before `c` belongs to the piece starting at `c`
+fn takes_leading_space(c: char) -> bool {
+    matches!(char_class(c), CharClass::Letter | CharClass::Other)
+}
+
+/// Longest run of one character class in a piece. Vocabulary tokens are
+/// rarely anywhere near this long, so cutting runs here seldom changes a
+/// count, and it keeps text without spaces from merging as one huge piece.
+const MAX_PIECE_CHARS: usize = 64;
+
+/// Splits text the way BPE vocabularies are trained: words and punctuation
+/// runs keep one leading space, numbers are split into groups of up to
+/// three digits, and whitespace runs leave their last space to the word
+/// after them. No run is longer than `MAX_PIECE_CHARS`.
+fn split_pieces(text: &str) -> Vec<&str> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut pieces = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let mut j = i;
+        if chars[j].1 == ' ' && chars.get(j + 1).is_some_and(|(_, c)| takes_leading_space(*c)) {
+            j += 1;
+        }
+
+        let class = char_class(chars[j].1);
+        let mut run = 0;
+        while j < chars.len() && char_class(chars[j].1) == class {
+            if (class == CharClass::Digit && run == 3) || run == MAX_PIECE_CHARS {
+                break;
+            }
+            if class == CharClass::Space
+                && j > i
+                && chars[j].1 == ' '
+                && chars.get(j + 1).is_some_and(|(_, c)| takes_leading_space(*c))
+            {
+                break;
+            }
+            j += 1;
+            run += 1;
+        }
+
+        let end = chars.get(j).map_or(text.len(), |(offset, _)| *offset);
+        pieces.push(&text[chars[i].0..end]);
+        i = j;
+    }
+    pieces
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+            b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let text = text.trim_end_matches('=').as_bytes();
+    if text.len() % 4 == 1 {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.chunks(4) {
+        let mut buffer = 0u32;
+        for (k, c) in chunk.iter().enumerate() {
+            buffer |= value(*c)? << (18 - 6 * k);
+        }
+        let decoded = [(buffer >> 16) as u8, (buffer >> 8) as u8, buffer as u8];
+        bytes.extend_from_slice(&decoded[..chunk.len() - 1]);
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokenizer() -> BpeTokenizer {
+        let vocab = [" h", "he", "ll", "hell", "hello", " hello", "wo", "wor", "ld", "12"];
+        let mut ranks: Vec<(Vec<u8>, u32)> = (0..=255u8).map(|b| (vec![b], b as u32)).collect();
+        ranks.extend(vocab.iter().enumerate().map(|(i, t)| (t.as_bytes().to_vec(), 256 + i as u32)));
+        BpeTokenizer::from_ranks(ranks)
+    }
+
+    #[test]
+    fn test_split_pieces() {
+        assert_eq!(split_pieces("hello  world, 12345!"), vec!["hello", " ", " world", ",", " ", "123", "45", "!"]);
+        assert_eq!(split_pieces("héllo wörld"), vec!["héllo", " wörld"]);
+    }
+
+    #[test]
+    fn test_bpe_counts_merged_pieces() {
+        let tokenizer = tokenizer();
+        assert_eq!(tokenizer.count("hello"), 1);
+        // " world" merges into " ", "wor" and "ld"
+        assert_eq!(tokenizer.count("hello world"), 4);
+        assert_eq!(tokenizer.count("hello hello"), 2);
+        // Digits are grouped in threes: "121" and "2"
+        assert_eq!(tokenizer.count("1212"), 3);
+        // Unknown multi-byte characters fall back to one token per byte
+        assert_eq!(tokenizer.count("é"), 2);
+        assert_eq!(tokenizer.count(""), 0);
+        assert_eq!(ByteLengthCounter.count("hello"), 2);
+    }
+
+    #[test]
+    fn test_long_runs_are_split() {
+        let word = "a".repeat(MAX_PIECE_CHARS * 3 + 1);
+        let pieces = split_pieces(&word);
+        assert_eq!(pieces.len(), 4);
+        assert!(pieces.iter().all(|piece| piece.len() <= MAX_PIECE_CHARS));
+        assert_eq!(pieces.concat(), word);
+
+        // No pair in the run merges, so each byte stays a token
+        assert_eq!(tokenizer().count(&"x".repeat(100_000)), 100_000);
+    }
+
+    #[test]
+    fn test_load_tiktoken_vocabulary() {
+        let path = std::env::temp_dir().join(format!("vocab-{}.tiktoken", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "aGVsbG8= 0\nIHdvcmxk 1\n\n").unwrap();
+        let tokenizer = BpeTokenizer::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(tokenizer.count("hello world"), 2);
+        assert_eq!(decode_base64("YQ"), Some(b"a".to_vec()));
+        assert_eq!(decode_base64("a"), None);
+    }
+}